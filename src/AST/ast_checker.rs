@@ -1,9 +1,10 @@
 use crate::AST::expr_node::Func_Header;
-use crate::{panic_error, MessageHandler::message_handler, Value::Value};
+use crate::token::token_type::TokenType;
+use crate::{MessageHandler::message_handler, Value::Value};
 use crate::MessageHandler::message_handler::{throw_message, MessageType};
-use super::expr_node::{DataType, Expr};
+use super::expr_node::{DataType, Expr, Pos};
+use super::const_eval;
 use std::collections::HashMap;
-use std::process::exit;
 
 #[derive(Debug, Clone,PartialEq)]
 pub struct FAST { // AST formatter
@@ -24,7 +25,12 @@ pub struct Checker<'a> {
     ast: &'a Vec<Expr>,
     pseudo_variable_stack: Vec<VariableData>, // DataType, Name, is_const, is_ptr, init_v
     pseudo_function_stack: Vec<FAST>,
-    extern_function_stack: HashMap<String, Func_Header>
+    // signatures of every top-level function, collected before any body is checked
+    function_stack: HashMap<String, Func_Header>,
+    extern_function_stack: HashMap<String, Func_Header>,
+    enum_stack: HashMap<String, Vec<(String, i64)>>, // name, [(variant, discriminant)]
+    current_function: Option<Func_Header>, // function whose body is being checked
+    source: String // file name in warnings
 }
 
 
@@ -49,6 +55,19 @@ fn check_literal_type(init: Option<Box<Expr>>, dt: DataType, is_ptr: bool) ->
 
         let to_v= v.to_value();
 
+        if matches!(to_v, Value::Boolean(_)) {
+            if !matches!(dt, DataType::Bool | DataType::Unknown) || is_ptr {
+                return Err(format!("Cannot convert from bool to {:?}", dt.clone()));
+            }
+            return Ok((Some(Box::new(v)), DataType::Bool, is_ptr));
+        }
+        if let DataType::Enum(name) = &dt {
+            return Err(format!("Cannot convert from {:?} to enum '{}'", to_v.to_datatype(), name));
+        }
+        if matches!(dt, DataType::Bool) && !is_ptr {
+            return Err(format!("Cannot convert from {:?} to bool (use 'true' or 'false')", to_v.to_datatype()));
+        }
+
         if to_v.clone().is_string() {
             if matches!(dt, DataType::Unknown) { // 'let' keyword
                 return Ok((Some(Box::new(v)), DataType::Char, true));
@@ -59,10 +78,13 @@ fn check_literal_type(init: Option<Box<Expr>>, dt: DataType, is_ptr: bool) ->
             if is_ptr && !matches!(dt, DataType::Char) {
                 return Err(format!("Cannot convert from {:?}* to string literal (data type MUST be char)", dt.clone()));
             }
+            return Ok((Some(Box::new(v)), dt, is_ptr));
         }
 
         let vi64 = if to_v.clone().is_literal() {
             to_v.to_literal() as f64
+        } else if to_v.clone().is_float() {
+            to_v.to_float() as f64
         } else if to_v.clone().is_double() {
             to_v.to_double() as f64
         }
@@ -105,6 +127,11 @@ fn check_literal_type(init: Option<Box<Expr>>, dt: DataType, is_ptr: bool) ->
                     )
                 )
             },
+            DataType::Float => {
+                v=Expr::Literal(
+                    crate::Value::Value::Float(vi64 as f32)
+                )
+            }
             DataType::Suu => {
                 if vi64 > f64::MAX as f64 {
                     message_handler::throw_message("source", message_handler::MessageType::Warning, 1, 0, format!("suu (double) overflow, rolling back from {} to {}",
@@ -138,12 +165,39 @@ fn check_literal_type(init: Option<Box<Expr>>, dt: DataType, is_ptr: bool) ->
     Ok((init_v,data_type, is_ptr))
 }
 
-pub fn catch_error(r: Result<FAST, String>) -> FAST {
-    match r {
-        Ok(f) => f,
-        Err(s) => {
-            panic_error!("stdin", 1, 0, &s);
+/// Whether every path through `e` ends in a `return`
+fn always_returns(e: &Expr) -> bool {
+    match e {
+        Expr::Return(_, _) => true,
+        Expr::Statement(e) => always_returns(e),
+        Expr::Block(v) => v.iter().any(always_returns),
+        Expr::IfStmt(_, then_bl, else_bl, _) => always_returns(then_bl) && always_returns(else_bl),
+        // there is no `break`, so only returning leaves `while true`
+        Expr::WhileStmt(cond, _, _) => matches!(**cond, Expr::Literal(Value::Boolean(true))),
+        Expr::SwitchStmt(_, cases, default, _) => {
+            cases.iter().all(|(_, body)| always_returns(body)) && always_returns(default)
         }
+        _ => false
+    }
+}
+
+/// Common type of the two arms of a ternary, numbers are widened
+fn unify_types(a: DataType, b: DataType) -> Result<DataType, String> {
+    let rank = |dt: &DataType| match dt {
+        DataType::Char => Some(0),
+        DataType::Short => Some(1),
+        DataType::Int => Some(2),
+        DataType::Long => Some(3),
+        DataType::Float => Some(4),
+        DataType::Suu => Some(5),
+        _ => None
+    };
+    match (rank(&a), rank(&b)) {
+        _ if a == b => Ok(a),
+        _ if matches!(a, DataType::Unknown) => Ok(b),
+        _ if matches!(b, DataType::Unknown) => Ok(a),
+        (Some(ra), Some(rb)) => Ok(if ra >= rb { a } else { b }),
+        _ => Err(format!("Ternary arms have different types: {:?} and {:?}", a, b))
     }
 }
 
@@ -154,7 +208,167 @@ impl<'a> Checker<'a> {
             ast: ast, 
             pseudo_variable_stack: Vec::new(), 
             pseudo_function_stack: Vec::new(),
-            extern_function_stack: HashMap::new()
+            function_stack: HashMap::new(),
+            extern_function_stack: HashMap::new(),
+            enum_stack: HashMap::new(),
+            current_function: None,
+            source: "stdin".to_string()
+        }
+    }
+
+    /// Name of the checked file, for the position of warnings
+    pub fn set_source(&mut self, name: &str) {
+        self.source = name.to_string();
+    }
+
+    fn warn(&self, pos: Option<Pos>, message: &str) {
+        let pos = pos.unwrap_or_default();
+        throw_message(&self.source, MessageType::Warning, pos.line as i64, pos.col as i64, message);
+    }
+
+    /// Make host functions callable without an extern declaration, the
+    /// script's own functions and externs take precedence
+    pub fn declare_natives(&mut self, headers: Vec<Func_Header>) {
+        for h in headers {
+            self.extern_function_stack.entry(h.name.clone()).or_insert(h);
+        }
+    }
+
+    /// Best-effort static type of an expression, `DataType::Unknown` when it can't be told.
+    fn type_of(&self, expr: &Expr) -> DataType {
+        match expr {
+            Expr::Literal(v) => v.clone().to_datatype(),
+            Expr::EnumValue(name, _, _) => DataType::Enum(name.clone()),
+            Expr::Grouping(e) | Expr::Statement(e) => self.type_of(e),
+            Expr::Var(n) => {
+                self.pseudo_variable_stack.iter().rev().find(|f| f.name == *n)
+                    .map(|f| f.dt.clone())
+                    .unwrap_or(DataType::Unknown)
+            }
+            Expr::Unary(op, rhs) => {
+                if op.tok_type == TokenType::Not && self.type_of(rhs) == DataType::Bool {
+                    DataType::Bool
+                } else {
+                    self.type_of(rhs)
+                }
+            }
+            Expr::Binary(lhs, op, _) => {
+                match op.tok_type {
+                    TokenType::Less | TokenType::LessEqual |
+                    TokenType::Greater | TokenType::GreaterEqual |
+                    TokenType::EqualEqual | TokenType::NotEqual |
+                    TokenType::AndBool | TokenType::OrBool => DataType::Bool,
+                    _ => self.type_of(lhs)
+                }
+            }
+            Expr::Ternary(_, then_e, else_e) => {
+                unify_types(self.type_of(then_e), self.type_of(else_e)).unwrap_or(DataType::Unknown)
+            }
+            // a pointer variable has the type of its elements
+            Expr::Index(p, _) => self.type_of(p),
+            Expr::Postfix(a) => a.assign_target().map_or(DataType::Unknown, |t| self.type_of(&t)),
+            Expr::Callee(n, _, _) => {
+                let name = n.ident_to_string();
+                self.function_stack.get(&name)
+                    .or_else(|| self.extern_function_stack.get(&name))
+                    .map_or(DataType::Unknown, |f| f.return_type.clone().unwrap_or(DataType::Void))
+            }
+            _ => DataType::Unknown
+        }
+    }
+
+    fn find_enum_variant(&self, variant: &str) -> Option<Expr> {
+        self.enum_stack.iter().find_map(|(name, variants)| {
+            variants.iter().find(|(v, _)| v == variant)
+                .map(|(v, d)| Expr::EnumValue(name.clone(), v.clone(), *d))
+        })
+    }
+
+    /// Value of a name usable in constant expressions (const variables and enum variants)
+    fn const_lookup(&self, name: &str) -> Option<Value> {
+        if let Some(var) = self.pseudo_variable_stack.iter().rev().find(|f| f.name == name) {
+            return match &var.init {
+                Expr::Literal(v) if var.is_const && !matches!(v, Value::Str(_) | Value::List(_)) => Some(v.clone()),
+                _ => None
+            };
+        }
+        match self.find_enum_variant(name) {
+            Some(Expr::EnumValue(_, _, d)) => Some(Value::Number(d)),
+            _ => None
+        }
+    }
+
+    /// Evaluate a constant expression at compile time
+    pub fn eval_const(&self, expr: &Expr) -> Result<Value, String> {
+        const_eval::eval(expr, &|n| self.const_lookup(n))
+    }
+
+    /// Type names other than the builtin ones are enums, declared anywhere at the top level
+    fn check_data_type(&self, dt: &DataType) -> Result<(), String> {
+        match dt {
+            DataType::Enum(name) if !self.ast.iter().any(|e| matches!(e, Expr::EnumDecl(n, _) if n == name)) => {
+                Err(format!("Unknown data type '{}'", name))
+            }
+            _ => Ok(())
+        }
+    }
+
+    fn check_enum_type(&self, dt: &DataType, v: &Expr) -> Result<(), String> {
+        if let DataType::Enum(name) = dt {
+            if !self.enum_stack.contains_key(name) {
+                return Err(format!("Unknown data type '{}'", name));
+            }
+            let v_dt = self.type_of(v);
+            if v_dt != *dt {
+                return Err(format!("Cannot convert from {:?} to enum '{}'", v_dt, name));
+            }
+        }
+        Ok(())
+    }
+
+    /// `return v;` inside `f` must match its return type
+    fn check_return(&self, f: &Func_Header, v: Option<&Expr>) -> Result<(), String> {
+        let is_number = |dt: &DataType| matches!(dt,
+            DataType::Char | DataType::Short | DataType::Int | DataType::Long | DataType::Float | DataType::Suu);
+
+        match (&f.return_type, v) {
+            (None | Some(DataType::Void), None) => Ok(()),
+            (None | Some(DataType::Void), Some(_)) => Err(format!("Function '{}' returns void but a value is returned", f.name)),
+            (Some(dt), None) => Err(format!("Function '{}' must return a value of type {:?}", f.name, dt)),
+            (Some(dt), Some(v)) => {
+                self.check_enum_type(dt, v)?;
+                let got = self.type_of(v);
+                // numbers are converted implicitly, like in an assignment
+                if got == *dt || got == DataType::Unknown || (is_number(dt) && is_number(&got)) || f.is_ptr_dt {
+                    Ok(())
+                } else {
+                    Err(format!("Function '{}' returns {:?} but the returned value is {:?}", f.name, dt, got))
+                }
+            }
+        }
+    }
+
+    /// `p[i]` needs a pointer variable and an integer index, gives back the pointer
+    fn check_index(&mut self, p: &Expr, i: &Expr) -> Result<&mut VariableData, String> {
+        let Expr::Var(n) = p else {
+            return Err("Only pointer variables can be indexed".to_string());
+        };
+        if !matches!(self.type_of(i), DataType::Char | DataType::Short | DataType::Int | DataType::Long | DataType::Enum(_) | DataType::Unknown) {
+            return Err(format!("Index of '{}' must be an integer, got {:?}", n, self.type_of(i)));
+        }
+        let var = self.pseudo_variable_stack.iter_mut().rev().find(|v| v.name == *n)
+            .ok_or_else(|| format!("Variable '{}' not declared!", n))?;
+        if !var.is_ptr {
+            return Err(format!("Cannot index '{}', it is not a pointer", n));
+        }
+        var.is_used = true;
+        Ok(var)
+    }
+
+    fn check_condition(&self, cond: &Expr) -> Result<(), String> {
+        match self.type_of(cond) {
+            DataType::Bool | DataType::Unknown => Ok(()),
+            dt => Err(format!("Condition must be 'bool', got {:?}", dt))
         }
     }
 
@@ -162,84 +376,161 @@ impl<'a> Checker<'a> {
         let e = expr.clone();
         match expr {
             Expr::Statement(e) => self.visit(*e),
-            // bypass checking
-            Expr::Return(_v) => Ok(FAST { expr: e, is_used: true }),
-            Expr::Callee(n, _e) => {
-                
-                if !self.pseudo_function_stack.iter().any(|f| {
-                    let func = f.expr.get_function();
-                    n.ident_to_string() == func.0
-                }) {
-                    if !self.extern_function_stack.contains_key(&n.ident_to_string()) {
-                        return Err(format!("Function '{}' not declared!", n.ident_to_string()));
-                    }
+            Expr::Return(v, pos) => {
+                let v = match v {
+                    Some(v) => Some(Box::new(self.visit(*v)?.expr)),
+                    None => None
+                };
+                if let Some(f) = &self.current_function {
+                    self.check_return(f, v.as_deref())?;
+                }
+                Ok(FAST { expr: Expr::Return(v, pos), is_used: true })
+            }
+            Expr::Callee(n, _e, pos) => {
+                let name = n.ident_to_string();
+                let header = self.function_stack.get(&name)
+                    .or_else(|| self.extern_function_stack.get(&name))
+                    .ok_or_else(|| format!("Function '{}' not declared!", name))?;
+                if header.args.len() != _e.len() {
+                    return Err(format!("Function '{}' expects {} arguments, got {}", name, header.args.len(), _e.len()));
                 }
 
-                Ok(FAST {expr: e, is_used: true})
+                let args = _e.into_iter()
+                    .map(|f| self.visit(f).map(|f| f.expr))
+                    .collect::<Result<Vec<Expr>, String>>()?;
+
+                Ok(FAST {expr: Expr::Callee(n, args, pos), is_used: true})
             },
             Expr::Var(n) => {
                 if let Some(idx) = self.pseudo_variable_stack.iter().position(|f| {
                     f.name == n
                 }) {
                     self.pseudo_variable_stack[idx].is_used = true;
+                    if let Some(v) = self.const_lookup(&n) {
+                        return Ok(FAST { expr: Expr::Literal(v), is_used: true });
+                    }
                     Ok(FAST { expr:e, is_used: true }) // let codegen do the rest
+                } else if let Some(v) = self.find_enum_variant(&n) {
+                    Ok(FAST { expr: v, is_used: true })
                 } else {
                     Err(format!("Variable '{}' not declared!", n))
                 }
             }
-            Expr::WhileStmt(_s, _r) => Ok(FAST { expr: e, is_used: true}),
+            Expr::EnumValue(_, _, _) => Ok(FAST { expr: e, is_used: true }),
+            Expr::WhileStmt(cond, body, pos) => {
+                let cond = self.visit(*cond)?;
+                self.check_condition(&cond.expr)?;
+                let body = self.visit(*body)?;
+                Ok(FAST { expr: Expr::WhileStmt(Box::new(cond.expr), Box::new(body.expr), pos), is_used: true})
+            }
 
-            Expr::VarDecl(dt, is_p,is_const, n, init) => {
-                let (init_v,data_type) = 
-                    match check_literal_type(init, dt.clone(), is_p) {
-                        Ok(v) => (v.0,v.1),
-                        Err(s) => {
-                            throw_message("source",
-                             MessageType::Error, 1,1 , s.as_str());
-                            exit(1);
-                        }
-                    };
+            Expr::VarDecl(dt, is_p,is_const, n, init, pos) => {
+                self.check_data_type(&dt)?;
+                // fold first, so const variables can be initialized from other consts
+                let init = match init {
+                    Some(i) => Some(Box::new(self.visit(*i)?.expr)),
+                    None => None
+                };
+                // `let s = "..."` is a `char*`
+                let (init_v,data_type, is_p) = check_literal_type(init, dt.clone(), is_p)?;
 
                 if self.pseudo_variable_stack.iter().find(|f| {
                     f.name == n
                 }).is_some() {
                     return Err(format!("Variable '{}' already defined", n));
                 }
-                let k = self.visit(*init_v.clone().unwrap());
+                let k = match init_v {
+                    Some(i) => *i,
+                    None if is_const => return Err(format!("Constant variable '{}' must be initialized", n)),
+                    None => Expr::None
+                };
+                let data_type = if matches!(data_type, DataType::Unknown) {
+                    self.type_of(&k)
+                } else {
+                    data_type
+                };
+                if matches!(k, Expr::None) {
+                    if let DataType::Enum(name) = &data_type {
+                        if !self.enum_stack.contains_key(name) {
+                            return Err(format!("Unknown data type '{}'", name));
+                        }
+                    }
+                } else {
+                    self.check_enum_type(&data_type, &k)?;
+                }
 
                 self.pseudo_variable_stack.push(
-                    VariableData { dt: dt, name: n.clone(), is_const: is_const, is_ptr: is_p, init: k.clone()?.expr, is_used: false }
+                    VariableData { dt: data_type.clone(), name: n.clone(), is_const: is_const, is_ptr: is_p, init: k.clone(), is_used: false }
                 );
-                Ok(FAST { expr: Expr::VarDecl(data_type, is_p,is_const, n, Some(Box::new(k?.expr))), is_used: false })
+                let init = if matches!(k, Expr::None) { None } else { Some(Box::new(k)) };
+                Ok(FAST { expr: Expr::VarDecl(data_type, is_p,is_const, n, init, pos), is_used: false })
             },
 
 
-            Expr::Assign(n, v) => {
+            Expr::Assign(n, v, pos) => {
                 if !self.pseudo_variable_stack.iter().any(|v| *v.name == n) {
                     Err(format!("Undefined variable {}", n))
                 } else {
-                    let assign = self.pseudo_variable_stack.iter().find(|v| v.name == n).unwrap();
+                    let assign = self.pseudo_variable_stack.iter_mut().rev().find(|v| v.name == n).unwrap();
+                    assign.is_used = true;
 
                     if assign.is_const {
                         return Err(format!("Constant variable '{}' cannot be assignable!", n));
                     }
-                    let init_v = 
-                        if let Ok(v) = check_literal_type(Some(v), assign.dt.clone(), assign.is_ptr) {
-                            v.0.unwrap()
-                        } else {
-                            exit(1);
-                        };
-                    Ok(FAST { expr: Expr::Assign(n, init_v), is_used: true })
+                    let dt = assign.dt.clone();
+                    let init_v = check_literal_type(Some(v), dt.clone(), assign.is_ptr)?.0.unwrap();
+                    let init_v = self.visit(*init_v)?.expr;
+                    self.check_enum_type(&dt, &init_v)?;
+                    Ok(FAST { expr: Expr::Assign(n, Box::new(init_v), pos), is_used: true })
                 }
             }
 
+            Expr::Index(p, i) => {
+                self.check_index(&p, &i)?;
+                let i = self.visit(*i)?.expr;
+                Ok(FAST { expr: Expr::Index(p, Box::new(i)), is_used: true })
+            }
+            Expr::SetIndex(p, i, v, pos) => {
+                let var = self.check_index(&p, &i)?;
+                if var.is_const {
+                    return Err(format!("Constant variable '{}' cannot be assignable!", var.name));
+                }
+                // an element has the pointer's type, without the pointer
+                let dt = var.dt.clone();
+                let i = self.visit(*i)?.expr;
+                let v = check_literal_type(Some(v), dt.clone(), false)?.0.unwrap();
+                let v = self.visit(*v)?.expr;
+                self.check_enum_type(&dt, &v)?;
+                Ok(FAST { expr: Expr::SetIndex(p, Box::new(i), Box::new(v), pos), is_used: true })
+            }
+            Expr::Postfix(a) => {
+                let a = self.visit(*a)?.expr;
+                Ok(FAST { expr: Expr::Postfix(Box::new(a)), is_used: true })
+            }
+
             Expr::Literal(_v) => Ok(FAST { expr: e, is_used: true }),
             Expr::FuncStmt(f,body) => {
                 //self.visit(*b)
                 //add to pseudo_variable_stack
 
+                // parameters are only visible inside the body
+                let scope = self.pseudo_variable_stack.len();
+                self.pseudo_variable_stack.extend(f.args.iter().map(|(dt, name, is_ptr)| VariableData {
+                    dt: dt.clone(), name: name.clone(), is_const: false, is_ptr: *is_ptr, init: Expr::None, is_used: true
+                }));
+
+                let outer = self.current_function.replace(f.clone());
+                let body = self.visit(*body);
+                self.current_function = outer;
+                self.pseudo_variable_stack.truncate(scope);
+                let body = body?.expr;
+
+                if !matches!(f.return_type, None | Some(DataType::Void)) && !always_returns(&body) {
+                    return Err(format!("Function '{}' does not return a value on every path", f.name));
+                }
+
                 let f=FAST {
-                    expr: Expr::FuncStmt(f, Box::new(self.visit(*body)?.expr)),
+                    expr: Expr::FuncStmt(f, Box::new(body)),
                     is_used: true
                 };
 
@@ -248,16 +539,26 @@ impl<'a> Checker<'a> {
                 Ok(f)
             }
             Expr::Block(b) => {
-                let bl = self.check_ast(b)?;
+                let mut bl = self.check_ast(b)?;
+                if let Some(idx) = bl.iter().position(always_returns) {
+                    if idx + 1 < bl.len() {
+                        let name = self.current_function.as_ref().map_or("<global>".to_string(), |f| f.name.clone());
+                        self.warn(bl[idx + 1].pos().or(bl[idx].pos()), &format!("unreachable code after return in function '{}'", name));
+                        bl.truncate(idx + 1);
+                    }
+                }
                 Ok(FAST { expr: Expr::Block(bl),
                     is_used: true
                 })
             }
-            Expr::Binary(mut lhs, op, mut rhs) => {
-                let lhs = self.visit(lhs.visit());
-                let rhs = self.visit(rhs.visit());
+            Expr::Binary(lhs, op, rhs) => {
+                let lhs = self.visit(*lhs)?.expr;
+                let rhs = self.visit(*rhs)?.expr;
 
-                let e = Expr::Binary(Box::new(lhs?.expr),op,Box::new(rhs?.expr)).visit();
+                let e = match (&lhs, &rhs) {
+                    (Expr::Literal(l), Expr::Literal(r)) => Expr::Literal(const_eval::eval_binary(l.clone(), &op, r.clone())?),
+                    _ => Expr::Binary(Box::new(lhs),op,Box::new(rhs))
+                };
 
                 Ok(
                     FAST {
@@ -266,10 +567,39 @@ impl<'a> Checker<'a> {
                     }
                 )
             }
-            Expr::IfStmt(cond,then_bl ,else_bl ) => {
+            Expr::Unary(op, rhs) => {
+                let rhs = self.visit(*rhs)?.expr;
+                if op.tok_type == TokenType::Tilde {
+                    let dt = self.type_of(&rhs);
+                    if matches!(dt, DataType::Bool | DataType::Float | DataType::Suu) {
+                        return Err(format!("Operator '~' cannot be applied to {:?}", dt));
+                    }
+                }
+                let e = match &rhs {
+                    Expr::Literal(v) => Expr::Literal(const_eval::eval_unary(&op, v.clone())?),
+                    _ => Expr::Unary(op, Box::new(rhs))
+                };
+                Ok(FAST { expr: e, is_used: true })
+            }
+            Expr::Grouping(e) => self.visit(*e),
+            Expr::Ternary(cond, then_e, else_e) => {
+                let cond = self.visit(*cond)?.expr;
+                self.check_condition(&cond)?;
+                let then_e = self.visit(*then_e)?.expr;
+                let else_e = self.visit(*else_e)?.expr;
+                unify_types(self.type_of(&then_e), self.type_of(&else_e))?;
+
+                let e = match cond {
+                    Expr::Literal(Value::Boolean(c)) => if c { then_e } else { else_e },
+                    c => Expr::Ternary(Box::new(c), Box::new(then_e), Box::new(else_e))
+                };
+                Ok(FAST { expr: e, is_used: true })
+            }
+            Expr::IfStmt(cond,then_bl ,else_bl, pos) => {
                 let cond = self.visit(*cond)?;
+                self.check_condition(&cond.expr)?;
                 if matches!(cond.expr, Expr::Literal(_)) {
-                    if cond.expr.to_value() == Value::Number(0) {
+                    if cond.expr.to_value() == Value::Boolean(false) {
                         if matches!(*else_bl, Expr::None) {
                             return Ok(
                                 FAST{
@@ -278,10 +608,10 @@ impl<'a> Checker<'a> {
                                 }
                             );
                         } else {
-                            return Ok(FAST { expr: *else_bl, is_used: true });
+                            return self.visit(*else_bl);
                         }
                     } else {
-                        return Ok(FAST { expr: *then_bl, is_used: true });
+                        return self.visit(*then_bl);
                     }
                 }
                 let then_bl = self.visit(*then_bl)?;
@@ -291,7 +621,7 @@ impl<'a> Checker<'a> {
                     FAST {expr: Expr::None, is_used: false}
                 };
                 Ok(FAST {
-                    expr: Expr::IfStmt(Box::new(cond.expr), Box::new(then_bl.expr), Box::new(else_bl.expr)),
+                    expr: Expr::IfStmt(Box::new(cond.expr), Box::new(then_bl.expr), Box::new(else_bl.expr), pos),
                     is_used: true
                 })
             }
@@ -307,14 +637,114 @@ impl<'a> Checker<'a> {
                     }
                 )
             }
-            o => todo!("Expression {:?} does not implemented yet!", o)
+            Expr::SwitchStmt(value, cases, default, pos) => {
+                let value = self.visit(*value)?.expr;
+                let value_dt = self.type_of(&value);
+                let is_integer = |dt: &DataType| matches!(dt, DataType::Char | DataType::Short | DataType::Int | DataType::Long);
+                if !(is_integer(&value_dt) || matches!(value_dt, DataType::Bool | DataType::Enum(_) | DataType::Unknown)) {
+                    return Err(format!("Cannot switch on {:?}, the value must be an integer, bool or enum", value_dt));
+                }
+
+                let mut seen: Vec<i64> = Vec::new();
+                let mut checked_cases = Vec::new();
+                for (label, body) in cases {
+                    let label = self.visit(label)?.expr.visit();
+                    let case_v = match label.case_value() {
+                        Some(v) => v,
+                        None => return Err(format!("Case label must be a constant, got {:?}", label))
+                    };
+                    let label_name = match &label {
+                        Expr::EnumValue(_, v, _) => v.clone(),
+                        Expr::Literal(Value::Boolean(b)) => b.to_string(),
+                        Expr::Literal(Value::Char(c)) => format!("'{}'", c),
+                        _ => case_v.to_string()
+                    };
+                    if seen.contains(&case_v) {
+                        return Err(format!("Duplicate case label '{}' in switch", label_name));
+                    }
+                    let label_dt = self.type_of(&label);
+                    match &value_dt {
+                        DataType::Enum(name) if label_dt != value_dt => {
+                            return Err(format!("Case label '{}' is not a variant of enum '{}'", label_name, name));
+                        }
+                        // chars are small ints, `case 'a':` on an int is fine
+                        dt if is_integer(dt) && !is_integer(&label_dt) => {
+                            return Err(format!("Case label '{}' is {:?}, but the switch value is {:?}", label_name, label_dt, dt));
+                        }
+                        DataType::Bool if label_dt != DataType::Bool => {
+                            return Err(format!("Case label '{}' is {:?}, but the switch value is Bool", label_name, label_dt));
+                        }
+                        _ => {}
+                    }
+                    seen.push(case_v);
+                    checked_cases.push((label, self.visit(body)?.expr));
+                }
+
+                if let DataType::Enum(name) = &value_dt {
+                    if matches!(*default, Expr::None) {
+                        let missing = self.enum_stack.get(name).map(|v| {
+                            v.iter().filter(|(_, d)| !seen.contains(d)).map(|(n, _)| n.clone()).collect::<Vec<String>>()
+                        }).unwrap_or_default();
+                        if !missing.is_empty() {
+                            self.warn(Some(pos), &format!("switch on enum '{}' does not handle: {}", name, missing.join(", ")));
+                        }
+                    }
+                }
+
+                let default = if matches!(*default, Expr::None) {
+                    Expr::None
+                } else {
+                    self.visit(*default)?.expr
+                };
+                Ok(FAST {
+                    expr: Expr::SwitchStmt(Box::new(value), checked_cases, Box::new(default), pos),
+                    is_used: true
+                })
+            }
+            Expr::EnumDecl(name, variants) => {
+                if self.enum_stack.contains_key(&name) {
+                    return Err(format!("Enum '{}' already defined", name));
+                }
+
+                let mut resolved: Vec<(String, i64)> = Vec::new();
+                let mut next = 0;
+                for (variant, discriminant) in variants {
+                    if resolved.iter().any(|(v, _)| *v == variant) || self.find_enum_variant(&variant).is_some() {
+                        return Err(format!("Enum variant '{}' already defined", variant));
+                    }
+                    if let Some(d) = discriminant {
+                        // earlier variants of the same enum can be referenced too
+                        let lookup = |n: &str| {
+                            resolved.iter().find(|(v, _)| v == n).map(|(_, d)| Value::Number(*d))
+                                .or_else(|| self.const_lookup(n))
+                        };
+                        next = const_eval::eval_int(&d, &lookup)
+                            .map_err(|s| format!("Enum discriminant of '{}': {}", variant, s))?;
+                    }
+                    // enums are lowered as int
+                    if i32::try_from(next).is_err() {
+                        return Err(format!("Enum discriminant of '{}' is {}, out of the range of int", variant, next));
+                    }
+                    resolved.push((variant.clone(), next));
+                    next += 1;
+                }
+
+                self.enum_stack.insert(name.clone(), resolved.clone());
+                Ok(FAST {
+                    expr: Expr::EnumDecl(name, resolved.into_iter().map(|(v, d)| {
+                        (v, Some(Box::new(Expr::Literal(Value::Number(d)))))
+                    }).collect()),
+                    is_used: true
+                })
+            }
+            o => Err(format!("Expression {:?} does not implemented yet!", o))
         }
     }
 
     fn check_ast(&mut self, ast: Vec<Expr>) -> Result<Vec<Expr>, String> {
         let mut res = Vec::new();
         let mut original_fast = ast.iter().map(|f|
-            catch_error(self.visit(f.clone()))).collect::<Vec<FAST>>();
+            self.visit(f.clone())).collect::<Result<Vec<FAST>, String>>()?;
 
         for func_f in &self.pseudo_function_stack {
             original_fast.iter().find(|f| {
@@ -328,7 +758,7 @@ impl<'a> Checker<'a> {
 
         for var_decl in &self.pseudo_variable_stack {
             if let Some(idx) = original_fast.iter().position(|f|{
-                if let Expr::VarDecl(_, _, _, name, _) = &f.expr {
+                if let Expr::VarDecl(_, _, _, name, _, _) = &f.expr {
                     *name == var_decl.name && var_decl.is_used
                 } else {
                     false
@@ -350,8 +780,47 @@ impl<'a> Checker<'a> {
         Ok(res)
     }
 
+    /// Register every top-level function before any body is checked, so calls
+    /// can refer to functions defined later in the file or to themselves
+    fn collect_declarations(&mut self) -> Result<(), String> {
+        for expr in self.ast.iter() {
+            if let Expr::FuncStmt(f, _) | Expr::Extern(f) = expr {
+                for dt in f.args.iter().map(|(dt, _, _)| dt).chain(&f.return_type) {
+                    self.check_data_type(dt)?;
+                }
+            }
+            match expr {
+                Expr::FuncStmt(f, _) => {
+                    if self.function_stack.contains_key(&f.name) {
+                        return Err(format!("Function '{}' already defined", f.name));
+                    }
+                    self.function_stack.insert(f.name.clone(), f.clone());
+                }
+                Expr::Extern(f) => {
+                    self.extern_function_stack.insert(f.name.clone(), f.clone());
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
     pub fn check(&mut self) -> Result<Vec<Expr>, String> {
-        self.check_ast(self.ast.to_vec())
+        self.collect_declarations()?;
+        let mut res = self.check_ast(self.ast.to_vec())?;
+
+        // globals are laid out by the compiler, so their initializer is folded to a literal of their type
+        for expr in res.iter_mut() {
+            if let Expr::VarDecl(dt, is_ptr, _, name, Some(init), _) = expr {
+                let v = self.eval_const(init)
+                    .map_err(|s| format!("Global variable '{}' initializer must be a constant expression: {}", name, s))?;
+                if !matches!(**init, Expr::Literal(_) | Expr::EnumValue(..)) {
+                    let (v, v_dt, _) = check_literal_type(Some(Box::new(Expr::Literal(v))), dt.clone(), *is_ptr)?;
+                    (*init, *dt) = (v.unwrap(), v_dt);
+                }
+            }
+        }
+        Ok(res)
     }
 
 }
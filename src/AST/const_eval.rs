@@ -0,0 +1,177 @@
+// compile-time evaluation of constant expressions
+
+use crate::{token::{token_type::TokenType, TokenData}, Value::Value};
+use super::expr_node::Expr;
+
+enum Operand {
+    Int(i64),
+    Float(f32),
+    Double(f64),
+    Bool(bool)
+}
+
+fn to_operand(v: Value) -> Result<Operand, String> {
+    match v {
+        Value::Number(n) => Ok(Operand::Int(n)),
+        Value::Char(c) => Ok(Operand::Int(c as i64)),
+        Value::Float(f) => Ok(Operand::Float(f)),
+        Value::Double(d) => Ok(Operand::Double(d)),
+        Value::Boolean(b) => Ok(Operand::Bool(b)),
+        o => Err(format!("{:?} cannot be used in a constant expression", o))
+    }
+}
+
+fn overflow(lhs: i64, op: &TokenData, rhs: i64) -> String {
+    format!("Integer overflow in constant expression: {} {:?} {}", lhs, op.tok_type, rhs)
+}
+
+fn int_binary(lhs: i64, op: &TokenData, rhs: i64) -> Result<Value, String> {
+    let v = match op.tok_type {
+        TokenType::Plus => lhs.checked_add(rhs),
+        TokenType::Minus => lhs.checked_sub(rhs),
+        TokenType::Star => lhs.checked_mul(rhs),
+        TokenType::Slash | TokenType::Modulo => {
+            if rhs == 0 {
+                return Err("Division by zero in constant expression".to_string());
+            }
+            if op.tok_type == TokenType::Slash { lhs.checked_div(rhs) } else { lhs.checked_rem(rhs) }
+        }
+        TokenType::ShiftLeft | TokenType::ShiftRight => {
+            if !(0..64).contains(&rhs) {
+                return Err(format!("Shift amount {} out of range in constant expression", rhs));
+            }
+            if op.tok_type == TokenType::ShiftLeft {
+                lhs.checked_shl(rhs as u32).filter(|v| v >> rhs == lhs)
+            } else {
+                lhs.checked_shr(rhs as u32)
+            }
+        }
+        TokenType::And => Some(lhs & rhs),
+        TokenType::Or => Some(lhs | rhs),
+        TokenType::Caret => Some(lhs ^ rhs),
+        TokenType::Less => return Ok(Value::Boolean(lhs < rhs)),
+        TokenType::LessEqual => return Ok(Value::Boolean(lhs <= rhs)),
+        TokenType::Greater => return Ok(Value::Boolean(lhs > rhs)),
+        TokenType::GreaterEqual => return Ok(Value::Boolean(lhs >= rhs)),
+        TokenType::EqualEqual => return Ok(Value::Boolean(lhs == rhs)),
+        TokenType::NotEqual => return Ok(Value::Boolean(lhs != rhs)),
+        ref o => return Err(format!("Operator {:?} cannot be applied to integers", o))
+    };
+    v.map(Value::Number).ok_or_else(|| overflow(lhs, op, rhs))
+}
+
+fn double_binary(lhs: f64, op: &TokenData, rhs: f64) -> Result<Value, String> {
+    Ok(match op.tok_type {
+        TokenType::Plus => Value::Double(lhs + rhs),
+        TokenType::Minus => Value::Double(lhs - rhs),
+        TokenType::Star => Value::Double(lhs * rhs),
+        TokenType::Slash | TokenType::Modulo => {
+            if rhs == 0.0 {
+                return Err("Division by zero in constant expression".to_string());
+            }
+            Value::Double(if op.tok_type == TokenType::Slash { lhs / rhs } else { lhs % rhs })
+        }
+        TokenType::Less => Value::Boolean(lhs < rhs),
+        TokenType::LessEqual => Value::Boolean(lhs <= rhs),
+        TokenType::Greater => Value::Boolean(lhs > rhs),
+        TokenType::GreaterEqual => Value::Boolean(lhs >= rhs),
+        TokenType::EqualEqual => Value::Boolean(lhs == rhs),
+        TokenType::NotEqual => Value::Boolean(lhs != rhs),
+        ref o => return Err(format!("Operator {:?} cannot be applied to floating point values", o))
+    })
+}
+
+/// Evaluate `lhs op rhs` where both sides are already constant
+pub fn eval_binary(lhs: Value, op: &TokenData, rhs: Value) -> Result<Value, String> {
+    if let (Value::Str(l), TokenType::Plus, Value::Str(r)) = (&lhs, &op.tok_type, &rhs) {
+        return Ok(Value::Str(format!("{}{}", l, r)));
+    }
+    match (to_operand(lhs)?, to_operand(rhs)?) {
+        (Operand::Bool(l), Operand::Bool(r)) => {
+            Ok(Value::Boolean(match op.tok_type {
+                TokenType::AndBool => l && r,
+                TokenType::OrBool => l || r,
+                TokenType::EqualEqual => l == r,
+                TokenType::NotEqual => l != r,
+                TokenType::And => l & r,
+                TokenType::Or => l | r,
+                TokenType::Caret => l ^ r,
+                ref o => return Err(format!("Operator {:?} cannot be applied to bool", o))
+            }))
+        }
+        (Operand::Bool(_), _) | (_, Operand::Bool(_)) => {
+            Err(format!("Operator {:?} cannot mix bool with other types", op.tok_type))
+        }
+        (Operand::Int(l), Operand::Int(r)) => int_binary(l, op, r),
+        (Operand::Float(l), Operand::Float(r)) => {
+            // keep f32 precision when both sides are float
+            double_binary(l as f64, op, r as f64).map(|v| match v {
+                Value::Double(d) => Value::Float(d as f32),
+                v => v
+            })
+        }
+        (l, r) => {
+            let to_f64 = |o: Operand| match o {
+                Operand::Int(n) => n as f64,
+                Operand::Float(f) => f as f64,
+                Operand::Double(d) => d,
+                Operand::Bool(b) => b as i64 as f64
+            };
+            double_binary(to_f64(l), op, to_f64(r))
+        }
+    }
+}
+
+/// Evaluate `op rhs` where rhs is already constant
+pub fn eval_unary(op: &TokenData, rhs: Value) -> Result<Value, String> {
+    match (&op.tok_type, to_operand(rhs.clone())?) {
+        (TokenType::Minus, Operand::Int(n)) => {
+            n.checked_neg().map(Value::Number).ok_or_else(|| format!("Integer overflow in constant expression: -({})", n))
+        }
+        (TokenType::Minus, Operand::Float(f)) => Ok(Value::Float(-f)),
+        (TokenType::Minus, Operand::Double(d)) => Ok(Value::Double(-d)),
+        (TokenType::Not, Operand::Bool(b)) => Ok(Value::Boolean(!b)),
+        (TokenType::Tilde, Operand::Int(n)) => Ok(Value::Number(!n)),
+        // same semantic as the VM's NOT
+        (TokenType::Not, Operand::Int(_)) => Ok(!rhs),
+        (o, _) => Err(format!("Operator {:?} cannot be applied to {:?}", o, rhs))
+    }
+}
+
+/// Evaluate a whole expression tree, `lookup` resolves names (const variables, enum variants)
+pub fn eval(expr: &Expr, lookup: &dyn Fn(&str) -> Option<Value>) -> Result<Value, String> {
+    match expr {
+        Expr::Literal(v) => Ok(v.clone()),
+        Expr::EnumValue(_, _, d) => Ok(Value::Number(*d)),
+        Expr::Grouping(e) | Expr::Statement(e) => eval(e, lookup),
+        Expr::Var(n) => lookup(n).ok_or_else(|| format!("'{}' is not a constant", n)),
+        Expr::Unary(op, rhs) => eval_unary(op, eval(rhs, lookup)?),
+        Expr::Ternary(cond, then_e, else_e) => {
+            match eval(cond, lookup)? {
+                Value::Boolean(true) => eval(then_e, lookup),
+                Value::Boolean(false) => eval(else_e, lookup),
+                o => Err(format!("Condition must be 'bool', got {:?}", o))
+            }
+        }
+        Expr::Binary(lhs, op, rhs) => {
+            let lhs = eval(lhs, lookup)?;
+            // short circuit, so `false && (1/0 == 0)` is fine
+            match (&op.tok_type, &lhs) {
+                (TokenType::AndBool, Value::Boolean(false)) => return Ok(Value::Boolean(false)),
+                (TokenType::OrBool, Value::Boolean(true)) => return Ok(Value::Boolean(true)),
+                _ => {}
+            }
+            eval_binary(lhs, op, eval(rhs, lookup)?)
+        }
+        o => Err(format!("Expression is not constant: {:?}", o))
+    }
+}
+
+/// Same as `eval` but the result must be an integer (array sizes, enum discriminants)
+pub fn eval_int(expr: &Expr, lookup: &dyn Fn(&str) -> Option<Value>) -> Result<i64, String> {
+    match eval(expr, lookup)? {
+        Value::Number(n) => Ok(n),
+        Value::Char(c) => Ok(c as i64),
+        o => Err(format!("Expect a constant integer, got {:?}", o))
+    }
+}
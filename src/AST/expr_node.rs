@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use crate::{token::TokenData, Value::{self}};
+use super::const_eval;
 
 
 #[derive(Debug, Clone, PartialEq)]
@@ -11,16 +12,19 @@ pub enum DataType {
     Long,
     Float,
     Suu, // replace for double data type
+    Bool,
     Void,
+    /// C-style enum, lowered as int
+    Enum(String),
     Unknown
 }
 
 impl DataType {
     pub fn size(&self) -> u32 {
         match self {
-            DataType::Char => 1,
+            DataType::Char | DataType::Bool => 1,
             DataType::Short => 2,
-            DataType::Int | DataType::Float => 4,
+            DataType::Int | DataType::Float | DataType::Enum(_) => 4,
             DataType::Long | DataType::Suu => 8,
             _ => 0
         }
@@ -35,6 +39,19 @@ pub struct Func_Header {
     pub is_ptr_dt: bool
 }
 
+/// Source position of a statement or call, line and column from 1
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Pos {
+    pub line: usize,
+    pub col: usize
+}
+
+impl Pos {
+    pub fn of(t: &TokenData) -> Self {
+        Self { line: t.line, col: t.col }
+    }
+}
+
 #[derive(Debug, Clone,PartialEq)]
 pub enum Expr {
     /// Binary Expression (Expr, Operator, Expr)
@@ -47,22 +64,37 @@ pub enum Expr {
     Var(String),
     Statement(Box<Expr>),
     Block(Vec<Expr>),
-    Assign(String, Box<Expr>),
-
-    IfStmt(Box<Expr>, Box<Expr>, Box<Expr>),
-    WhileStmt(Box<Expr>, Box<Expr>),
+    Assign(String, Box<Expr>, Pos),
+    /// Index(pointer, index), `p[i]`, `*p` is `p[0]`
+    Index(Box<Expr>, Box<Expr>),
+    /// SetIndex(pointer, index, value), `p[i] = v`
+    SetIndex(Box<Expr>, Box<Expr>, Box<Expr>, Pos),
+    /// Postfix(assignment) of `x++`, evaluates to the value before the assignment
+    Postfix(Box<Expr>),
+
+    IfStmt(Box<Expr>, Box<Expr>, Box<Expr>, Pos),
+    /// Ternary(cond, then, else), `cond ? a : b`
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+    WhileStmt(Box<Expr>, Box<Expr>, Pos),
+    /// SwitchStmt(value, [(case label, body)], default body)
+    SwitchStmt(Box<Expr>, Vec<(Expr, Expr)>, Box<Expr>, Pos),
     /// FuncStmt(name, args, body, return_type)
     FuncStmt(Func_Header, Box<Expr>),
-    Callee(Box<Expr>, Vec<Expr>),
+    Callee(Box<Expr>, Vec<Expr>, Pos),
 
     /// Var declare Statement VarDecl(dt, is_pointer, is_constant, name, initializer)
-    VarDecl(DataType, bool, bool, String, Option<Box<Expr>>),
+    VarDecl(DataType, bool, bool, String, Option<Box<Expr>>, Pos),
     List(Vec<Value::Value>),
-    Return(Option<Box<Expr>>),
+    Return(Option<Box<Expr>>, Pos),
 
     /// Extern declare statement
     Extern(Func_Header),
 
+    /// Enum declare statement EnumDecl(name, [(variant, discriminant)])
+    EnumDecl(String, Vec<(String, Option<Box<Expr>>)>),
+    /// Resolved enum variant EnumValue(enum name, variant, discriminant)
+    EnumValue(String, String, i64),
+
     None
 }
 
@@ -77,47 +109,58 @@ impl<'a> Expr
                 let lhs = lhs.visit();
                 let rhs = rhs.visit();
 
-
-                let result = if matches!(lhs, Expr::Literal(_)) && matches!(rhs, Expr::Literal(_)) {
-                    match op.tok_type {
-                        crate::token::token_type::TokenType::Plus => Expr::Literal(lhs.to_value()+rhs.to_value()),
-                        crate::token::token_type::TokenType::Minus => Expr::Literal(lhs.to_value()-rhs.to_value()),
-                        crate::token::token_type::TokenType::Star => Expr::Literal(lhs.to_value()*rhs.to_value()),
-                        crate::token::token_type::TokenType::Slash => Expr::Literal(lhs.to_value()/rhs.to_value()),
-                        crate::token::token_type::TokenType::Less => Expr::Literal(Value::Value::Number((lhs.to_value()<rhs.to_value()) as i64)),
-                        crate::token::token_type::TokenType::Greater => Expr::Literal(Value::Value::Number((lhs.to_value()>rhs.to_value()) as i64)),
-                        crate::token::token_type::TokenType::LessEqual => Expr::Literal(Value::Value::Number((lhs.to_value()<=rhs.to_value()) as i64)),
-                        crate::token::token_type::TokenType::GreaterEqual => Expr::Literal(Value::Value::Number((lhs.to_value()>=rhs.to_value()) as i64)),
-                        crate::token::token_type::TokenType::EqualEqual => Expr::Literal(Value::Value::Number((lhs.to_value()==rhs.to_value()) as i64)),
-                        crate::token::token_type::TokenType::ShiftLeft => Expr::Literal(Value::Value::Number((lhs.to_value()<<rhs.to_value()).to_literal())),
-                        crate::token::token_type::TokenType::ShiftRight => Expr::Literal(Value::Value::Number((lhs.to_value()>>rhs.to_value()).to_literal())),
-                        crate::token::token_type::TokenType::Or => Expr::Literal(Value::Value::Number((lhs.to_value()|rhs.to_value()).to_literal())),
-                        _ => {
-                            unimplemented!()
-                        }
+                // fold what can be folded, errors (e.g. division by zero) are reported by the checker
+                if let (Expr::Literal(l), Expr::Literal(r)) = (&lhs, &rhs) {
+                    if let Ok(v) = const_eval::eval_binary(l.clone(), op, r.clone()) {
+                        return Expr::Literal(v);
                     }
-                } else {
-                    Expr::Binary(Box::new(lhs), op.clone(),Box::new(rhs))
-                };
-
-                result
-
+                }
+                Expr::Binary(Box::new(lhs), op.clone(),Box::new(rhs))
             },
             Expr::Unary(op, rhs) => {
                 let rhs = rhs.visit();
-                Expr::Literal(match op.tok_type {
-                    crate::token::token_type::TokenType::Minus => -rhs.to_value(),
-                    crate::token::token_type::TokenType::Not => !rhs.to_value(),
-                    _ => unimplemented!()
-                })
+                if let Expr::Literal(v) = &rhs {
+                    if let Ok(v) = const_eval::eval_unary(op, v.clone()) {
+                        return Expr::Literal(v);
+                    }
+                }
+                Expr::Unary(op.clone(), Box::new(rhs))
             },
-            Expr::VarDecl(_,_,_,_,_) => {
+            Expr::Ternary(cond, then_e, else_e) => {
+                match cond.visit() {
+                    Expr::Literal(Value::Value::Boolean(true)) => then_e.visit(),
+                    Expr::Literal(Value::Value::Boolean(false)) => else_e.visit(),
+                    c => Expr::Ternary(Box::new(c), Box::new(then_e.visit()), Box::new(else_e.visit()))
+                }
+            }
+            Expr::VarDecl(_,_,_,_,_,_) => {
                 Expr::None
             }
             Expr::Var(_) => self.clone(),
+            Expr::EnumValue(_, _, _) => self.clone(),
             Expr::Statement(st) => st.visit(),
-            Expr::Callee(_, _) => self.clone(),
-            o => todo!("Expr visit does not implemented {:?} yet ", o)
+            Expr::Callee(_, _, _) => self.clone(),
+            o => o.clone()
+        }
+    }
+    /// Where a statement or call starts, expression statements give the
+    /// position of their first operator
+    pub fn pos(&self) -> Option<Pos> {
+        match self {
+            Expr::Assign(_, _, p) | Expr::SetIndex(_, _, _, p) | Expr::IfStmt(_, _, _, p) | Expr::WhileStmt(_, _, p) | Expr::SwitchStmt(_, _, _, p)
+            | Expr::Callee(_, _, p) | Expr::VarDecl(_, _, _, _, _, p) | Expr::Return(_, p) => Some(*p),
+            Expr::Binary(_, op, _) | Expr::Unary(op, _) => Some(Pos::of(op)),
+            Expr::Statement(e) | Expr::Grouping(e) | Expr::Ternary(e, _, _) | Expr::Postfix(e) => e.pos(),
+            Expr::Block(v) => v.iter().find_map(|e| e.pos()),
+            _ => None
+        }
+    }
+    /// What an assignment writes to, as an expression reading it
+    pub fn assign_target(&self) -> Option<Expr> {
+        match self {
+            Expr::Assign(n, _, _) => Some(Expr::Var(n.clone())),
+            Expr::SetIndex(p, i, _, _) => Some(Expr::Index(p.clone(), i.clone())),
+            _ => None
         }
     }
     pub fn to_value(&self) -> Value::Value {
@@ -127,6 +170,16 @@ impl<'a> Expr
             _ => Value::Value::Null
         }
     }
+    /// Integer value of a constant switch case label
+    pub fn case_value(&self) -> Option<i64> {
+        match self {
+            Expr::Literal(Value::Value::Number(n)) => Some(*n),
+            Expr::Literal(Value::Value::Char(c)) => Some(*c as i64),
+            Expr::Literal(Value::Value::Boolean(b)) => Some(*b as i64),
+            Expr::EnumValue(_, _, d) => Some(*d),
+            _ => None
+        }
+    }
     pub fn ident_to_string(&self) -> String {
         match self {
             Expr::Identifier(s) => s.clone(),
@@ -163,9 +216,12 @@ impl<'a> Expr
                     "float" => Ok(DataType::Float),
                     "suu" => Ok(DataType::Suu),
                     "void" => Ok(DataType::Void),
+                    "bool" => Ok(DataType::Bool),
                     _ => Ok(DataType::Unknown)
                 }
             }
+            // user defined type name, the checker makes sure it is a declared enum
+            Expr::Var(n) => Ok(DataType::Enum(n.clone())),
             _ => Err(format!("Expr type expect to be identifier, got {:#?}", self))
         }
     }
@@ -1,27 +1,11 @@
-use std::{collections::VecDeque, process::exit};
+use std::collections::VecDeque;
 
-use crate::{token::{token_type::TokenType, MetaData, TokenData}, MessageHandler::message_handler::throw_message, Value::Value, AST::expr_node::{DataType, Func_Header}};
+use crate::{token::{token_type::TokenType, MetaData, TokenData}, MessageHandler::message_handler::located, Value::Value, AST::expr_node::{DataType, Func_Header, Pos}};
 pub mod expr_node;
 pub mod ast_checker;
+pub mod const_eval;
 use expr_node::Expr;
 
-macro_rules! create_binary {
-    ($self:ident, $name: ident, $lhs: expr, $tok_list: expr, $rhs: expr) => {
-        fn $name(&mut $self) -> Box<Expr> {
-            let mut lhs = $lhs;
-
-            while $self.match_token(&mut $tok_list) {
-                let op = $self.previous();
-                let rhs = $rhs;
-                lhs = Box::new(Expr::Binary(lhs, op, rhs));
-            }
-
-            lhs
-        }
-    };
-}
-
-
 macro_rules! check_keyword {
     ($self:ident, $keyword: expr, $func: expr) => {
         if $self.peek().identifier == $keyword {
@@ -32,6 +16,51 @@ macro_rules! check_keyword {
 }
 
 
+enum Assoc {
+    Left,
+    Right
+}
+
+const ASSIGN_PREC: u8 = 1;
+
+/// Precedence and associativity of infix operators, same order as C.
+/// Higher binds tighter, unary operators bind tighter than all of these.
+fn infix_binding(t: &TokenType) -> Option<(u8, Assoc)> {
+    let binding = match t {
+        TokenType::Star | TokenType::Slash | TokenType::Modulo => (12, Assoc::Left),
+        TokenType::Plus | TokenType::Minus => (11, Assoc::Left),
+        TokenType::ShiftLeft | TokenType::ShiftRight => (10, Assoc::Left),
+        TokenType::Less | TokenType::LessEqual | TokenType::Greater | TokenType::GreaterEqual => (9, Assoc::Left),
+        TokenType::EqualEqual | TokenType::NotEqual => (8, Assoc::Left),
+        TokenType::And => (7, Assoc::Left),
+        TokenType::Caret => (6, Assoc::Left),
+        TokenType::Or => (5, Assoc::Left),
+        TokenType::AndBool => (4, Assoc::Left),
+        TokenType::OrBool => (3, Assoc::Left),
+        TokenType::Question => (2, Assoc::Right),
+        t if *t == TokenType::Equal || compound_op(t).is_some() => (ASSIGN_PREC, Assoc::Right),
+        _ => return None
+    };
+    Some(binding)
+}
+
+/// Binary operator behind a compound assignment, `+=` -> `+`
+fn compound_op(t: &TokenType) -> Option<TokenType> {
+    Some(match t {
+        TokenType::PlusEqual => TokenType::Plus,
+        TokenType::MinusEqual => TokenType::Minus,
+        TokenType::StarEqual => TokenType::Star,
+        TokenType::SlashEqual => TokenType::Slash,
+        TokenType::ModuloEqual => TokenType::Modulo,
+        TokenType::ShiftLeftEqual => TokenType::ShiftLeft,
+        TokenType::ShiftRightEqual => TokenType::ShiftRight,
+        TokenType::AndEqual => TokenType::And,
+        TokenType::OrEqual => TokenType::Or,
+        TokenType::CaretEqual => TokenType::Caret,
+        _ => return None
+    })
+}
+
 pub struct AST {
     filename: String,
     token: Vec<TokenData>,
@@ -53,6 +82,10 @@ impl AST {
         self.token[self.current].clone()
     }
 
+    fn peek_next(&self) -> Option<TokenData> {
+        self.token.get(self.current+1).cloned()
+    }
+
     fn previous(&self) -> TokenData {
         self.token[self.current-1].clone()
     }
@@ -79,26 +112,28 @@ impl AST {
         })
     }
 
-    fn consume(&mut self, tok_type: TokenType, message: &str) {
-        if self.check(tok_type) { self.advance(); }
-        else { 
-            let p = self.peek();
-            panic!("{} at {}:{}", message, p.line, p.end); 
-        }
+    /// Error at the position of `t`
+    fn error(&self, t: &TokenData, message: &str) -> String {
+        located(&self.filename, t.line as i64, t.col as i64, message)
+    }
+
+    fn consume(&mut self, tok_type: TokenType, message: &str) -> Result<(), String> {
+        if self.check(tok_type) { self.advance(); Ok(()) }
+        else { Err(self.error(&self.peek(), message)) }
     }
 
-    fn primary(&mut self) -> Box<Expr> {
-        if self.match_token(&mut vec![TokenType::Number, TokenType::String, TokenType::Char]) {
-            return Box::new(Expr::Literal(self.previous().value));
+    fn primary(&mut self) -> Result<Box<Expr>, String> {
+        if self.match_token(&mut vec![TokenType::Number, TokenType::String, TokenType::Char, TokenType::Boolean]) {
+            return Ok(Box::new(Expr::Literal(self.previous().value)));
         }
         if self.match_token(&mut vec![TokenType::LeftParen]) {
-            let expr = self.expr();
-            self.consume(TokenType::RightParen, "Expect ')'");
-            return Box::new(Expr::Grouping(expr));
+            let expr = self.expr()?;
+            self.consume(TokenType::RightParen, "Expect ')'")?;
+            return Ok(Box::new(Expr::Grouping(expr)));
         }
 
         if self.match_token(&mut vec![TokenType::Keywords, TokenType::DataType]) {
-            return Box::new(Expr::Identifier(self.previous().identifier));
+            return Ok(Box::new(Expr::Identifier(self.previous().identifier)));
         }
 
         if self.match_token(&mut vec![TokenType::LeftBracket]) {
@@ -106,101 +141,224 @@ impl AST {
         }
 
         if self.match_token(&mut vec![TokenType::Identifier]) {
-            return Box::new(Expr::Var(self.previous().identifier));
+            return Ok(Box::new(Expr::Var(self.previous().identifier)));
         }
 
-        panic!("Expect Expression at {}:{} ({:?})", self.peek().line, self.peek().end, self.peek().tok_type);
+        Err(self.error(&self.peek(), &format!("Expect Expression, got {:?}", self.peek().tok_type)))
     }
 
-    fn callee(&mut self) -> Box<Expr> {
-        let mut primary = self.primary();
+    fn callee(&mut self) -> Result<Box<Expr>, String> {
+        let pos = Pos::of(&self.peek());
+        let mut primary = self.primary()?;
         if self.match_token(&mut vec![TokenType::LeftParen]) {
             let mut arg_v = Vec::new();
             while !self.check(TokenType::RightParen) {
-                arg_v.push(*self.expr());
+                arg_v.push(*self.expr()?);
                 if !self.check(TokenType::RightParen) {
-                    self.consume(TokenType::Comma, "Expect ',' in parameter declare");
+                    self.consume(TokenType::Comma, "Expect ',' in parameter declare")?;
                 }
             }
-            self.consume(TokenType::RightParen, "Expect ')' after callee");
-            primary = Box::new(Expr::Callee(primary, arg_v));
+            self.consume(TokenType::RightParen, "Expect ')' after callee")?;
+            primary = Box::new(Expr::Callee(primary, arg_v, pos));
         }
-        primary
+        loop {
+            if self.match_token(&mut vec![TokenType::LeftBracket]) {
+                let index = self.expr()?;
+                self.consume(TokenType::RightBracket, "Expect ']' after index")?;
+                primary = Box::new(Expr::Index(primary, index));
+            } else if self.check(TokenType::Dot) {
+                return Err(self.error(&self.peek(), "Fields are not supported, there are no struct types"));
+            } else {
+                return Ok(primary);
+            }
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Box<Expr>, String> {
+        let expr = self.callee()?;
+        if self.match_token(&mut vec![TokenType::PlusPlus, TokenType::MinusMinus]) {
+            let op = self.previous();
+            return Ok(Box::new(Expr::Postfix(self.increment(expr, op)?)));
+        }
+        Ok(expr)
     }
 
-    fn unary(&mut self) -> Box<Expr> {
-        if self.match_token(&mut vec![TokenType::Not, TokenType::Minus]) {
+    fn unary(&mut self) -> Result<Box<Expr>, String> {
+        if self.match_token(&mut vec![TokenType::PlusPlus, TokenType::MinusMinus]) {
+            let op = self.previous();
+            let target = self.unary()?;
+            return self.increment(target, op);
+        }
+        if self.match_token(&mut vec![TokenType::Star]) {
+            // `*p` is `p[0]`
+            let ptr = self.unary()?;
+            return Ok(Box::new(Expr::Index(ptr, Box::new(Expr::Literal(Value::Number(0))))));
+        }
+        if self.match_token(&mut vec![TokenType::Not, TokenType::Minus, TokenType::Tilde]) {
             let op = self.previous();
-            let expr = self.unary();
-            return Box::new(Expr::Unary(op, expr));
+            let expr = self.unary()?;
+            return Ok(Box::new(Expr::Unary(op, expr)));
         }
-        self.callee()
+        self.postfix()
     }
     
-    // very rust
-    create_binary!(self, factor, self.unary(), vec![TokenType::Star, TokenType::Slash], self.unary());
-    create_binary!(self, term, self.factor(), vec![TokenType::Plus, TokenType::Minus], self.factor());
-    create_binary!(self, compare, self.term(), vec![TokenType::Less, TokenType::LessEqual, TokenType::Greater, TokenType::GreaterEqual], self.term());
-    create_binary!(self, shift, self.compare(), vec![TokenType::ShiftLeft, TokenType::ShiftRight], self.compare());
-    create_binary!(self, equal, self.shift(), vec![TokenType::EqualEqual, TokenType::NotEqual], self.shift());
-    create_binary!(self, logical, self.equal(), vec![TokenType::Or, TokenType::And], self.equal());
-    create_binary!(self, bool_logical, self.logical(), vec![TokenType::OrBool, TokenType::AndBool], self.logical());
+    fn expr(&mut self) -> Result<Box<Expr>, String> {
+        self.binary(ASSIGN_PREC)
+    }
 
-    fn expr(&mut self) -> Box<Expr> {
-        self.assignment()
+    /// Pratt loop: parse operators binding at least as tight as `min_prec`
+    fn binary(&mut self, min_prec: u8) -> Result<Box<Expr>, String> {
+        let mut lhs = self.unary()?;
+
+        while let Some((prec, assoc)) = infix_binding(&self.peek().tok_type) {
+            if prec < min_prec { break; }
+            let op = self.advance();
+            let next_prec = match assoc {
+                Assoc::Left => prec + 1,
+                Assoc::Right => prec
+            };
+
+            lhs = match op.tok_type {
+                TokenType::Question => {
+                    // the middle operand is delimited by ':', so any expression is allowed
+                    let then_e = self.expr()?;
+                    self.consume(TokenType::Colon, "Expect ':' in ternary expression")?;
+                    let else_e = self.binary(next_prec)?;
+                    Box::new(Expr::Ternary(lhs, then_e, else_e))
+                }
+                TokenType::Equal => {
+                    let v = self.binary(next_prec)?;
+                    self.assign_to(*lhs, v, &op)?
+                }
+                ref t => {
+                    let rhs = self.binary(next_prec)?;
+                    if let Some(bin) = compound_op(t) {
+                        // `x op= v` is desugared to `x = x op v`
+                        let bin_op = TokenData { tok_type: bin, ..op.clone() };
+                        self.assign_to(*lhs.clone(), Box::new(Expr::Binary(lhs, bin_op, rhs)), &op)?
+                    } else {
+                        Box::new(Expr::Binary(lhs, op, rhs))
+                    }
+                }
+            };
+        }
+        Ok(lhs)
     }
 
-    fn assignment(&mut self) -> Box<Expr> {
-        let expr = self.bool_logical();
+    /// Build `target = v`, the target must be a variable or an element
+    fn assign_to(&self, target: Expr, v: Box<Expr>, op: &TokenData) -> Result<Box<Expr>, String> {
+        match target {
+            Expr::Var(n) => Ok(Box::new(Expr::Assign(n, v, Pos::of(op)))),
+            Expr::Index(p, i) => Ok(Box::new(Expr::SetIndex(p, i, v, Pos::of(op)))),
+            Expr::Grouping(e) => self.assign_to(*e, v, op),
+            _ => Err(self.error(op, "Invalid assignment target"))
+        }
+    }
 
-        if self.match_token(&mut vec![TokenType::Equal]) {
-            let v = self.assignment();
+    /// `x++`, `++x`, `x--` and `--x` are desugared to `x = x + 1` / `x = x - 1`,
+    /// the postfix forms are wrapped in `Postfix` by the caller
+    fn increment(&self, target: Box<Expr>, op: TokenData) -> Result<Box<Expr>, String> {
+        let bin_op = TokenData {
+            tok_type: if op.tok_type == TokenType::PlusPlus { TokenType::Plus } else { TokenType::Minus },
+            ..op.clone()
+        };
+        let one = Box::new(Expr::Literal(Value::Number(1)));
+        self.assign_to(*target.clone(), Box::new(Expr::Binary(target, bin_op, one)), &op)
+    }
+
+    fn while_stmt(&mut self) -> Result<Box<Expr>, String> {
+        let pos = Pos::of(&self.previous());
+        let expr = self.expr()?;
+        let body = self.statement()?;
+        Ok(Box::new(Expr::WhileStmt(expr, body, pos)))
+    }
+
+    fn is_case_label(&self) -> bool {
+        self.peek().tok_type == TokenType::Keywords
+            && (self.peek().identifier == "case" || self.peek().identifier == "default")
+    }
+
+    fn case_body(&mut self) -> Result<Expr, String> {
+        let mut block = Vec::new();
+        while !self.is_case_label() && !self.check(TokenType::RightBrace) && !self.is_eof() {
+            block.push(*self.statement()?);
+        }
+        Ok(Expr::Block(block))
+    }
 
-            if matches!(*expr, Expr::Var(_)) {
-                let n = expr.ident_to_string();
-                return Box::new(Expr::Assign(n, v));
+    fn switch_stmt(&mut self) -> Result<Box<Expr>, String> {
+        /*
+         * switch x {
+         *  case 1: a = 2;
+         *  case Red: a = 3;
+         *  default: a = 4;
+         * }
+         *
+         * cases do not fall through
+         * */
+        let pos = Pos::of(&self.previous());
+        let value = self.expr()?;
+        self.consume(TokenType::LeftBrace, "Expect '{' after switch value")?;
+
+        let mut cases = Vec::new();
+        let mut default = Box::new(Expr::None);
+        while !self.check(TokenType::RightBrace) && !self.is_eof() {
+            let label = self.advance();
+            if label.identifier == "case" {
+                let case_v = self.expr()?;
+                self.consume(TokenType::Colon, "Expect ':' after case label")?;
+                cases.push((*case_v, self.case_body()?));
+            } else if label.identifier == "default" {
+                self.consume(TokenType::Colon, "Expect ':' after default")?;
+                if !matches!(*default, Expr::None) {
+                    return Err(self.error(&label, "Multiple default labels in one switch"));
+                }
+                default = Box::new(self.case_body()?);
+            } else {
+                return Err(self.error(&label, "Expect 'case' or 'default' in switch"));
             }
-            panic!("Invaild assignment object");
         }
-        return expr;
+        self.consume(TokenType::RightBrace, "Expect '}' after switch")?;
+
+        Ok(Box::new(Expr::SwitchStmt(value, cases, default, pos)))
     }
 
-    fn while_stmt(&mut self) -> Box<Expr> {
-        let expr = self.expr();
-        let body = self.statement();
-        return Box::new(Expr::WhileStmt(expr, body));
+    /// Type name at the current token
+    fn data_type(&mut self, message: &str) -> Result<DataType, String> {
+        let t = self.peek();
+        self.primary()?.to_datatype().map_err(|_| self.error(&t, message))
     }
 
-    fn func_header(&mut self) -> (Box<Expr>, Vec<(DataType,String,bool)>, (bool, Option<DataType>)){
-        let func_name = self.primary();
+    fn func_header(&mut self) -> Result<Func_Header, String> {
+        let func_name = self.primary()?;
 
-        self.consume(TokenType::LeftParen, "Expect '(' in declare func");
+        self.consume(TokenType::LeftParen, "Expect '(' in declare func")?;
         let mut arg_v = Vec::new();
 
         while !self.check(TokenType::RightParen) {
-            let dt = self.primary().to_datatype().expect("Expect data type - FuncStmt");
+            let dt = self.data_type("Expect data type")?;
             let is_ptr = self.match_token(&mut vec![TokenType::Star]);
-            let name = self.primary().ident_to_string();
+            let name = self.primary()?.ident_to_string();
             arg_v.push((dt, name, is_ptr));
             if !self.check(TokenType::RightParen) {
-                self.consume(TokenType::Comma, "Expect ',' in arguments declare");
+                self.consume(TokenType::Comma, "Expect ',' in arguments declare")?;
             }
         }
 
-        self.consume(TokenType::RightParen, "Expect ')' in declare func");
+        self.consume(TokenType::RightParen, "Expect ')' in declare func")?;
 
         let return_type = if self.match_token(&mut vec![TokenType::PointTo]) {
-            Some(self.primary().to_datatype().expect("Invaild data type"))
+            Some(self.data_type("Invaild data type")?)
         } else {
             None
         };
 
         let is_ptr = self.match_token(&mut vec![TokenType::Star]);
 
-        (func_name, arg_v, (is_ptr,return_type))
+        Ok(Func_Header { name: func_name.ident_to_string(), args: arg_v, return_type, is_ptr_dt: is_ptr })
     }
 
-    fn func_stmt(&mut self) -> Box<Expr> {
+    fn func_stmt(&mut self) -> Result<Box<Expr>, String> {
 
         /*
          * func test(suu test_args) -> suu {
@@ -211,130 +369,147 @@ impl AST {
          *  return (suu*)0x123;
          * }
          * */
-        let func_header = self.func_header();
+        let func_header = self.func_header()?;
         
-        self.consume(TokenType::LeftBrace, "Expect '{' in declare func");
-        let body = self.block();
-
-        Box::new(
-            Expr::FuncStmt(
-                Func_Header { 
-                    name: func_header.0.ident_to_string(), 
-                    args: func_header.1,
-                    return_type: func_header.2.1,
-                    is_ptr_dt: func_header.2.0
-                },
-                body
-            )
-        )
-    }
-
-    fn list(&mut self) -> Box<Expr> {
+        self.consume(TokenType::LeftBrace, "Expect '{' in declare func")?;
+        let body = self.block()?;
+
+        Ok(Box::new(
+            Expr::FuncStmt(func_header, body)
+        ))
+    }
+
+    fn list(&mut self) -> Result<Box<Expr>, String> {
         let mut data_type = DataType::Unknown;
         let mut l: Vec<Value> = Vec::new();
         while !self.check(TokenType::RightBracket) {
-            let v = self.primary();
+            let v = self.primary()?;
             l.push(v.to_value());
             if l.len() == 1 {
                 data_type=v.to_value().to_datatype();
             }else {
                 if v.to_value().to_datatype() != data_type {
-                    let p = self.peek();
-                    throw_message(
-                        &self.filename, 
-                        crate::MessageHandler::message_handler::MessageType::Error,
-                        p.line as i64, p.start as i64, &format!("List item must be same as {:?}", data_type));
-                    exit(1);
+                    return Err(self.error(&self.peek(), &format!("List item must be same as {:?}", data_type)));
                 }
             }
             if !self.check(TokenType::RightBracket) {
-                self.consume(TokenType::Comma, "Expect ',' in list item declaration");
+                self.consume(TokenType::Comma, "Expect ',' in list item declaration")?;
             }
         }
-        self.consume(TokenType::RightBracket, "Expect ']' in list declaration");
+        self.consume(TokenType::RightBracket, "Expect ']' in list declaration")?;
 
-        Box::new (
+        Ok(Box::new (
             Expr::List(l)
-        )
+        ))
     }
 
-    fn statement(&mut self) -> Box<Expr> {
+    fn statement(&mut self) -> Result<Box<Expr>, String> {
         
         if self.match_token(&mut vec![TokenType::LeftBrace]) {
             return self.block();
         }
         check_keyword!(self, "if",self.if_stmt());
         check_keyword!(self, "while", self.while_stmt());
+        check_keyword!(self, "switch", self.switch_stmt());
         check_keyword!(self, "func", self.func_stmt());
         check_keyword!(self, "extern", self.extern_func());
+        check_keyword!(self, "enum", self.enum_decl());
         check_keyword!(self, "return", self.return_keyw());
 
-        let expr = self.var_decl();
+        let expr = self.var_decl()?;
         if ! matches!(*expr, Expr::None) {
-            self.consume(TokenType::Semicolon, "Expect semicolon");
-            Box::new(
+            self.consume(TokenType::Semicolon, "Expect semicolon")?;
+            Ok(Box::new(
                 Expr::Statement(expr)
-            )
+            ))
         } else {
-            Box::new(Expr::None)
+            Ok(Box::new(Expr::None))
         }
     }
 
-    fn return_keyw(&mut self) -> Box<Expr> {
+    fn return_keyw(&mut self) -> Result<Box<Expr>, String> {
         // return 3;
+        let pos = Pos::of(&self.previous());
         let mut v = None;
         if !self.check(TokenType::Semicolon) {
-            v = Some(self.expr());
+            v = Some(self.expr()?);
         }
 
-        self.consume(TokenType::Semicolon, "Expect ';' after return keyw");
-        Box::new(
+        self.consume(TokenType::Semicolon, "Expect ';' after return keyw")?;
+        Ok(Box::new(
 
-            Expr::Return(v)
+            Expr::Return(v, pos)
 
-            )
+            ))
     }
 
-    fn extern_func(&mut self) -> Box<Expr> {
+    fn extern_func(&mut self) -> Result<Box<Expr>, String> {
         //extern <func_header>;
 
-        if self.advance().identifier != "func" {
-            throw_message(&self.filename,
-                crate::MessageHandler::message_handler::MessageType::Error,
-                self.peek().line as i64, self.peek().start as i64, 
-            "extern declare must be start with 'func' keywords");
-            exit(1);
+        let keyword = self.advance();
+        if keyword.identifier != "func" {
+            return Err(self.error(&keyword, "extern declare must be start with 'func' keywords"));
         }
 
-        let func_header = self.func_header();
+        let func_header = self.func_header()?;
 
-        self.consume(TokenType::Semicolon, "Expect ';' after extern function");
+        self.consume(TokenType::Semicolon, "Expect ';' after extern function")?;
         
-        Box::new (
-            Expr::Extern(Func_Header {
-                name: func_header.0.ident_to_string(),
-                args: func_header.1,
-                return_type: func_header.2.1,
-                is_ptr_dt: func_header.2.0
-            })
-        )
+        Ok(Box::new (
+            Expr::Extern(func_header)
+        ))
     }
 
+    fn enum_decl(&mut self) -> Result<Box<Expr>, String> {
+        /*
+         * enum Color {
+         *  Red,
+         *  Green = 5,
+         *  Blue
+         * }
+         * */
+        let name = self.primary()?;
+        if !matches!(*name, Expr::Var(_)) {
+            return Err(self.error(&self.previous(), "Expect enum name"));
+        }
+        self.consume(TokenType::LeftBrace, "Expect '{' in enum declare")?;
+
+        let mut variants = Vec::new();
+        while !self.check(TokenType::RightBrace) {
+            let variant = self.primary()?.ident_to_string();
+            let discriminant = if self.match_token(&mut vec![TokenType::Equal]) {
+                Some(self.expr()?)
+            } else {
+                None
+            };
+            variants.push((variant, discriminant));
+            if !self.check(TokenType::RightBrace) {
+                self.consume(TokenType::Comma, "Expect ',' in enum declare")?;
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after enum declare")?;
+        self.match_token(&mut vec![TokenType::Semicolon]);
 
-    fn if_stmt(&mut self) -> Box<Expr> {
-        let condition = self.expr();
-        let then_block = self.statement();
+        Ok(Box::new(
+            Expr::EnumDecl(name.ident_to_string(), variants)
+        ))
+    }
+
+    fn if_stmt(&mut self) -> Result<Box<Expr>, String> {
+        let pos = Pos::of(&self.previous());
+        let condition = self.expr()?;
+        let then_block = self.statement()?;
         let mut else_block = Box::new(Expr::None);
         if self.peek().identifier == "else" {
             self.advance();
-            else_block = self.statement();
+            else_block = self.statement()?;
         }
-        Box::new(
-            Expr::IfStmt(condition, then_block, else_block)
-            )
+        Ok(Box::new(
+            Expr::IfStmt(condition, then_block, else_block, pos)
+            ))
     }
 
-    fn block(&mut self) -> Box<Expr> {
+    fn block(&mut self) -> Result<Box<Expr>, String> {
         /*
          * {
          *  int a = 0;
@@ -344,73 +519,82 @@ impl AST {
 
         let mut block = Vec::new();
         while ! self.check(TokenType::RightBrace) && !self.is_eof() {
-            let st = *self.statement();
+            let st = *self.statement()?;
             block.push(st);
         }
-        self.consume(TokenType::RightBrace, "Expect '}' after block declare");
-        Box::new(
+        self.consume(TokenType::RightBrace, "Expect '}' after block declare")?;
+        Ok(Box::new(
             Expr::Block(block)
-        )
+        ))
     }
 
 
-    fn var_decl(&mut self) -> Box<Expr> {
+    fn var_decl(&mut self) -> Result<Box<Expr>, String> {
         // char* a = "hello world";
         // let a: const = 3;
         
         //check if current token is not data type
         
 
+        // user defined type: <type name> <var name>
+        let is_user_type = self.peek().tok_type == TokenType::Identifier
+            && self.peek_next().is_some_and(|f| f.tok_type == TokenType::Identifier);
+
         if self.peek().tok_type != TokenType::DataType 
         && self.peek().identifier != "let" 
-        && self.peek().identifier != "const" {
+        && self.peek().identifier != "const"
+        && !is_user_type {
             return self.expr();
         }
 
+        let pos = Pos::of(&self.peek());
         let is_const = self.peek().identifier=="const";
+        if is_const {
+            // const <type> <name> = ...;
+            let next = self.peek_next();
+            if next.as_ref().is_some_and(|f| f.tok_type == TokenType::DataType)
+            || (next.is_some_and(|f| f.tok_type == TokenType::Identifier)
+                && self.token.get(self.current+2).is_some_and(|f| f.tok_type == TokenType::Identifier)) {
+                self.advance();
+            }
+        }
+        let is_user_type = is_user_type || (is_const
+            && self.peek().tok_type == TokenType::Identifier
+            && self.peek_next().is_some_and(|f| f.tok_type == TokenType::Identifier));
         
-        let mut data_type = if self.peek().tok_type == TokenType::DataType {
-            self.primary().to_datatype().expect("VarDecl")
+        let mut data_type = if is_user_type {
+            DataType::Enum(self.advance().identifier)
+        } else if self.peek().tok_type == TokenType::DataType {
+            self.data_type("Expect data type")?
         } else {
             self.advance();
             DataType::Unknown
         };
         let mut is_pointer = self.match_token(&mut vec![TokenType::Star]);
 
-        let name = self.primary();
+        let name = self.primary()?;
         if !matches!(*name, Expr::Var(_)) {
-            let l =self.peek().line;
-            let p =self.peek().start;
-            throw_message(
-                "stdin", 
-                crate::MessageHandler::message_handler::MessageType::Error,
-                l as i64, p as i64,"Using keyword as variable name is forbidden!");
-            exit(1);
+            return Err(self.error(&self.previous(), "Using keyword as variable name is forbidden!"));
         }
 
         if self.peek().tok_type == TokenType::Colon {
             if !matches!(data_type, DataType::Unknown) {
-                let l =self.peek().line;
-                let p =self.peek().start;
-                throw_message(
-                    &self.filename, 
-                    crate::MessageHandler::message_handler::MessageType::Error,
-                    l as i64, p as i64,&format!("Can't override to data type: {:?}\nFix this by using 'let' instead.", data_type));
-                exit(1);
+                return Err(self.error(&self.peek(), &format!("Can't override to data type: {:?}\nFix this by using 'let' instead.", data_type)));
             }
             self.advance();
-            data_type = self.primary().to_datatype().expect("VarDecl(override)");
+            data_type = self.data_type("Expect data type")?;
             is_pointer = self.match_token(&mut vec![TokenType::Star]);
         }
 
         let mut init = None;
 
         if self.match_token(&mut vec![TokenType::Equal]) {
-            let i = self.expr();
+            let i = self.expr()?;
             if matches!(data_type, DataType::Unknown) && matches!(*i, Expr::Literal(_)) {
                 let v = i.to_value();
                 if !v.clone().is_null() {
-                    data_type = if v.clone().is_char() { DataType::Char }
+                    data_type = if v.clone().is_bool() { DataType::Bool }
+                            else if v.clone().is_char() { DataType::Char }
                             else if v.clone().is_double() {DataType::Suu}
                             else if v.clone().is_float() {DataType::Float}
                             else if v.clone().is_literal() {DataType::Long}
@@ -419,13 +603,22 @@ impl AST {
             }
             init = Some(i);
         }
-        Box::new(
-            Expr::VarDecl(data_type, is_pointer,is_const, name.ident_to_string(), init)
-            )
+        Ok(Box::new(
+            Expr::VarDecl(data_type, is_pointer,is_const, name.ident_to_string(), init, pos)
+            ))
     }
 
 
-    pub fn parse(&mut self) -> Vec<Expr> {
+    /// Parse a single expression that must use every token (e.g. a `#!if` condition)
+    pub fn parse_expr(&mut self) -> Result<Expr, String> {
+        let expr = *self.expr()?;
+        if !self.is_eof() {
+            return Err(format!("Unexpected token {:?} after expression", self.peek().tok_type));
+        }
+        Ok(expr)
+    }
+
+    pub fn parse(&mut self) -> Result<Vec<Expr>, String> {
         let mut expr_vec: Vec<Expr> = Vec::new();
         
         while !self.is_eof() {
@@ -437,14 +630,14 @@ impl AST {
                     let macro_name = macro_queue.pop_front().unwrap();
                     let mut sub_ast = AST::new(MetaData {filename: self.filename.clone(), tok_data: Vec::from(macro_queue), data: self.meta_data.data.clone()});
                     while !sub_ast.is_eof() {
-                        vect.push(*sub_ast.expr());
+                        vect.push(*sub_ast.expr()?);
                     }
                     expr_vec.push(Expr::Macro(macro_name.identifier, vect));
                 }
             }
             else {
                 
-                let expr = *self.statement();
+                let expr = *self.statement()?;
                 match expr {
                     Expr::None => {}
                     _ => expr_vec.push(expr)
@@ -452,6 +645,6 @@ impl AST {
             }
         }
 
-        expr_vec
+        Ok(expr_vec)
     }
 }
@@ -0,0 +1,344 @@
+/*
+ * C interop headers.
+ *
+ * generate: dcz -> .h, one prototype per function and one `extern` per global
+ * import:   .h -> extern func, only a restricted subset of C is understood:
+ *           function prototypes with scalar/pointer types and simple typedefs.
+ *           Variadic prototypes keep only their fixed parameters.
+ *           Anything else (structs by value, function pointers, inline
+ *           functions, macros) is skipped.
+ * */
+
+use std::collections::HashMap;
+
+use crate::{AST::expr_node::{DataType, Expr, Func_Header}, Value::Value};
+
+fn c_type(dt: &DataType, is_ptr: bool) -> String {
+    let t = match dt {
+        DataType::Char => "char",
+        DataType::Short => "int16_t",
+        DataType::Int => "int32_t",
+        DataType::Long => "int64_t",
+        DataType::Float => "float",
+        DataType::Suu => "double",
+        DataType::Bool => "bool",
+        DataType::Void | DataType::Unknown => "void",
+        // enums from other modules are not emitted, they're still ints
+        DataType::Enum(n) if n.contains("::") => "int32_t",
+        DataType::Enum(n) => n.as_str()
+    };
+    format!("{}{}", t, if is_ptr { "*" } else { "" })
+}
+
+/// Generate a C header for the functions, globals and enums of a checked AST.
+/// Namespaced symbols (`math::add`) are not valid C names and are left out.
+pub fn generate(filename: &str, ast: &[Expr]) -> String {
+    let guard = std::path::Path::new(filename)
+        .file_name()
+        .map_or(filename.to_string(), |f| f.to_string_lossy().to_string())
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect::<String>() + "_H";
+
+    let mut enums = Vec::new();
+    let mut globals = Vec::new();
+    let mut funcs = Vec::new();
+
+    for e in ast {
+        match e {
+            Expr::EnumDecl(name, variants) if !name.contains("::") => {
+                let variants = variants.iter().map(|(v, d)| {
+                    match d.as_deref() {
+                        Some(Expr::Literal(Value::Number(d))) => format!("    {} = {},", v, d),
+                        _ => format!("    {},", v)
+                    }
+                }).collect::<Vec<String>>();
+                enums.push(format!("typedef enum {{\n{}\n}} {};", variants.join("\n"), name));
+            }
+            Expr::VarDecl(dt, is_ptr, is_const, name, _, _) if !name.contains("::") => {
+                globals.push(format!("extern {}{} {};", c_type(dt, *is_ptr), if *is_const { " const" } else { "" }, name));
+            }
+            Expr::FuncStmt(h, _) if !h.name.contains("::") && h.name != "main" => {
+                let args = if h.args.is_empty() {
+                    "void".to_string()
+                } else {
+                    h.args.iter().map(|(dt, n, p)| format!("{} {}", c_type(dt, *p), n)).collect::<Vec<String>>().join(", ")
+                };
+                let ret = c_type(h.return_type.as_ref().unwrap_or(&DataType::Void), h.is_ptr_dt);
+                funcs.push(format!("{} {}({});", ret, h.name, args));
+            }
+            _ => {}
+        }
+    }
+
+    let sections = [enums, globals, funcs].into_iter()
+        .filter(|s| !s.is_empty())
+        .map(|s| s.join("\n"))
+        .collect::<Vec<String>>();
+
+    format!("/* Generated by dcz from {}, do not edit */\n\
+        #ifndef {guard}\n#define {guard}\n\n\
+        #include <stdbool.h>\n#include <stdint.h>\n\n\
+        #ifdef __cplusplus\nextern \"C\" {{\n#endif\n\n\
+        {}\n\n\
+        #ifdef __cplusplus\n}}\n#endif\n\n\
+        #endif\n", filename, sections.join("\n\n"))
+}
+
+/// Split C source into identifiers / punctuation, without comments and preprocessor lines
+fn c_tokens(src: &str) -> Vec<String> {
+    let mut toks = Vec::new();
+    let mut chars = src.chars().peekable();
+    let mut line_start = true;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => { line_start = true; continue; }
+            c if c.is_whitespace() => continue,
+            '#' if line_start => {
+                // skip the directive, including `\` continuations
+                let mut prev = '#';
+                while let Some(&n) = chars.peek() {
+                    if n == '\n' && prev != '\\' { break; }
+                    prev = n;
+                    chars.next();
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.peek().is_some_and(|n| *n != '\n') { chars.next(); }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = ' ';
+                for n in chars.by_ref() {
+                    if prev == '*' && n == '/' { break; }
+                    prev = n;
+                }
+            }
+            '"' => {
+                let mut s = String::from('"');
+                while let Some(n) = chars.next() {
+                    s.push(n);
+                    if n == '\\' {
+                        s.extend(chars.next());
+                    } else if n == '"' {
+                        break;
+                    }
+                }
+                toks.push(s);
+            }
+            '.' if chars.peek() == Some(&'.') => {
+                chars.next();
+                chars.next();
+                toks.push("...".to_string());
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut s = String::from(c);
+                while let Some(&n) = chars.peek() {
+                    if !(n.is_alphanumeric() || n == '_') { break; }
+                    s.push(n);
+                    chars.next();
+                }
+                toks.push(s);
+            }
+            c => toks.push(c.to_string())
+        }
+        line_start = false;
+    }
+    toks
+}
+
+const QUALIFIERS: [&str; 10] = ["const", "volatile", "restrict", "__restrict", "extern", "static", "inline", "__inline", "__extension__", "register"];
+
+fn is_type_word(w: &str, typedefs: &HashMap<String, (DataType, bool)>) -> bool {
+    ["char", "short", "int", "long", "float", "double", "void", "unsigned", "signed", "_Bool", "bool"].contains(&w)
+        || QUALIFIERS.contains(&w)
+        || typedefs.contains_key(w)
+}
+
+/// C type words -> (dcz type, is pointer), None if the type is outside the supported subset
+fn dcz_type(words: &[String], typedefs: &HashMap<String, (DataType, bool)>) -> Option<(DataType, bool)> {
+    let depth = words.iter().filter(|w| *w == "*").count();
+    let words = words.iter()
+        .filter(|w| *w != "*" && !QUALIFIERS.contains(&w.as_str()))
+        .map(String::as_str)
+        .collect::<Vec<&str>>();
+
+    let has = |w: &str| words.contains(&w);
+    let dt = if has("double") {
+        // long double has no dcz equivalent
+        if has("long") { return None; }
+        DataType::Suu
+    } else if has("char") || has("int8_t") || has("uint8_t") {
+        DataType::Char
+    } else if has("short") || has("int16_t") || has("uint16_t") {
+        DataType::Short
+    } else if has("long") || ["int64_t", "uint64_t", "size_t", "ssize_t", "intptr_t", "uintptr_t", "ptrdiff_t", "off_t"].iter().any(|w| has(w)) {
+        DataType::Long
+    } else if has("int") || has("int32_t") || has("uint32_t") || has("unsigned") || has("signed") || has("enum") {
+        DataType::Int
+    } else if has("float") {
+        DataType::Float
+    } else if has("_Bool") || has("bool") {
+        DataType::Bool
+    } else if has("void") {
+        DataType::Void
+    } else if let [name] = words.as_slice() {
+        match typedefs.get(*name) {
+            Some((dt, p)) if depth == 0 => return Some((dt.clone(), *p)),
+            Some((dt, false)) => dt.clone(),
+            // opaque type like FILE, usable only through a pointer
+            _ if depth > 0 => DataType::Void,
+            _ => return None
+        }
+    } else if depth > 0 && (has("struct") || has("union")) {
+        DataType::Void
+    } else {
+        return None;
+    };
+
+    match depth {
+        0 => Some((dt, false)),
+        1 => Some((dt, true)),
+        // only one level of pointer in dcz, `T**` is passed as `void*`
+        _ => Some((DataType::Void, true))
+    }
+}
+
+/// Parse `<type> <name>(<params>)`, params are (type words, name)
+fn prototype(decl: &[String], typedefs: &HashMap<String, (DataType, bool)>) -> Option<Func_Header> {
+    let open = decl.iter().position(|t| t == "(")?;
+    let name = decl[..open].last()?;
+    if !name.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        return None;
+    }
+    let (ret, is_ptr_dt) = dcz_type(&decl[..open-1], typedefs)?;
+
+    let mut depth = 0;
+    let close = decl[open..].iter().position(|t| {
+        match t.as_str() {
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            _ => {}
+        }
+        depth == 0
+    })? + open;
+    let params = &decl[open+1..close];
+
+    // a variadic function keeps its fixed params, `printf(fmt)` is called with the format only
+    let params = match params {
+        [rest @ .., comma, dots] if comma == "," && dots == "..." => rest,
+        _ => params
+    };
+
+    let mut args = Vec::new();
+    if !(params.is_empty() || params == ["void"]) {
+        for (i, p) in params.split(|t| t == ",").enumerate() {
+            // function pointers are outside the subset
+            if p.iter().any(|t| t == "..." || t == "(" || t == "[") {
+                return None;
+            }
+            let (words, arg_name) = match p.split_last() {
+                Some((last, rest)) if dcz_type(rest, typedefs).is_some() && !is_type_word(last, typedefs)
+                    && last.starts_with(|c: char| c.is_alphabetic() || c == '_') => {
+                    (rest, last.trim_start_matches('_').to_string())
+                }
+                _ => (p, String::new())
+            };
+            let (dt, is_ptr) = dcz_type(words, typedefs)?;
+            let arg_name = if arg_name.is_empty() { format!("arg{}", i) } else { arg_name };
+            args.push((dt, arg_name, is_ptr));
+        }
+    }
+
+    Some(Func_Header {
+        name: name.clone(),
+        args,
+        return_type: if matches!(ret, DataType::Void) && !is_ptr_dt { None } else { Some(ret) },
+        is_ptr_dt
+    })
+}
+
+/// Import the prototypes of a C header as `Expr::Extern`
+pub fn import(src: &str) -> Vec<Expr> {
+    let toks = c_tokens(src);
+    let mut typedefs: HashMap<String, (DataType, bool)> = HashMap::new();
+    let mut externs = Vec::new();
+
+    // `extern "C" {` blocks are transparent, other braces are skipped as a whole
+    let mut blocks: Vec<bool> = Vec::new();
+    let mut decl: Vec<String> = Vec::new();
+    let mut skip_depth = 0;
+
+    let mut i = 0;
+    while i < toks.len() {
+        let t = &toks[i];
+        i += 1;
+        match t.as_str() {
+            "extern" if toks.get(i).is_some_and(|n| n.starts_with('"')) && toks.get(i+1).is_some_and(|n| n == "{") => {
+                blocks.push(true);
+                i += 2;
+            }
+            "{" => {
+                blocks.push(false);
+                skip_depth += 1;
+            }
+            "}" => {
+                if !blocks.pop().unwrap_or(true) {
+                    skip_depth -= 1;
+                }
+                // `static inline int f(void) { ... }` has no ';' after the body
+                if skip_depth == 0 && decl.iter().any(|t| t == "(") {
+                    decl.clear();
+                }
+            }
+            ";" if skip_depth == 0 => {
+                let d = std::mem::take(&mut decl);
+                if d.first().is_some_and(|t| t == "typedef") {
+                    // typedef <type> <name>;
+                    let Some((name, words)) = d[1..].split_last() else { continue };
+                    if words.iter().any(|t| t == "(" || t == "[") {
+                        continue;
+                    }
+                    let dt = dcz_type(words, &typedefs)
+                        .or_else(|| words.first().is_some_and(|w| w == "enum").then_some((DataType::Int, false)));
+                    if let Some(dt) = dt {
+                        typedefs.insert(name.clone(), dt);
+                    }
+                } else if let Some(h) = prototype(&d, &typedefs)
+                    && !externs.iter().any(|e| matches!(e, Expr::Extern(f) if f.name == h.name)) {
+                    externs.push(Expr::Extern(h));
+                }
+            }
+            _ if skip_depth == 0 => decl.push(t.clone()),
+            _ => {}
+        }
+    }
+    externs
+}
+
+/// dcz source of the imported externs, so the module loader can tokenize them
+pub fn to_dcz(externs: &[Expr]) -> String {
+    let dcz_type = |dt: &DataType, is_ptr: bool| {
+        let t = match dt {
+            DataType::Char => "char",
+            DataType::Short => "short",
+            DataType::Int | DataType::Enum(_) => "int",
+            DataType::Long => "long",
+            DataType::Float => "float",
+            DataType::Suu => "suu",
+            DataType::Bool => "bool",
+            DataType::Void | DataType::Unknown => "void"
+        };
+        format!("{}{}", t, if is_ptr { "*" } else { "" })
+    };
+
+    externs.iter().filter_map(|e| match e {
+        Expr::Extern(h) => {
+            let args = h.args.iter().map(|(dt, n, p)| format!("{} {}", dcz_type(dt, *p), n)).collect::<Vec<String>>().join(", ");
+            let ret = h.return_type.as_ref().map(|r| format!(" -> {}", dcz_type(r, h.is_ptr_dt))).unwrap_or_default();
+            Some(format!("extern func {}({}){};\n", h.name, args, ret))
+        }
+        _ => None
+    }).collect()
+}
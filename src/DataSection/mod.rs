@@ -22,6 +22,13 @@ impl DataSection {
         self.dotdata.entry(name).or_insert_with(|| Value::Str(s));
     }
 
+    /// Merge the data section of another source file (names are unique across files)
+    pub fn merge(&mut self, other: &DataSection) {
+        other.dotdata.iter().for_each(|(n, v)| {
+            self.dotdata.entry(n.clone()).or_insert_with(|| v.clone());
+        });
+    }
+
     pub fn return_data(&self) -> &BTreeMap<String, Value> {
         &self.dotdata
     }
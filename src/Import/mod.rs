@@ -0,0 +1,263 @@
+/*
+ * Module system: resolve `import "path.dcz";` / `use name;` and merge every
+ * file into a single token stream before parsing.
+ *
+ *   import "lib/math.dcz";   // relative to the importing file, then -I paths
+ *   use math;                // same as import "math.dcz";
+ *   import "stdio.h";        // C header, see CHeader::import
+ *
+ * Top-level functions, globals and enums of an imported file are namespaced
+ * with the module path of `use` (`use lib::math;` -> `lib::math::add(1, 2)`)
+ * or the file stem of `import` (`math::add(1, 2)`). Two different files with
+ * the same namespace are an error.
+ * */
+
+use std::{collections::{HashMap, HashSet}, error::Error, fs::{self, File}, path::{Path, PathBuf}};
+
+use crate::{CHeader, Preprocessor::Preprocessor, token::{token_type::TokenType, MetaData, Token, TokenData}, DataSection::DataSection, Value::Value};
+
+pub struct ModuleLoader {
+    include_paths: Vec<PathBuf>,
+    // `-D` symbols, every module starts with only these defined
+    defines: HashMap<String, Vec<TokenData>>,
+    // current import chain, used for cycle detection
+    loading: Vec<PathBuf>,
+    // every file already merged, a module is only merged once
+    loaded: Vec<PathBuf>,
+    // file each namespace belongs to
+    namespaces: HashMap<String, PathBuf>,
+    tok_data: Vec<TokenData>,
+    data: DataSection
+}
+
+impl ModuleLoader {
+    pub fn new(include_paths: Vec<PathBuf>, defines: HashMap<String, Vec<TokenData>>) -> Self {
+        Self { include_paths, defines, loading: Vec::new(), loaded: Vec::new(), namespaces: HashMap::new(), tok_data: Vec::new(), data: DataSection::new() }
+    }
+
+    /// Load `p` and everything it imports, dependencies come first in the result
+    pub fn load(mut self, p: &Path) -> Result<MetaData, Box<dyn Error>> {
+        self.load_file(p, None)?;
+        Ok(self.finish(p.display().to_string()))
+    }
+
+    /// Load source text as if it were a file in `dir`, its imports are resolved from there
+    pub fn load_source(mut self, source: &str, dir: &Path) -> Result<MetaData, Box<dyn Error>> {
+        let meta = Token::new(source.to_string()).tokenize()?;
+        let filename = meta.filename.clone();
+        self.merge(meta, &dir.join(&filename), dir, None)?;
+        Ok(self.finish(filename))
+    }
+
+    fn finish(mut self, filename: String) -> MetaData {
+        let (line, end) = self.tok_data.last().map_or((1, 0), |t| (t.line, t.end));
+        self.tok_data.push(TokenData { tok_type: TokenType::EOF, start: end, end, line, col: 0, identifier: String::new(), value: Value::Null, sub_tok: None });
+
+        MetaData { filename, tok_data: self.tok_data, data: self.data }
+    }
+
+    fn load_file(&mut self, p: &Path, namespace: Option<String>) -> Result<(), Box<dyn Error>> {
+        let canonical = fs::canonicalize(p).map_err(|e| format!("Cannot open module '{}': {}", p.display(), e))?;
+
+        if let Some(idx) = self.loading.iter().position(|f| *f == canonical) {
+            let chain = self.loading[idx..].iter()
+                .chain(std::iter::once(&canonical))
+                .map(|f| f.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(format!("Import cycle detected: {}", chain).into());
+        }
+        if self.loaded.contains(&canonical) {
+            return Ok(());
+        }
+        self.loading.push(canonical.clone());
+
+        let meta = if p.extension().is_some_and(|e| e == "h") {
+            // C header, only its prototypes are imported as `extern func`
+            let mut t = Token::new(CHeader::to_dcz(&CHeader::import(&fs::read_to_string(p)?)));
+            t.source_file_name = p.display().to_string();
+            t.tokenize()?
+        } else {
+            Token::FromIO(p, File::open(p)?)?.tokenize()?
+        };
+        let dir = canonical.parent().map(Path::to_path_buf).unwrap_or_default();
+        self.merge(meta, p, &dir, namespace)?;
+
+        self.loading.pop();
+        self.loaded.push(canonical);
+        Ok(())
+    }
+
+    /// Preprocess the tokens of one module, load its imports, then append it
+    fn merge(&mut self, meta: MetaData, p: &Path, dir: &Path, namespace: Option<String>) -> Result<(), Box<dyn Error>> {
+        self.data.merge(&meta.data);
+
+        let mut tokens = meta.tok_data;
+        tokens.retain(|t| t.tok_type != TokenType::EOF);
+        let tokens = Preprocessor::new(self.defines.clone(), self.include_paths.clone())
+            .process(tokens, p, &mut self.data)?;
+        let tokens = join_paths(tokens);
+
+        let (imports, mut tokens) = split_imports(tokens, &meta.filename)?;
+        for Import { name, namespace, line } in imports {
+            let path = resolve(dir, &name, &self.include_paths)
+                .ok_or_else(|| format!("{}:{}: Module '{}' not found", meta.filename, line, name))?;
+            let ns = namespace.or_else(|| path.file_stem().map(|s| s.to_string_lossy().to_string())).unwrap_or(name);
+
+            let canonical = fs::canonicalize(&path)?;
+            match self.namespaces.get(&ns) {
+                Some(other) if *other != canonical => {
+                    return Err(format!("{}:{}: Modules '{}' and '{}' are both namespaced '{}', import one of them with 'use dir::name;'",
+                        meta.filename, line, other.display(), canonical.display(), ns).into());
+                }
+                _ => { self.namespaces.insert(ns.clone(), canonical); }
+            }
+            self.load_file(&path, Some(ns))?;
+        }
+
+        if let Some(ns) = namespace {
+            let names = top_level_names(&tokens);
+            rename(&mut tokens, &names, &ns);
+        }
+
+        self.tok_data.extend(tokens);
+        Ok(())
+    }
+}
+
+/// Find `name` relative to `dir`, then in each include path
+pub fn resolve(dir: &Path, name: &str, include_paths: &[PathBuf]) -> Option<PathBuf> {
+    std::iter::once(dir)
+        .chain(include_paths.iter().map(PathBuf::as_path))
+        .map(|d| d.join(name))
+        .find(|p| p.is_file())
+}
+
+/// `a :: b` -> one identifier token `a::b`
+fn join_paths(tokens: Vec<TokenData>) -> Vec<TokenData> {
+    let mut out: Vec<TokenData> = Vec::with_capacity(tokens.len());
+    let mut iter = tokens.into_iter().peekable();
+    while let Some(mut t) = iter.next() {
+        if let Some(sub) = t.sub_tok.take() {
+            t.sub_tok = Some(join_paths(sub));
+        }
+        if t.tok_type == TokenType::ColonColon
+            && out.last().is_some_and(|l| l.tok_type == TokenType::Identifier)
+            && iter.peek().is_some_and(|n| n.tok_type == TokenType::Identifier) {
+            let rhs = iter.next().unwrap();
+            let lhs = out.last_mut().unwrap();
+            lhs.identifier = format!("{}::{}", lhs.identifier, rhs.identifier);
+            lhs.value = Value::new_obj(lhs.identifier.clone());
+            lhs.end = rhs.end;
+            continue;
+        }
+        out.push(t);
+    }
+    out
+}
+
+fn is_kw(t: &TokenData, kw: &str) -> bool {
+    t.tok_type == TokenType::Keywords && t.identifier == kw
+}
+
+/// One `import "x";` / `use x;`
+struct Import {
+    // module file, relative to the importing file or an include path
+    name: String,
+    // `use` names it, `import` takes the file stem
+    namespace: Option<String>,
+    line: usize
+}
+
+/// Remove the imports from the stream, returns them and the remaining tokens
+fn split_imports(tokens: Vec<TokenData>, filename: &str) -> Result<(Vec<Import>, Vec<TokenData>), String> {
+    let mut imports = Vec::new();
+    let mut out = Vec::with_capacity(tokens.len());
+    let mut depth = 0;
+    let mut iter = tokens.into_iter();
+
+    while let Some(t) = iter.next() {
+        match t.tok_type {
+            TokenType::LeftBrace => depth += 1,
+            TokenType::RightBrace => depth -= 1,
+            _ => {}
+        }
+        if !is_kw(&t, "import") && !is_kw(&t, "use") {
+            out.push(t);
+            continue;
+        }
+        if depth != 0 {
+            return Err(format!("{}:{}: '{}' is only allowed at top level", filename, t.line, t.identifier));
+        }
+
+        let (name, namespace) = match (t.identifier.as_str(), iter.next()) {
+            ("import", Some(n)) if n.tok_type == TokenType::String => (n.identifier[1..n.identifier.len()-1].to_string(), None),
+            ("use", Some(n)) if n.tok_type == TokenType::Identifier => (format!("{}.dcz", n.identifier.replace("::", "/")), Some(n.identifier)),
+            (kw, _) => {
+                let expect = if kw == "import" { "a file path string" } else { "a module name" };
+                return Err(format!("{}:{}: Expect {} after '{}'", filename, t.line, expect, kw));
+            }
+        };
+        if !iter.next().is_some_and(|s| s.tok_type == TokenType::Semicolon) {
+            return Err(format!("{}:{}: Expect ';' after {}", filename, t.line, t.identifier));
+        }
+        imports.push(Import { name, namespace, line: t.line });
+    }
+    Ok((imports, out))
+}
+
+/// Names declared at the top level of a module: functions, globals, enums and their variants
+fn top_level_names(tokens: &[TokenData]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    let (mut braces, mut parens) = (0, 0);
+
+    for (i, t) in tokens.iter().enumerate() {
+        let prev = if i > 0 { tokens.get(i-1) } else { None };
+        match t.tok_type {
+            TokenType::LeftBrace => braces += 1,
+            TokenType::RightBrace => braces -= 1,
+            TokenType::LeftParen => parens += 1,
+            TokenType::RightParen => parens -= 1,
+            TokenType::Identifier if parens == 0 => {
+                let Some(prev) = prev else { continue };
+                let extern_fn = i >= 2 && is_kw(&tokens[i-2], "extern");
+                let declared = match braces {
+                    // extern functions keep their C symbol name
+                    0 => (is_kw(prev, "func") && !extern_fn)
+                        || is_kw(prev, "enum")
+                        || is_kw(prev, "let")
+                        || is_kw(prev, "const")
+                        || prev.tok_type == TokenType::DataType
+                        || (prev.tok_type == TokenType::Star && i >= 2 && tokens[i-2].tok_type == TokenType::DataType)
+                        || (prev.tok_type == TokenType::Identifier && !tokens.get(i+1).is_some_and(|n| n.tok_type == TokenType::LeftBrace)),
+                    // enum variants
+                    1 => matches!(prev.tok_type, TokenType::LeftBrace | TokenType::Comma) && in_enum_body(tokens, i),
+                    _ => false
+                };
+                if declared {
+                    names.insert(t.identifier.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+/// Whether the token at `i` (brace depth 1) is inside `enum Name { ... }`
+fn in_enum_body(tokens: &[TokenData], i: usize) -> bool {
+    tokens[..i].iter().rposition(|t| t.tok_type == TokenType::LeftBrace)
+        .is_some_and(|open| open >= 2 && is_kw(&tokens[open-2], "enum"))
+}
+
+fn rename(tokens: &mut [TokenData], names: &HashSet<String>, ns: &str) {
+    for t in tokens.iter_mut() {
+        if let Some(sub) = t.sub_tok.as_mut() {
+            rename(sub, names, ns);
+        }
+        if t.tok_type == TokenType::Identifier && names.contains(&t.identifier) {
+            t.identifier = format!("{}::{}", ns, t.identifier);
+            t.value = Value::new_obj(t.identifier.clone());
+        }
+    }
+}
@@ -30,10 +30,7 @@ pub fn throw_message(source_name: &str,message_type: MessageType, line: i64, pos
     eprintln!("{}: {}\nat {}", message_type, message, format!("{}:{}:{}", source_name,line,pos).bold())
 }
 
-#[macro_export]
-macro_rules! panic_error {
-    ($source: expr, $line: expr, $pos: expr, $message: expr) => {
-        throw_message($source, MessageType::Error, $line, $pos, $message);
-        exit(1)
-    };
+/// Message with its position like `throw_message`, for errors that are returned instead of printed
+pub fn located(source_name: &str, line: i64, pos: i64, message: &str) -> String {
+    format!("{}\nat {}:{}:{}", message, source_name, line, pos)
 }
\ No newline at end of file
@@ -0,0 +1,446 @@
+/*
+ * Token level preprocessor for `#!` directives, runs before parsing.
+ *
+ *   #!define DEBUG           // flag
+ *   #!define SIZE 4 * 1024   // object-like macro, SIZE is replaced by its tokens
+ *   #!macro max(a, b) = (a > b ? a : b)   // function-like macro
+ *   #!undef SIZE
+ *   #!if DEBUG && LEVEL > 2  // constant expression, undefined names are 0
+ *   #!elif RELEASE
+ *   #!else
+ *   #!endif
+ *   #!include "common.dcz"   // textual include, same search path as imports
+ *
+ * Symbols can also be defined from the command line with `-D NAME[=value]`.
+ * */
+
+use std::{collections::HashMap, fs::File, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}};
+
+use crate::{token::{token_type::TokenType, MetaData, Token, TokenData}, DataSection::DataSection, Import, Value::Value, AST::{const_eval, expr_node::Expr, AST}};
+
+// nested macro calls deeper than this are reported as an error
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<TokenData>,
+    // where it was defined, for diagnostics
+    file: String,
+    line: usize
+}
+
+impl Macro {
+    /// Variables declared in the body, renamed at every expansion
+    fn locals(&self) -> Vec<String> {
+        (0..self.body.len()).filter(|i| {
+            self.body[*i].tok_type == TokenType::Identifier && !self.params.contains(&self.body[*i].identifier) && declares(&self.body, *i)
+        }).map(|i| self.body[i].identifier.clone()).collect()
+    }
+
+    /// Whether the body is an expression, which is expanded as one operand
+    fn is_expr(&self) -> bool {
+        !self.body.iter().any(|t| matches!(t.tok_type, TokenType::Semicolon | TokenType::LeftBrace | TokenType::RightBrace))
+    }
+}
+
+/// Whether `tokens[i]` is the name in a declaration, `int x`, `int* x`, `let x`
+fn declares(tokens: &[TokenData], i: usize) -> bool {
+    match i.checked_sub(1).map(|p| &tokens[p]) {
+        Some(p) if p.tok_type == TokenType::DataType => true,
+        Some(p) if p.tok_type == TokenType::Keywords => p.identifier == "let" || p.identifier == "const",
+        Some(p) if p.tok_type == TokenType::Star => i > 1 && tokens[i-2].tok_type == TokenType::DataType,
+        _ => false
+    }
+}
+
+/// Whether `tokens` is a single parenthesized group, `(a + b)`
+fn is_group(tokens: &[TokenData]) -> bool {
+    let mut depth = 0;
+    for (i, t) in tokens.iter().enumerate() {
+        match t.tok_type {
+            TokenType::LeftParen => depth += 1,
+            TokenType::RightParen => depth -= 1,
+            _ if i == 0 => return false,
+            _ => {}
+        }
+        if depth == 0 && i != tokens.len() - 1 {
+            return false;
+        }
+    }
+    !tokens.is_empty()
+}
+
+struct Cond {
+    // tokens of the current branch are kept
+    active: bool,
+    // one of the branches was already taken
+    taken: bool,
+    // every enclosing #!if is active
+    parent: bool,
+    seen_else: bool,
+    line: usize
+}
+
+pub struct Preprocessor {
+    defines: HashMap<String, Vec<TokenData>>,
+    macros: HashMap<String, Macro>,
+    include_paths: Vec<PathBuf>,
+    // current #!include chain, used for cycle detection
+    including: Vec<PathBuf>
+}
+
+impl Preprocessor {
+    pub fn new(defines: HashMap<String, Vec<TokenData>>, include_paths: Vec<PathBuf>) -> Self {
+        Self { defines, macros: HashMap::new(), include_paths, including: Vec::new() }
+    }
+
+    /// `NAME` or `NAME=value` from the command line
+    pub fn parse_define(def: &str) -> Result<(String, Vec<TokenData>), String> {
+        match def.split_once('=') {
+            Some((name, value)) => {
+                let mut tokens = Token::new(value.to_string()).tokenize()?.tok_data;
+                tokens.retain(|t| t.tok_type != TokenType::EOF);
+                Ok((name.to_string(), tokens))
+            }
+            None => Ok((def.to_string(), Vec::new()))
+        }
+    }
+
+    /// Expand every directive and macro of a tokenized file, strings of included files go to `data`
+    pub fn process(&mut self, tokens: Vec<TokenData>, file: &Path, data: &mut DataSection) -> Result<Vec<TokenData>, String> {
+        let filename = file.display().to_string();
+        let mut out = Vec::with_capacity(tokens.len());
+        let mut conds: Vec<Cond> = Vec::new();
+        // tokens between two directives, expanded with the macros defined at that point
+        let mut pending = Vec::new();
+
+        for t in tokens {
+            let active = conds.last().is_none_or(|c| c.active);
+            if t.tok_type != TokenType::Macro {
+                if active {
+                    pending.push(t);
+                }
+                continue;
+            }
+            self.expand(std::mem::take(&mut pending), &filename, &mut Vec::new(), &mut out)?;
+
+            let sub = t.sub_tok.unwrap_or_default();
+            let Some(directive) = sub.first() else {
+                return Err(format!("{}:{}: Expect directive after '#!'", filename, t.line));
+            };
+            let err = |msg: String| format!("{}:{}: {}", filename, t.line, msg);
+
+            match directive.identifier.as_str() {
+                "if" => {
+                    let v = active && self.eval_cond(&sub[1..], &filename).map_err(err)?;
+                    conds.push(Cond { active: v, taken: v, parent: active, seen_else: false, line: t.line });
+                }
+                "elif" => {
+                    let Some(c) = conds.last() else { return Err(err("'#!elif' without '#!if'".to_string())) };
+                    if c.seen_else {
+                        return Err(err("'#!elif' after '#!else'".to_string()));
+                    }
+                    let v = c.parent && !c.taken && self.eval_cond(&sub[1..], &filename).map_err(err)?;
+                    let c = conds.last_mut().unwrap();
+                    c.active = v;
+                    c.taken |= v;
+                }
+                "else" => {
+                    let Some(c) = conds.last_mut() else { return Err(err("'#!else' without '#!if'".to_string())) };
+                    if c.seen_else {
+                        return Err(err("'#!else' after '#!else'".to_string()));
+                    }
+                    c.active = c.parent && !c.taken;
+                    c.seen_else = true;
+                }
+                "endif" => {
+                    if conds.pop().is_none() {
+                        return Err(err("'#!endif' without '#!if'".to_string()));
+                    }
+                }
+                _ if !active => {}
+                "define" => {
+                    let Some(name) = sub.get(1).filter(|n| n.tok_type == TokenType::Identifier) else {
+                        return Err(err("Expect macro name after '#!define'".to_string()));
+                    };
+                    self.macros.remove(&name.identifier);
+                    self.defines.insert(name.identifier.clone(), sub[2..].to_vec());
+                }
+                "macro" => {
+                    let (name, m) = self.macro_def(&sub[1..], &filename, t.line).map_err(err)?;
+                    self.defines.remove(&name);
+                    self.macros.insert(name, m);
+                }
+                "undef" => {
+                    if let Some(name) = sub.get(1) {
+                        self.defines.remove(&name.identifier);
+                        self.macros.remove(&name.identifier);
+                    }
+                }
+                "include" => {
+                    let Some(path) = sub.get(1).filter(|p| p.tok_type == TokenType::String) else {
+                        return Err(err("Expect a file path string after '#!include'".to_string()));
+                    };
+                    let name = &path.identifier[1..path.identifier.len()-1];
+                    let dir = file.parent().unwrap_or(Path::new(""));
+                    let p = Import::resolve(dir, name, &self.include_paths)
+                        .ok_or_else(|| err(format!("Include file '{}' not found", name)))?;
+                    out.extend(self.include(&p, data)?);
+                }
+                o => return Err(err(format!("Unknown directive '#!{}'", o)))
+            }
+        }
+
+        if let Some(c) = conds.last() {
+            return Err(format!("{}:{}: Unterminated '#!if'", filename, c.line));
+        }
+        self.expand(pending, &filename, &mut Vec::new(), &mut out)?;
+        Ok(out)
+    }
+
+    fn include(&mut self, p: &Path, data: &mut DataSection) -> Result<Vec<TokenData>, String> {
+        let canonical = p.canonicalize().map_err(|e| format!("Cannot open '{}': {}", p.display(), e))?;
+        if let Some(idx) = self.including.iter().position(|f| *f == canonical) {
+            let chain = self.including[idx..].iter()
+                .chain(std::iter::once(&canonical))
+                .map(|f| f.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(format!("Include cycle detected: {}", chain));
+        }
+
+        let file = File::open(p).map_err(|e| format!("Cannot open '{}': {}", p.display(), e))?;
+        let meta = Token::FromIO(p, file).map_err(|e| e.to_string())?.tokenize()?;
+        data.merge(&meta.data);
+
+        let mut tokens = meta.tok_data;
+        tokens.retain(|t| t.tok_type != TokenType::EOF);
+
+        self.including.push(canonical);
+        let tokens = self.process(tokens, p, data);
+        self.including.pop();
+        tokens
+    }
+
+    /// `name(a, b) = body`
+    fn macro_def(&self, def: &[TokenData], filename: &str, line: usize) -> Result<(String, Macro), String> {
+        let Some(name) = def.first().filter(|n| n.tok_type == TokenType::Identifier) else {
+            return Err("Expect macro name after '#!macro'".to_string());
+        };
+        if !def.get(1).is_some_and(|t| t.tok_type == TokenType::LeftParen) {
+            return Err(format!("Expect '(' after macro name '{}'", name.identifier));
+        }
+
+        let mut params: Vec<String> = Vec::new();
+        let mut i = 2;
+        while !def.get(i).is_some_and(|t| t.tok_type == TokenType::RightParen) {
+            let Some(p) = def.get(i).filter(|p| p.tok_type == TokenType::Identifier) else {
+                return Err(format!("Expect parameter name in macro '{}'", name.identifier));
+            };
+            if params.contains(&p.identifier) {
+                return Err(format!("Duplicate parameter '{}' in macro '{}'", p.identifier, name.identifier));
+            }
+            params.push(p.identifier.clone());
+            i += 1;
+            if def.get(i).is_some_and(|t| t.tok_type == TokenType::Comma) {
+                i += 1;
+            }
+        }
+        if !def.get(i+1).is_some_and(|t| t.tok_type == TokenType::Equal) {
+            return Err(format!("Expect '=' after parameters of macro '{}'", name.identifier));
+        }
+
+        Ok((name.identifier.clone(), Macro { params, body: def[i+2..].to_vec(), file: filename.to_string(), line }))
+    }
+
+    /// Expand every macro in `tokens`, `stack` holds the macros being expanded (object-like
+    /// macros are not expanded inside themselves, like C, function-like recursion is an error)
+    fn expand(&self, tokens: Vec<TokenData>, filename: &str, stack: &mut Vec<String>, out: &mut Vec<TokenData>) -> Result<(), String> {
+        let mut iter = tokens.into_iter().peekable();
+
+        while let Some(t) = iter.next() {
+            if t.tok_type != TokenType::Identifier {
+                out.push(t);
+                continue;
+            }
+
+            if let Some(m) = self.macros.get(&t.identifier)
+                && iter.peek().is_some_and(|n| n.tok_type == TokenType::LeftParen) {
+                let err = |msg: String| format!("{}:{}: {}\nnote: macro '{}' is defined at {}:{}", filename, t.line, msg, t.identifier, m.file, m.line);
+
+                if stack.contains(&t.identifier) {
+                    return Err(err(format!("Recursive expansion of macro '{}'", t.identifier)));
+                }
+                if stack.len() == MAX_EXPANSION_DEPTH {
+                    return Err(err(format!("Macro expansion deeper than {} levels", MAX_EXPANSION_DEPTH)));
+                }
+
+                // arguments, split on top-level commas
+                iter.next();
+                let mut args: Vec<Vec<TokenData>> = vec![Vec::new()];
+                let mut depth = 0;
+                loop {
+                    let Some(a) = iter.next() else {
+                        return Err(err(format!("Unterminated call to macro '{}'", t.identifier)));
+                    };
+                    match a.tok_type {
+                        TokenType::LeftParen => depth += 1,
+                        TokenType::RightParen if depth == 0 => break,
+                        TokenType::RightParen => depth -= 1,
+                        TokenType::Comma if depth == 0 => {
+                            args.push(Vec::new());
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    args.last_mut().unwrap().push(a);
+                }
+                if m.params.is_empty() && args.len() == 1 && args[0].is_empty() {
+                    args.clear();
+                }
+                if args.len() != m.params.len() {
+                    return Err(err(format!("Macro '{}' expects {} argument(s), got {}", t.identifier, m.params.len(), args.len())));
+                }
+
+                // arguments are expanded first, then substituted
+                let mut expanded_args = Vec::new();
+                for a in args {
+                    let mut e = Vec::new();
+                    self.expand(a, filename, stack, &mut e)?;
+                    expanded_args.push(e);
+                }
+
+                if let Some((name, line)) = self.captured(m, out) {
+                    return Err(err(format!("Macro '{}' uses '{}', which is declared again at the call site (line {})", t.identifier, name, line)));
+                }
+
+                let body = self.substitute(m, &t, &expanded_args);
+                stack.push(t.identifier.clone());
+                self.expand(body, filename, stack, out).map_err(|e| {
+                    format!("{}\nnote: in expansion of macro '{}' at {}:{}", e, t.identifier, filename, t.line)
+                })?;
+                stack.pop();
+            } else if let Some(body) = self.defines.get(&t.identifier).filter(|_| !stack.contains(&t.identifier)) {
+                // report errors at the place the macro is used
+                let body = body.iter().map(|b| TokenData { line: t.line, ..b.clone() }).collect();
+                stack.push(t.identifier.clone());
+                self.expand(body, filename, stack, out)?;
+                stack.pop();
+            } else {
+                out.push(t);
+            }
+        }
+        Ok(())
+    }
+
+    /// Body of `m` with parameters replaced by the arguments of the call `at`
+    fn substitute(&self, m: &Macro, at: &TokenData, args: &[Vec<TokenData>]) -> Vec<TokenData> {
+        static EXPANSION_COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = EXPANSION_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+        // hygiene: variables declared in the body can't capture names of the caller
+        let locals = m.locals();
+
+        let paren = |tok_type: TokenType| TokenData { tok_type, start: at.start, end: at.end, line: at.line, col: at.col, identifier: String::new(), value: Value::Null, sub_tok: None };
+
+        let mut out = Vec::new();
+        for b in &m.body {
+            if let Some(idx) = m.params.iter().position(|p| *p == b.identifier).filter(|_| b.tok_type == TokenType::Identifier) {
+                // keep the argument as one operand, `max(a + 1, b)`
+                let wrap = args[idx].len() > 1 && !is_group(&args[idx]);
+                if wrap { out.push(paren(TokenType::LeftParen)); }
+                out.extend(args[idx].iter().cloned());
+                if wrap { out.push(paren(TokenType::RightParen)); }
+            } else if b.tok_type == TokenType::Identifier && locals.contains(&b.identifier) {
+                let name = format!("{}${}", b.identifier, id);
+                out.push(TokenData { line: at.line, value: Value::new_obj(name.clone()), identifier: name, ..b.clone() });
+            } else {
+                out.push(TokenData { line: at.line, ..b.clone() });
+            }
+        }
+
+        // and the whole expansion is one operand too, `10 / sq(2)`
+        if m.is_expr() && !is_group(&out) {
+            out.insert(0, paren(TokenType::LeftParen));
+            out.push(paren(TokenType::RightParen));
+        }
+        out
+    }
+
+    /// A name the body of `m` takes from where it is defined, but the call site declares again.
+    /// `out` is everything expanded before the call, searched back through the open blocks
+    /// to the enclosing `func`, so globals and closed blocks don't count
+    fn captured(&self, m: &Macro, out: &[TokenData]) -> Option<(String, usize)> {
+        let locals = m.locals();
+        let free = (0..m.body.len()).filter(|i| {
+            let b = &m.body[*i];
+            // calls and module paths `m::x` are not variables
+            let is_var = !m.body.get(i + 1).is_some_and(|n| matches!(n.tok_type, TokenType::LeftParen | TokenType::ColonColon))
+                && !i.checked_sub(1).is_some_and(|p| m.body[p].tok_type == TokenType::ColonColon);
+            b.tok_type == TokenType::Identifier && is_var && !m.params.contains(&b.identifier) && !locals.contains(&b.identifier)
+                && !self.macros.contains_key(&b.identifier) && !self.defines.contains_key(&b.identifier)
+        }).map(|i| &m.body[i].identifier).collect::<Vec<&String>>();
+
+        let (mut depth, mut inside, mut found) = (0, false, None);
+        for (i, t) in out.iter().enumerate().rev() {
+            match t.tok_type {
+                TokenType::RightBrace => depth += 1,
+                TokenType::LeftBrace if depth > 0 => depth -= 1,
+                TokenType::LeftBrace => inside = true,
+                TokenType::Keywords if depth == 0 && t.identifier == "func" => return found.filter(|_| inside),
+                TokenType::Identifier if depth == 0 && found.is_none() && free.contains(&&t.identifier) && declares(out, i) => {
+                    found = Some((t.identifier.clone(), t.line));
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn eval_cond(&self, cond: &[TokenData], filename: &str) -> Result<bool, String> {
+        if cond.is_empty() {
+            return Err("Expect condition after '#!if'".to_string());
+        }
+
+        // a flag (defined without value) is 1, like C
+        let cond = cond.iter().map(|t| match self.defines.get(&t.identifier) {
+            Some(body) if body.is_empty() && t.tok_type == TokenType::Identifier => {
+                TokenData { tok_type: TokenType::Number, value: Value::Number(1), ..t.clone() }
+            }
+            _ => t.clone()
+        }).collect::<Vec<TokenData>>();
+        let (line, col) = cond.last().map(|t| (t.line, t.col)).unwrap();
+        let mut tokens = Vec::new();
+        self.expand(cond, filename, &mut Vec::new(), &mut tokens)?;
+        tokens.push(TokenData { tok_type: TokenType::EOF, start: 0, end: 0, line, col, identifier: String::new(), value: Value::Null, sub_tok: None });
+
+        let expr = AST::new(MetaData { filename: filename.to_string(), tok_data: tokens, data: DataSection::new() }).parse_expr()?;
+        truth(cond_value(&expr)?)
+    }
+}
+
+fn truth(v: Value) -> Result<bool, String> {
+    match v {
+        Value::Boolean(b) => Ok(b),
+        Value::Number(n) => Ok(n != 0),
+        o => Err(format!("'#!if' condition must be bool or integer, got {:?}", o))
+    }
+}
+
+/// Same as const_eval::eval but `&&`, `||` and `!` accept integers and undefined names are 0, like C
+fn cond_value(expr: &Expr) -> Result<Value, String> {
+    match expr {
+        Expr::Binary(lhs, op, rhs) if matches!(op.tok_type, TokenType::AndBool | TokenType::OrBool) => {
+            let lhs = truth(cond_value(lhs)?)?;
+            Ok(Value::Boolean(match op.tok_type {
+                TokenType::AndBool => lhs && truth(cond_value(rhs)?)?,
+                _ => lhs || truth(cond_value(rhs)?)?
+            }))
+        }
+        Expr::Unary(op, rhs) if op.tok_type == TokenType::Not => Ok(Value::Boolean(!truth(cond_value(rhs)?)?)),
+        Expr::Binary(lhs, op, rhs) => const_eval::eval_binary(cond_value(lhs)?, op, cond_value(rhs)?),
+        Expr::Unary(op, rhs) => const_eval::eval_unary(op, cond_value(rhs)?),
+        Expr::Grouping(e) => cond_value(e),
+        o => const_eval::eval(o, &|_| Some(Value::Number(0)))
+    }
+}
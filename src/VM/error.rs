@@ -0,0 +1,97 @@
+use std::fmt::{Debug, Display};
+
+/// What went wrong while executing an opcode
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// an opcode needed more operands than the stack holds
+    StackUnderflow,
+    /// call of an unknown function, or load of a variable that was never stored
+    UndefinedName(String),
+    DivisionByZero,
+    /// IndexOutOfBounds(index, length)
+    IndexOutOfBounds(i64, usize),
+    /// operands the opcode can not work on, e.g. `"a" - 1`
+    TypeMismatch(String),
+    /// an opcode the VM can not execute, e.g. INVAILD at the end of a non-void function
+    InvalidOpcode(String),
+    /// an extern function that can not be called from the VM
+    Ffi(String),
+    /// failure reported by a native function, e.g. a failed `assert`
+    Native(String),
+    /// code rejected by the verifier before it ran, e.g. a jump out of its function
+    Verify(String),
+    /// not a failure, the script called `exit(code)`
+    Exit(i32)
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            ErrorKind::UndefinedName(n) => write!(f, "undefined name '{}'", n),
+            ErrorKind::DivisionByZero => write!(f, "division by zero"),
+            ErrorKind::IndexOutOfBounds(idx, len) => write!(f, "index {} out of bounds for length {}", idx, len),
+            ErrorKind::TypeMismatch(s) => write!(f, "type mismatch: {}", s),
+            ErrorKind::InvalidOpcode(op) => write!(f, "invalid opcode {}", op),
+            ErrorKind::Ffi(s) => write!(f, "foreign call: {}", s),
+            ErrorKind::Native(s) => write!(f, "{}", s),
+            ErrorKind::Verify(s) => write!(f, "invalid bytecode: {}", s),
+            ErrorKind::Exit(code) => write!(f, "exit with code {}", code)
+        }
+    }
+}
+
+/// One active call when the error was raised
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    /// `None` for top-level code
+    pub function: Option<String>,
+    /// index of the executing opcode, the call site for callers
+    pub ip: usize,
+    /// source line of `ip`, 0 when unknown
+    pub line: usize
+}
+
+impl Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.function.as_deref().unwrap_or("<top level>");
+        match self.line {
+            0 => write!(f, "in {} (opcode {})", name, self.ip),
+            line => write!(f, "in {} at line {} (opcode {})", name, line, self.ip)
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct VMError {
+    pub kind: ErrorKind,
+    /// index of the failing opcode
+    pub ip: usize,
+    /// source line of the failing opcode, 0 when unknown
+    pub line: usize,
+    /// active calls, innermost first
+    pub trace: Vec<TraceEntry>
+}
+
+impl VMError {
+    /// Error of a native function, the VM fills in where it happened
+    pub fn new(kind: ErrorKind) -> Self {
+        Self { kind, ip: 0, line: 0, trace: Vec::new() }
+    }
+}
+
+impl Display for VMError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        for entry in &self.trace {
+            write!(f, "\n    {}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for VMError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at opcode {} (line {})", self.kind, self.ip, self.line)
+    }
+}
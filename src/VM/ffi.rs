@@ -0,0 +1,214 @@
+/*
+ * Calls from the VM into native C libraries.
+ *
+ * `extern func` declarations are resolved with dlsym, first in the process
+ * itself (libc and everything linked into dcz), then in the libraries
+ * loaded with `VM::load_library`.
+ *
+ * There is no libffi: every function is called through one signature with
+ * six integer and eight floating point arguments. With the System V (x86_64)
+ * and AAPCS64 (aarch64 Linux) conventions integer and float arguments go to
+ * separate register files, so the callee finds each argument where it
+ * expects it and ignores the unused registers. Arguments that would be
+ * passed on the stack are rejected. On any other target (Windows, macOS on
+ * Apple silicon, 32-bit) an extern call is an `ErrorKind::Ffi`.
+ *
+ * A `char*` argument is a NUL-terminated copy of the string that lives until
+ * the call returns. What the callee writes into it (fgets, strcpy, sprintf)
+ * is not copied back, and there is no room past the end of the string.
+ */
+
+use std::ffi::{c_void, CStr, CString};
+
+use crate::{Value::Value, AST::expr_node::{DataType, Func_Header}};
+
+use super::error::ErrorKind;
+
+const INT_ARGS: usize = 6;
+const FLOAT_ARGS: usize = 8;
+
+/// A dlopen handle or a dlsym address
+#[derive(Clone, Copy)]
+struct DlPtr(*mut c_void);
+
+// SAFETY: handles and symbols belong to the process, not to the thread that
+// got them, and dlsym/dlclose may be called from any thread
+unsafe impl Send for DlPtr {}
+
+/// Shared libraries symbols are looked up in, the process comes first
+pub struct Libraries {
+    handles: Vec<DlPtr>
+}
+
+fn dl_error() -> String {
+    // SAFETY: dlerror gives back a C string or null
+    unsafe {
+        let e = libc::dlerror();
+        if e.is_null() { "unknown error".to_string() } else { CStr::from_ptr(e).to_string_lossy().into_owned() }
+    }
+}
+
+impl Default for Libraries {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Libraries {
+    pub fn new() -> Self {
+        // SAFETY: a null path opens the main program
+        let process = unsafe { libc::dlopen(std::ptr::null(), libc::RTLD_NOW) };
+        Self { handles: if process.is_null() { Vec::new() } else { vec![DlPtr(process)] } }
+    }
+
+    pub fn open(&mut self, path: &str) -> Result<(), String> {
+        let c_path = CString::new(path).map_err(|e| e.to_string())?;
+        // SAFETY: c_path is a valid C string
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW) };
+        if handle.is_null() {
+            return Err(format!("Can't load library '{}': {}", path, dl_error()));
+        }
+        self.handles.push(DlPtr(handle));
+        Ok(())
+    }
+
+    fn symbol(&self, name: &str) -> Option<DlPtr> {
+        let c_name = CString::new(name).ok()?;
+        self.handles.iter()
+            // SAFETY: handles are open, c_name is a valid C string
+            .map(|h| DlPtr(unsafe { libc::dlsym(h.0, c_name.as_ptr()) }))
+            .find(|addr| !addr.0.is_null())
+    }
+
+    /// Resolve the C function of an extern declaration
+    pub fn resolve(&self, header: Func_Header) -> Result<ExternFunc, ErrorKind> {
+        let addr = self.symbol(&header.name).ok_or_else(|| ErrorKind::UndefinedName(header.name.clone()))?;
+        let ints = header.args.iter().filter(|(dt, _, is_p)| !is_float(dt, *is_p)).count();
+        let floats = header.args.len() - ints;
+        if ints > INT_ARGS || floats > FLOAT_ARGS {
+            return Err(ErrorKind::Ffi(format!("'{}' has more arguments than the VM can pass ({} integer, {} float)", header.name, INT_ARGS, FLOAT_ARGS)));
+        }
+        Ok(ExternFunc { header, addr })
+    }
+}
+
+impl Drop for Libraries {
+    fn drop(&mut self) {
+        for h in &self.handles {
+            // SAFETY: every handle came from dlopen and is closed once
+            unsafe { libc::dlclose(h.0); }
+        }
+    }
+}
+
+fn is_float(dt: &DataType, is_p: bool) -> bool {
+    !is_p && matches!(dt, DataType::Float | DataType::Suu)
+}
+
+/// C function of an `extern func` declaration
+#[derive(Clone)]
+pub struct ExternFunc {
+    header: Func_Header,
+    // only called on the targets `invoke` supports
+    #[cfg_attr(not(all(unix, any(target_arch = "x86_64", all(target_arch = "aarch64", not(target_vendor = "apple"))))), allow(dead_code))]
+    addr: DlPtr
+}
+
+impl ExternFunc {
+    pub fn name(&self) -> &str {
+        &self.header.name
+    }
+
+    pub fn arity(&self) -> usize {
+        self.header.args.len()
+    }
+
+    /// Marshal `args` by the declared parameter types, call, and convert the result back
+    pub fn call(&self, args: &[Value]) -> Result<Option<Value>, ErrorKind> {
+        let mismatch = |v: &Value, dt: &DataType| ErrorKind::TypeMismatch(
+            format!("{:?} passed as {:?} to '{}'", v, dt, self.header.name));
+
+        // the C strings live until the call returns
+        let mut strings = Vec::new();
+        let (mut ints, mut floats) = (Vec::new(), Vec::new());
+        for ((dt, _, is_p), v) in self.header.args.iter().zip(args) {
+            if is_float(dt, *is_p) {
+                floats.push(match (dt, v) {
+                    // a float argument only uses the low half of its register
+                    (DataType::Float, Value::Float(f)) => f64::from_bits(f.to_bits() as u64),
+                    (DataType::Float, Value::Number(n)) => f64::from_bits((*n as f32).to_bits() as u64),
+                    (_, Value::Double(d)) => *d,
+                    (_, Value::Float(f)) => *f as f64,
+                    (_, Value::Number(n)) => *n as f64,
+                    _ => return Err(mismatch(v, dt))
+                });
+                continue;
+            }
+            ints.push(match v {
+                Value::Number(n) => *n,
+                Value::Char(c) => *c as i64,
+                Value::Boolean(b) => *b as i64,
+                Value::Null if *is_p => 0,
+                Value::Str(s) if *is_p => {
+                    let c_str = CString::new(s.as_str()).map_err(|_| mismatch(v, dt))?;
+                    let ptr = c_str.as_ptr() as i64;
+                    strings.push(c_str);
+                    ptr
+                }
+                _ => return Err(mismatch(v, dt))
+            });
+        }
+        ints.resize(INT_ARGS, 0);
+        floats.resize(FLOAT_ARGS, 0.0);
+
+        let v = self.invoke(&ints, &floats);
+        drop(strings);
+        v
+    }
+
+    /// Call through the one signature, every argument is in its register
+    #[cfg(all(unix, any(target_arch = "x86_64", all(target_arch = "aarch64", not(target_vendor = "apple")))))]
+    fn invoke(&self, i: &[i64], f: &[f64]) -> Result<Option<Value>, ErrorKind> {
+        // the float arguments are variadic, so callers like printf get the vector register count
+        type IntFn = unsafe extern "C" fn(i64, i64, i64, i64, i64, i64, ...) -> i64;
+        type FloatFn = unsafe extern "C" fn(i64, i64, i64, i64, i64, i64, ...) -> f32;
+        type DoubleFn = unsafe extern "C" fn(i64, i64, i64, i64, i64, i64, ...) -> f64;
+
+        let ret = self.header.return_type.clone().unwrap_or(DataType::Void);
+        let is_p = self.header.is_ptr_dt;
+        // SAFETY: addr is the symbol of the declared function, its arguments are all in registers
+        let v = unsafe {
+            match ret {
+                DataType::Float if !is_p => {
+                    let func: FloatFn = std::mem::transmute(self.addr.0);
+                    Value::Float(func(i[0], i[1], i[2], i[3], i[4], i[5], f[0], f[1], f[2], f[3], f[4], f[5], f[6], f[7]))
+                }
+                DataType::Suu if !is_p => {
+                    let func: DoubleFn = std::mem::transmute(self.addr.0);
+                    Value::Double(func(i[0], i[1], i[2], i[3], i[4], i[5], f[0], f[1], f[2], f[3], f[4], f[5], f[6], f[7]))
+                }
+                _ => {
+                    let func: IntFn = std::mem::transmute(self.addr.0);
+                    let r = func(i[0], i[1], i[2], i[3], i[4], i[5], f[0], f[1], f[2], f[3], f[4], f[5], f[6], f[7]);
+                    match ret {
+                        DataType::Char if is_p && r == 0 => Value::Null,
+                        DataType::Char if is_p => Value::Str(CStr::from_ptr(r as *const libc::c_char).to_string_lossy().into_owned()),
+                        _ if is_p => Value::Number(r),
+                        DataType::Void => return Ok(None),
+                        DataType::Bool => Value::Boolean(r as u8 != 0),
+                        DataType::Char => Value::Char(r as u8 as char),
+                        DataType::Short => Value::Number(r as i16 as i64),
+                        DataType::Long => Value::Number(r),
+                        _ => Value::Number(r as i32 as i64)
+                    }
+                }
+            }
+        };
+        Ok(Some(v))
+    }
+
+    #[cfg(not(all(unix, any(target_arch = "x86_64", all(target_arch = "aarch64", not(target_vendor = "apple"))))))]
+    fn invoke(&self, _: &[i64], _: &[f64]) -> Result<Option<Value>, ErrorKind> {
+        Err(ErrorKind::Ffi(format!("can't call '{}', extern calls need the System V x86_64 or aarch64 Linux calling convention", self.header.name)))
+    }
+}
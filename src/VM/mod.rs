@@ -1,2 +1,6 @@
 pub mod vm;
 pub mod stack;
+pub mod error;
+pub mod ffi;
+pub mod native;
+pub mod verify;
@@ -0,0 +1,115 @@
+/*
+ * Functions implemented in Rust that scripts call like their own functions.
+ *
+ * The builtins are always there, embedders add theirs with
+ * `VM::register_native`. A script function or an extern with the same name
+ * takes precedence.
+ */
+
+use std::io::{BufRead, Write};
+
+use crate::{Value::Value, AST::expr_node::{DataType, Func_Header}};
+
+use super::{error::{ErrorKind, VMError}, vm::truthy};
+
+/// A host function, a void native returns `Value::Null`
+pub type NativeFn = fn(&[Value]) -> Result<Value, VMError>;
+
+#[derive(Clone)]
+pub struct Native {
+    /// signature given to the checker, `DataType::Unknown` accepts anything
+    pub header: Func_Header,
+    pub func: NativeFn
+}
+
+impl Native {
+    /// A native taking `arity` values of any type and returning any type
+    pub fn new(name: &str, arity: usize, func: NativeFn) -> Self {
+        Self::typed(name, vec![DataType::Unknown; arity], DataType::Unknown, false, func)
+    }
+
+    fn typed(name: &str, args: Vec<DataType>, return_type: DataType, is_ptr_dt: bool, func: NativeFn) -> Self {
+        let args = args.into_iter().enumerate().map(|(idx, dt)| (dt, format!("arg{}", idx), false)).collect();
+        Self { header: Func_Header { name: name.to_string(), args, return_type: Some(return_type), is_ptr_dt }, func }
+    }
+}
+
+/// Text of a value as `print` shows it
+pub fn format_value(v: &Value) -> String {
+    match v {
+        Value::Null => "null".to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Double(d) => d.to_string(),
+        Value::Str(s) | Value::Object(s) => s.clone(),
+        Value::Char(c) => c.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::List(l) => format!("[{}]", l.iter().map(format_value).collect::<Vec<_>>().join(", "))
+    }
+}
+
+fn print(args: &[Value]) -> Result<Value, VMError> {
+    print!("{}", format_value(&args[0]));
+    std::io::stdout().flush().map_err(|e| VMError::new(ErrorKind::Native(e.to_string())))?;
+    Ok(Value::Null)
+}
+
+fn println(args: &[Value]) -> Result<Value, VMError> {
+    println!("{}", format_value(&args[0]));
+    Ok(Value::Null)
+}
+
+fn len(args: &[Value]) -> Result<Value, VMError> {
+    match &args[0] {
+        Value::Str(s) => Ok(Value::Number(s.chars().count() as i64)),
+        Value::List(l) => Ok(Value::Number(l.len() as i64)),
+        v => Err(VMError::new(ErrorKind::TypeMismatch(format!("len of {:?}", v))))
+    }
+}
+
+fn assert(args: &[Value]) -> Result<Value, VMError> {
+    if truthy(args[0].clone()).map_err(VMError::new)? {
+        Ok(Value::Null)
+    } else {
+        Err(VMError::new(ErrorKind::Native("assertion failed".to_string())))
+    }
+}
+
+/// Seconds since the Unix epoch
+fn clock(_: &[Value]) -> Result<Value, VMError> {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+        .map_err(|e| VMError::new(ErrorKind::Native(e.to_string())))?;
+    Ok(Value::Double(now.as_secs_f64()))
+}
+
+fn exit(args: &[Value]) -> Result<Value, VMError> {
+    match &args[0] {
+        Value::Number(n) => Err(VMError::new(ErrorKind::Exit(*n as i32))),
+        v => Err(VMError::new(ErrorKind::TypeMismatch(format!("exit with {:?}", v))))
+    }
+}
+
+/// A line of stdin without its line break, null at the end of input
+fn read_line(_: &[Value]) -> Result<Value, VMError> {
+    let mut line = String::new();
+    let n = std::io::stdin().lock().read_line(&mut line)
+        .map_err(|e| VMError::new(ErrorKind::Native(e.to_string())))?;
+    if n == 0 {
+        return Ok(Value::Null);
+    }
+    let trimmed = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(trimmed);
+    Ok(Value::Str(line))
+}
+
+pub fn builtins() -> Vec<Native> {
+    vec![
+        Native::typed("print", vec![DataType::Unknown], DataType::Void, false, print),
+        Native::typed("println", vec![DataType::Unknown], DataType::Void, false, println),
+        Native::typed("len", vec![DataType::Unknown], DataType::Int, false, len),
+        Native::typed("assert", vec![DataType::Unknown], DataType::Void, false, assert),
+        Native::typed("clock", vec![], DataType::Suu, false, clock),
+        Native::typed("exit", vec![DataType::Int], DataType::Void, false, exit),
+        Native::typed("read_line", vec![], DataType::Char, true, read_line),
+    ]
+}
@@ -1,18 +1,35 @@
-use crate::{codegen::ir_opcode::Opcode, Value::Value};
+use std::sync::{Arc, Mutex};
+
+use crate::Value::Value;
+
+/// A string or a list, every copy of the pointer sees a store through another one
+pub type Pointer = Arc<Mutex<Value>>;
 
 #[derive(Clone,Debug)]
 pub enum Stack {
     Value(Value),
+    Pointer(Pointer),
     MemoryAddr(usize),
-    CompressedFunc(Vec<Opcode>)
+    /// Function(entry ip, number of params)
+    Function(usize, usize),
+    /// Extern(index in the VM's resolved extern functions)
+    Extern(usize)
 }
 
 impl Stack {
+    /// Strings and lists are kept behind a pointer, like `char*` in the native backend
+    pub fn new(v: Value) -> Self {
+        match v {
+            Value::Str(_) | Value::List(_) => Self::Pointer(Arc::new(Mutex::new(v))),
+            v => Self::Value(v)
+        }
+    }
     pub fn as_value(self) -> Value {
-        if let Self::Value(v) = self {
-            return v;
+        match self {
+            Self::Value(v) => v,
+            Self::Pointer(p) => p.lock().unwrap().clone(),
+            _ => Value::Null
         }
-        Value::Null
     }
     pub fn as_memory_addr(self) -> usize {
         if let Self::MemoryAddr(addr) = self {
@@ -20,10 +37,4 @@ impl Stack {
         }
         0
     }
-    pub fn as_compressed_func(self) -> Vec<Opcode> {
-        if let Self::CompressedFunc(f) = self {
-            return f;
-        }
-        Vec::new()
-    }
 }
@@ -0,0 +1,230 @@
+/*
+ * Static checks of VM code before it runs.
+ *
+ * Ast2Ir computes jump offsets and function sizes by hand, a wrong one
+ * would make the VM run off the code or underflow its stack. Every opcode
+ * reachable from the top-level code or a function entry is walked once:
+ *
+ *  - MAKEFUNC bodies end with their END_FUNC, an END_FUNC has a MAKEFUNC
+ *  - jumps land in their own function (or the top-level code)
+ *  - all paths meet with the same stack depth and the same open blocks
+ *  - END has a BEGIN, END_FUNC and the end of the code are reached with
+ *    every block closed
+ *  - operands are never popped from an empty stack, calls go to known
+ *    functions and constants exist
+ *  - INVAILD, and the opcodes the VM can not execute, are unreachable
+ *
+ * Errors use the kind the VM would have raised at run time where there is
+ * one, `ErrorKind::Verify` otherwise.
+ */
+
+use std::collections::HashMap;
+
+use crate::{codegen::ir_opcode::{ConstantPool, Opcode}, AST::expr_node::Func_Header};
+
+use super::error::{ErrorKind, VMError};
+
+/// Code of the top level or of one function
+struct Region {
+    /// `None` for the top level
+    name: Option<String>,
+    start: usize,
+    /// index of the END_FUNC, the code length for the top level
+    end: usize,
+    /// whether the function gives back a value
+    returns: bool
+}
+
+/// Stack depth and open blocks before an opcode
+type State = (usize, usize);
+
+struct Verifier<'a> {
+    code: &'a [Opcode],
+    lines: &'a [usize],
+    regions: Vec<Region>,
+    /// region each opcode belongs to, a MAKEFUNC to the code around it
+    owner: Vec<usize>,
+    /// arity of each callable name, a call always leaves one value
+    signatures: HashMap<String, usize>,
+    /// state of each opcode, one more for the end of the code
+    states: Vec<Option<State>>
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&self, kind: ErrorKind, ip: usize) -> VMError {
+        VMError { kind, ip, line: self.lines.get(ip).copied().unwrap_or(0), trace: Vec::new() }
+    }
+
+    fn verify_error(&self, msg: String, ip: usize) -> VMError {
+        self.error(ErrorKind::Verify(msg), ip)
+    }
+
+    /// Split `start..end` into functions, recursively for nested MAKEFUNCs
+    fn scan(&mut self, region: usize, start: usize, end: usize) -> Result<(), VMError> {
+        let mut ip = start;
+        while ip < end {
+            self.owner[ip] = region;
+            match &self.code[ip] {
+                Opcode::MakeFunc(size, name) => {
+                    let close = ip.checked_add(size + 1).filter(|close| *close < end && matches!(self.code[*close], Opcode::EndFunc))
+                        .ok_or_else(|| self.verify_error(format!("body of '{}' does not end with END_FUNC", name), ip))?;
+                    let inner = self.regions.len();
+                    self.regions.push(Region { name: Some(name.clone()), start: ip + 1, end: close, returns: false });
+                    self.owner[close] = inner;
+                    self.scan(inner, ip + 1, close)?;
+                    ip = close + 1;
+                }
+                Opcode::EndFunc => return Err(self.verify_error("END_FUNC without MAKEFUNC".to_string(), ip)),
+                _ => ip += 1
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the opcode at `ip` continues and with which state
+    fn successors(&self, region: &Region, ip: usize, (depth, blocks): State) -> Result<Vec<(usize, State)>, VMError> {
+        let op = &self.code[ip];
+        let need = |n: usize| if depth < n { Err(self.error(ErrorKind::StackUnderflow, ip)) } else { Ok(depth - n) };
+        let target = |t: Option<usize>| t.ok_or_else(|| self.verify_error(format!("{:?} jumps before the start of the code", op), ip));
+        let func = || region.name.clone().ok_or_else(|| self.error(ErrorKind::InvalidOpcode(format!("{:?} outside of a function", op)), ip));
+        let next = ip + 1;
+
+        Ok(match op {
+            Opcode::Invaild | Opcode::StoreArg(_) => return Err(self.error(ErrorKind::InvalidOpcode(format!("{:?}", op)), ip)),
+            Opcode::Nop | Opcode::StoreParam(_, _) | Opcode::MakeExtern(_) => vec![(next, (depth, blocks))],
+            Opcode::Constant(_) | Opcode::LoadConstant(_) | Opcode::Push(_) | Opcode::LoadGlobal(_) | Opcode::LoadLocal(_) => vec![(next, (depth + 1, blocks))],
+            Opcode::StoreGlobal(_) | Opcode::StoreLocal(_) | Opcode::Pop => vec![(next, (need(1)?, blocks))],
+            Opcode::Not | Opcode::Neg | Opcode::BitNot => vec![(next, (need(1)? + 1, blocks))],
+            Opcode::BinOp(_) | Opcode::Index => vec![(next, (need(2)? + 1, blocks))],
+            Opcode::Dup => vec![(next, (need(1)? + 2, blocks))],
+            Opcode::SetIndex => vec![(next, (need(3)?, blocks))],
+            Opcode::Begin => vec![(next, (depth, blocks + 1))],
+            Opcode::End => match blocks {
+                0 => return Err(self.verify_error("END without BEGIN".to_string(), ip)),
+                _ => vec![(next, (depth, blocks - 1))]
+            },
+            Opcode::Jmp(off) => vec![(target(ip.checked_add(*off))?, (depth, blocks))],
+            Opcode::JBackward(off) => {
+                if *off == 0 {
+                    return Err(self.verify_error("JBackward (0) has no target".to_string(), ip));
+                }
+                vec![(target(next.checked_sub(*off))?, (depth, blocks))]
+            }
+            Opcode::JIfFalse(off) => {
+                let depth = need(1)?;
+                vec![(next, (depth, blocks)), (target(next.checked_add(*off))?, (depth, blocks))]
+            }
+            Opcode::Switch(table, default) => {
+                let depth = need(1)?;
+                table.iter().map(|(_, off)| off).chain(std::iter::once(default))
+                    .map(|off| Ok((target(next.checked_add(*off))?, (depth, blocks)))).collect::<Result<_, VMError>>()?
+            }
+            Opcode::MakeFunc(size, _) => vec![(ip + size + 2, (depth, blocks))],
+            Opcode::Call(name) => {
+                let arity = *self.signatures.get(name).ok_or_else(|| self.error(ErrorKind::UndefinedName(name.clone()), ip))?;
+                vec![(next, (need(arity)? + 1, blocks))]
+            }
+            Opcode::Return(v) => {
+                let name = func()?;
+                if region.returns && v.is_none() {
+                    return Err(self.verify_error(format!("'{}' returns without a value on some paths", name), ip));
+                }
+                Vec::new()
+            }
+            Opcode::ReturnValue => {
+                func()?;
+                need(1)?;
+                Vec::new()
+            }
+            Opcode::EndFunc => {
+                let name = func()?;
+                if region.returns {
+                    return Err(self.verify_error(format!("'{}' can end without returning a value", name), ip));
+                }
+                if blocks != 0 {
+                    return Err(self.verify_error(format!("END_FUNC of '{}' with {} open blocks", name, blocks), ip));
+                }
+                Vec::new()
+            }
+        })
+    }
+
+    fn walk(&mut self, region: usize) -> Result<(), VMError> {
+        let start = self.regions[region].start;
+        self.states[start] = Some((0, 0));
+        let mut work = vec![start];
+        while let Some(ip) = work.pop() {
+            let state = self.states[ip].expect("queued opcodes have a state");
+            if ip == self.code.len() {
+                if state.1 != 0 {
+                    return Err(self.verify_error(format!("the code ends with {} open blocks", state.1), ip));
+                }
+                continue;
+            }
+            for (t, s) in self.successors(&self.regions[region], ip, state)? {
+                if self.owner.get(t) != Some(&region) {
+                    return Err(self.verify_error(format!("{:?} leaves its function, target {}", self.code[ip], t), ip));
+                }
+                match self.states[t] {
+                    None => {
+                        self.states[t] = Some(s);
+                        work.push(t);
+                    }
+                    Some(other) if other != s => return Err(self.verify_error(format!(
+                        "paths meet at {} with stack depth {} and {} open blocks, and with stack depth {} and {} open blocks",
+                        t, other.0, other.1, s.0, s.1), ip)),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check `code` before the VM runs it, `natives` are the host functions it may call
+pub fn verify(code: &[Opcode], lines: &[usize], c_pool: &ConstantPool, natives: &[Func_Header]) -> Result<(), VMError> {
+    let mut v = Verifier {
+        code, lines,
+        regions: vec![Region { name: None, start: 0, end: code.len(), returns: false }],
+        owner: vec![0; code.len() + 1],
+        signatures: HashMap::new(),
+        states: vec![None; code.len() + 1]
+    };
+    v.scan(0, 0, code.len())?;
+
+    // script functions and externs come before natives, like in the VM
+    for n in natives {
+        v.signatures.insert(n.name.clone(), n.args.len());
+    }
+    for (ip, op) in code.iter().enumerate() {
+        match op {
+            Opcode::MakeExtern(h) => {
+                v.signatures.insert(h.name.clone(), h.args.len());
+            }
+            Opcode::LoadConstant(idx) if *idx >= c_pool.len() => {
+                return Err(v.error(ErrorKind::InvalidOpcode(format!("{:?}", op)), ip));
+            }
+            _ => {}
+        }
+    }
+    for r in v.regions.iter_mut().skip(1) {
+        let body = &code[r.start..r.end];
+        let arity = body.iter().take_while(|o| matches!(o, Opcode::StoreParam(_, _))).count();
+        // nested functions are skipped
+        let mut ip = 0;
+        while ip < body.len() {
+            match &body[ip] {
+                Opcode::Return(Some(_)) | Opcode::ReturnValue => r.returns = true,
+                Opcode::MakeFunc(size, _) => ip += size + 1,
+                _ => {}
+            }
+            ip += 1;
+        }
+        v.signatures.insert(r.name.clone().expect("functions are named"), arity);
+    }
+
+    for region in 0..v.regions.len() {
+        v.walk(region)?;
+    }
+    Ok(())
+}
@@ -1,151 +1,529 @@
-use std::{collections::HashMap, fmt::Debug};
+use std::{collections::HashMap, sync::Arc};
 
-use crate::{codegen::ir_opcode::*, Value::Value};
+use crate::{codegen::{ir::Ir, ir_opcode::*}, token::token_type::TokenType, Value::Value, AST::expr_node::Func_Header};
 
-use super::stack::Stack;
+use super::{error::{ErrorKind, TraceEntry, VMError}, ffi::{ExternFunc, Libraries}, native::{builtins, Native, NativeFn}, stack::Stack, verify::verify};
+
+/// Activation record of a function call
+struct Frame {
+    function: String,
+    /// where execution continues after the return, `None` for the entry call
+    ret_ip: Option<usize>,
+    /// index of the first local of this frame in `VM::slots`
+    bp: usize,
+    /// operand stack height at the call, restored on return
+    sp: usize,
+    /// `VM::scopes` height at the call, blocks left open by a return are dropped
+    scope_depth: usize
+}
+
+impl Frame {
+    fn new(function: String, ret_ip: Option<usize>, bp: usize, sp: usize, scope_depth: usize) -> Self {
+        Self { function, ret_ip, bp, sp, scope_depth }
+    }
+}
 
 pub struct VM {
     c_pool: ConstantPool,
+    /// the loaded program, `call` runs its functions
+    code: Arc<Vec<Opcode>>,
+    /// source line of each opcode, empty when the opcodes came without a line table
+    lines: Vec<usize>,
+    /// operand stack
     stack: Vec<Stack>,
-    variable_stack: HashMap<String, Stack>,
-    local_stack: Vec<Vec<String>>
+    /// globals by index
+    globals: Vec<Stack>,
+    functions: HashMap<String, Stack>,
+    libraries: Libraries,
+    /// resolved `extern func`s, `Stack::Extern` indexes them
+    externs: Vec<ExternFunc>,
+    /// host functions, kept across runs
+    natives: HashMap<String, Native>,
+    /// locals of every active frame, each frame owns `slots[bp..]` up to the next frame
+    slots: Vec<Stack>,
+    frames: Vec<Frame>,
+    /// `slots.len()` at each BEGIN, END drops the locals declared after it
+    scopes: Vec<usize>
 }
 
-pub enum VMError {
-    CompileError,
-    RuntimeError
+fn type_name(v: &Value) -> &'static str {
+    match v {
+        Value::Null => "null",
+        Value::Number(_) => "int",
+        Value::Float(_) => "float",
+        Value::Double(_) => "suu",
+        Value::Str(_) => "string",
+        Value::Object(_) => "object",
+        Value::Char(_) => "char",
+        Value::Boolean(_) => "bool",
+        Value::List(_) => "list"
+    }
 }
 
-impl Debug for VMError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            VMError::CompileError => write!(f, "Throw compile error!"),
-            VMError::RuntimeError => write!(f, "Throw runtime error!")
+/// Truth value of a condition, numbers and chars are true when not zero
+pub(super) fn truthy(v: Value) -> Result<bool, ErrorKind> {
+    match v {
+        Value::Boolean(b) => Ok(b),
+        Value::Number(n) => Ok(n != 0),
+        Value::Char(c) => Ok(c != '\0'),
+        v => Err(ErrorKind::TypeMismatch(format!("{} used as a condition", type_name(&v))))
+    }
+}
+
+/// Position `idx` in a pointer of `len` elements
+fn element(idx: Value, len: usize) -> Result<usize, ErrorKind> {
+    match idx {
+        Value::Number(n) if n >= 0 && (n as usize) < len => Ok(n as usize),
+        Value::Number(n) => Err(ErrorKind::IndexOutOfBounds(n, len)),
+        v => Err(ErrorKind::TypeMismatch(format!("index with {}", type_name(&v))))
+    }
+}
+
+/// Element of a string or a list, `p[i]`
+fn index(p: &Value, idx: Value) -> Result<Value, ErrorKind> {
+    match p {
+        Value::Str(s) => Ok(Value::Char(s.chars().nth(element(idx, s.chars().count())?).unwrap_or('\0'))),
+        Value::List(l) => Ok(l[element(idx, l.len())?].clone()),
+        v => Err(ErrorKind::TypeMismatch(format!("index into {}", type_name(v))))
+    }
+}
+
+/// Replace the element at `idx` of the string or list `p` points to, `p[i] = v`
+fn set_index(p: &mut Value, idx: Value, v: Value) -> Result<(), ErrorKind> {
+    match p {
+        Value::Str(s) => {
+            let mut chars = s.chars().collect::<Vec<char>>();
+            let at = element(idx, chars.len())?;
+            // the checker stores char constants as their code
+            chars[at] = match v {
+                Value::Char(c) => c,
+                Value::Number(n) => char::from(n as u8),
+                v => return Err(ErrorKind::TypeMismatch(format!("store of {} into a string", type_name(&v))))
+            };
+            *s = chars.into_iter().collect();
+            Ok(())
+        }
+        Value::List(l) => {
+            let at = element(idx, l.len())?;
+            l[at] = v;
+            Ok(())
         }
+        v => Err(ErrorKind::TypeMismatch(format!("index into {}", type_name(v))))
+    }
+}
+
+/// Bring mixed numbers to a common type, like the checker's implicit conversions
+fn promote(lhs: Value, rhs: Value) -> (Value, Value) {
+    match (lhs, rhs) {
+        (Value::Double(a), Value::Float(b)) => (Value::Double(a), Value::Double(b as f64)),
+        (Value::Float(a), Value::Double(b)) => (Value::Double(a as f64), Value::Double(b)),
+        (Value::Double(a), Value::Number(b)) => (Value::Double(a), Value::Double(b as f64)),
+        (Value::Number(a), Value::Double(b)) => (Value::Double(a as f64), Value::Double(b)),
+        (Value::Float(a), Value::Number(b)) => (Value::Float(a), Value::Float(b as f32)),
+        (Value::Number(a), Value::Float(b)) => (Value::Float(a as f32), Value::Float(b)),
+        // chars are small ints, `s[i] + 1`
+        (Value::Char(a), Value::Number(b)) => (Value::Number(a as i64), Value::Number(b)),
+        (Value::Number(a), Value::Char(b)) => (Value::Number(a), Value::Number(b as i64)),
+        (a, b) => (a, b)
     }
 }
+
+fn binop(op: &TokenType, lhs: Value, rhs: Value) -> Result<Value, ErrorKind> {
+    use TokenType::*;
+    let (lhs, rhs) = promote(lhs, rhs);
+    Ok(match (op, lhs, rhs) {
+        (Slash | Modulo, Value::Number(_), Value::Number(0)) => return Err(ErrorKind::DivisionByZero),
+        (_, Value::Number(a), Value::Number(b)) if !matches!(op, Less | LessEqual | Greater | GreaterEqual | EqualEqual | NotEqual | AndBool | OrBool) => {
+            // integers wrap like the native backend
+            Value::Number(match op {
+                Plus => a.wrapping_add(b),
+                Minus => a.wrapping_sub(b),
+                Star => a.wrapping_mul(b),
+                Slash => a.wrapping_div(b),
+                Modulo => a.wrapping_rem(b),
+                ShiftLeft => a.wrapping_shl(b as u32),
+                ShiftRight => a.wrapping_shr(b as u32),
+                And => a & b,
+                Or => a | b,
+                Caret => a ^ b,
+                _ => return Err(ErrorKind::InvalidOpcode(format!("[BINOP (lhs {:?} rhs)]", op)))
+            })
+        }
+        (Plus, Value::Str(a), Value::Str(b)) => Value::Str(a + &b),
+        (Plus | Minus | Star | Slash, Value::Float(a), Value::Float(b)) => Value::Float(match op {
+            Plus => a + b, Minus => a - b, Star => a * b, _ => a / b
+        }),
+        (Plus | Minus | Star | Slash, Value::Double(a), Value::Double(b)) => Value::Double(match op {
+            Plus => a + b, Minus => a - b, Star => a * b, _ => a / b
+        }),
+        (EqualEqual, a, b) => Value::Boolean(a == b),
+        (NotEqual, a, b) => Value::Boolean(a != b),
+        (Less | LessEqual | Greater | GreaterEqual, a, b) if std::mem::discriminant(&a) == std::mem::discriminant(&b) => Value::Boolean(match op {
+            Less => a < b, LessEqual => a <= b, Greater => a > b, _ => a >= b
+        }),
+        (AndBool, a, b) => Value::Boolean(truthy(a)? && truthy(b)?),
+        (OrBool, a, b) => Value::Boolean(truthy(a)? || truthy(b)?),
+        (_, a, b) => return Err(ErrorKind::TypeMismatch(format!("{:?} on {} and {}", op, type_name(&a), type_name(&b))))
+    })
+}
+
 impl VM {
     pub fn new(c_pool: ConstantPool) -> Self {
-        Self { c_pool: c_pool, stack: Vec::new(), variable_stack: HashMap::new(), local_stack: Vec::new() }
+        Self { c_pool, code: Arc::new(Vec::new()), lines: Vec::new(), stack: Vec::new(), globals: Vec::new(), functions: HashMap::new(), libraries: Libraries::new(), externs: Vec::new(),
+            natives: builtins().into_iter().map(|n| (n.header.name.clone(), n)).collect(),
+            slots: Vec::new(), frames: Vec::new(), scopes: Vec::new() }
     }
 
-    pub fn run(&mut self, opcodes: Vec<Opcode>, mut ip: usize) -> Result<(), VMError> {
-        dbg!(&opcodes);
-        let mut result = Result::Ok(());
+    /// Let scripts call `func` as `name`, with `arity` arguments of any type
+    pub fn register_native(&mut self, name: &str, arity: usize, func: NativeFn) {
+        self.natives.insert(name.to_string(), Native::new(name, arity, func));
+    }
 
-        let opcode_vec = &opcodes;
+    /// Signatures of the host functions, for the checker
+    pub fn native_headers(&self) -> Vec<Func_Header> {
+        self.natives.values().map(|n| n.header.clone()).collect()
+    }
+
+    /// Make the symbols of a shared library available to `extern func`s
+    pub fn load_library(&mut self, path: &str) -> Result<(), String> {
+        self.libraries.open(path)
+    }
+
+    pub fn run(&mut self, opcodes: Vec<Opcode>, ip: usize) -> Result<(), VMError> {
+        verify(&opcodes, &self.lines, &self.c_pool, &self.native_headers())?;
+        let result = self.execute(&opcodes, ip).map(|_| ());
+        self.reset();
+        result
+    }
+
+    /// Replace the loaded program with `ir` and run its top-level code.
+    /// Its functions and globals stay around for `call`
+    pub fn load(&mut self, ir: Ir) -> Result<(), VMError> {
+        verify(&ir.instr, &ir.lines, &ir.c_pool, &self.native_headers())?;
+        self.reset();
+        self.c_pool = ir.c_pool;
+        self.lines = ir.lines;
+        self.code = Arc::new(ir.instr);
+        let code = self.code.clone();
+        let result = self.execute(&code, 0).map(|_| ());
+        self.unwind();
+        result
+    }
+
+    pub fn has_function(&self, name: &str) -> bool {
+        matches!(self.functions.get(name), Some(Stack::Function(_, _)))
+    }
+
+    /// Call a function of the loaded program, `None` for a void function
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Option<Value>, VMError> {
+        let code = self.code.clone();
+        let entry = match self.functions.get(name) {
+            Some(Stack::Function(entry, arity)) if *arity == args.len() => *entry,
+            Some(Stack::Function(_, arity)) => return Err(self.error(ErrorKind::TypeMismatch(
+                format!("'{}' expects {} arguments, got {}", name, arity, args.len())), code.len())),
+            _ => return Err(self.error(ErrorKind::UndefinedName(name.to_string()), code.len()))
+        };
+        let bp = self.slots.len();
+        self.slots.extend(args.iter().cloned().map(Stack::new));
+        self.frames.push(Frame::new(name.to_string(), None, bp, self.stack.len(), self.scopes.len()));
+        let result = self.execute(&code, entry);
+        self.unwind();
+        result
+    }
+
+    /// Run the top-level code, then `main()`. Its return value is the exit code
+    pub fn run_main(&mut self, ir: Ir) -> Result<i32, VMError> {
+        let result = self.load(ir).and_then(|_| self.call("main", &[]));
+        self.reset();
+
+        let result = match result {
+            Err(VMError { kind: ErrorKind::Exit(code), .. }) => return Ok(code),
+            result => result?
+        };
+        Ok(match result {
+            Some(Value::Number(n)) => n as i32,
+            Some(Value::Boolean(b)) => b as i32,
+            Some(Value::Char(c)) => c as i32,
+            _ => 0
+        })
+    }
+
+    /// Drop what a finished or failed run left behind, the program stays loaded
+    fn unwind(&mut self) {
+        self.stack.clear();
+        self.slots.clear();
+        self.frames.clear();
+        self.scopes.clear();
+    }
+
+    fn reset(&mut self) {
+        self.code = Arc::new(Vec::new());
+        self.lines.clear();
+        self.stack.clear();
+        self.globals.clear();
+        self.functions.clear();
+        self.externs.clear();
+        self.slots.clear();
+        self.frames.clear();
+        self.scopes.clear();
+    }
+
+    fn line(&self, ip: usize) -> usize {
+        self.lines.get(ip).copied().unwrap_or(0)
+    }
 
-        while ip < opcode_vec.len() {
-            let op = &opcode_vec[ip];
+    /// Attach where `kind` happened and the active calls, innermost first
+    fn error(&self, kind: ErrorKind, ip: usize) -> VMError {
+        let mut trace = Vec::new();
+        let mut site = ip;
+        for frame in self.frames.iter().rev() {
+            trace.push(TraceEntry { function: Some(frame.function.clone()), ip: site, line: self.line(site) });
+            match frame.ret_ip {
+                Some(ret_ip) => site = ret_ip - 1,
+                None => break
+            }
+        }
+        // the outermost call was made by top-level code
+        if self.frames.first().is_none_or(|f| f.ret_ip.is_some()) {
+            trace.push(TraceEntry { function: None, ip: site, line: self.line(site) });
+        }
+        VMError { kind, ip, line: self.line(ip), trace }
+    }
+
+    /// Leave the current frame, gives back where to continue (None when the entry call returned)
+    fn ret(&mut self) -> Option<usize> {
+        let frame = self.frames.pop()?;
+        self.slots.truncate(frame.bp);
+        self.stack.truncate(frame.sp);
+        self.scopes.truncate(frame.scope_depth);
+        frame.ret_ip
+    }
+
+    /// Base of the current frame, top-level blocks use the slots from 0
+    fn bp(&self) -> usize {
+        self.frames.last().map_or(0, |f| f.bp)
+    }
+
+    fn pop_stack(&mut self) -> Result<Stack, ErrorKind> {
+        self.stack.pop().ok_or(ErrorKind::StackUnderflow)
+    }
+
+    fn pop(&mut self) -> Result<Value, ErrorKind> {
+        self.pop_stack().map(Stack::as_value)
+    }
+
+    /// The top `n` values, in the order they were pushed
+    fn pop_args(&mut self, n: usize) -> Result<Vec<Stack>, ErrorKind> {
+        if self.stack.len() < n {
+            return Err(ErrorKind::StackUnderflow);
+        }
+        Ok(self.stack.split_off(self.stack.len() - n))
+    }
+
+    /// Execute from `ip` until the end of `opcodes`, or until the frame active
+    /// on entry returns. Gives back the returned value
+    fn execute(&mut self, opcodes: &[Opcode], mut ip: usize) -> Result<Option<Value>, VMError> {
+        match self.dispatch(opcodes, &mut ip) {
+            Ok(v) => Ok(v),
+            Err(kind) => Err(self.error(kind, ip))
+        }
+    }
+
+    /// The interpreter loop, `ip` is left at the failing opcode on error
+    fn dispatch(&mut self, opcodes: &[Opcode], ip: &mut usize) -> Result<Option<Value>, ErrorKind> {
+        while *ip < opcodes.len() {
+            let op = &opcodes[*ip];
             match op.clone() {
-                Opcode::Nop | Opcode::EndFunc => {},
+                Opcode::Nop => {},
                 Opcode::LoadConstant(idx) => {
                     // store to stack
-                    self.stack.push(Stack::Value(self.c_pool.get(idx).expect("none_value").clone()));
+                    let v = self.c_pool.get(idx).ok_or_else(|| ErrorKind::InvalidOpcode(format!("{:?}", op)))?.clone();
+                    self.stack.push(Stack::new(v));
                 }
-                Opcode::StoreLocal(_,_,s) => {
-                    let tmp1 = self.stack.pop().unwrap();
-                    self.variable_stack.entry(s.clone()).or_insert_with(|| tmp1);
-                    let len = self.local_stack.len();
-                    self.local_stack[len-1].push(s.clone());
+                Opcode::StoreLocal(idx) => {
+                    let tmp1 = self.pop_stack()?;
+                    let slot = self.bp() + idx;
+                    // a declaration takes the next free slot
+                    if slot == self.slots.len() {
+                        self.slots.push(tmp1);
+                    } else {
+                        *self.slots.get_mut(slot).ok_or_else(|| ErrorKind::UndefinedName(format!("local #{}", idx)))? = tmp1;
+                    }
                 }
-                Opcode::End => {
-                    if self.local_stack.last().is_some() {
-                        self.local_stack.last().unwrap().iter().for_each(|f| {
-                            self.variable_stack.remove(f);
-                        });
-
-                        for _i in 0..self.local_stack.len() {
-                            self.stack.pop();
-                        }
-
-                        self.local_stack.pop();
-                     }
+                Opcode::LoadLocal(idx) => {
+                    let v = self.slots.get(self.bp() + idx).ok_or_else(|| ErrorKind::UndefinedName(format!("local #{}", idx)))?.clone();
+                    self.stack.push(v);
+                }
+                Opcode::StoreParam(_, _) => {
+                    // arguments are already in their slots
                 }
                 Opcode::Begin => {
-                    self.local_stack.push(Vec::new());
+                    self.scopes.push(self.slots.len());
+                }
+                Opcode::End => {
+                    let mark = self.scopes.pop().unwrap_or(0);
+                    self.slots.truncate(mark);
+                }
+                Opcode::Return(_) | Opcode::ReturnValue | Opcode::EndFunc => {
+                    if self.frames.is_empty() {
+                        return Err(ErrorKind::InvalidOpcode(format!("{:?} outside of a function", op)));
+                    }
+                    // END_FUNC is only reached by falling off the end of a void function
+                    let v = match op {
+                        Opcode::Return(v) => v.clone().map(Stack::new),
+                        // a returned pointer still points to the caller's string
+                        Opcode::ReturnValue => Some(self.pop_stack()?),
+                        _ => None
+                    };
+                    match self.ret() {
+                        Some(ret_ip) => {
+                            // every call leaves one value, null for a void function
+                            self.stack.push(v.unwrap_or(Stack::Value(Value::Null)));
+                            *ip = ret_ip;
+                            continue;
+                        }
+                        None => return Ok(v.map(Stack::as_value))
+                    }
                 }
-                Opcode::StoreGlobal(_,_,s) => {
-                    let tmp1 = self.stack.pop().unwrap(); // get value
-                    self.variable_stack.entry(s.clone()).or_insert_with(|| tmp1);
+                Opcode::StoreGlobal(idx) => {
+                    let tmp1 = self.pop_stack()?; // get value
+                    if idx >= self.globals.len() {
+                        self.globals.resize(idx+1, Stack::Value(Value::Null));
+                    }
+                    self.globals[idx] = tmp1;
                 }
-                Opcode::LoadName(n) => {
-                    self.stack.push(
-                        self.variable_stack.get(&n).unwrap().clone()
-                    );
+                Opcode::LoadGlobal(idx) => {
+                    let v = self.globals.get(idx).ok_or_else(|| ErrorKind::UndefinedName(format!("global #{}", idx)))?.clone();
+                    self.stack.push(v);
                 }
                 Opcode::BinOp(op) => {
-                    let tmp1 = self.stack.pop().unwrap().as_value();
-                    let tmp2 = self.stack.pop().unwrap().as_value();
-                    self.stack.push(Stack::Value(
-                        match op.tok_type {
-                            crate::token::token_type::TokenType::Plus => tmp2 + tmp1,
-                            crate::token::token_type::TokenType::Minus => tmp2 - tmp1,
-                            crate::token::token_type::TokenType::Star => tmp2 * tmp1,
-                            crate::token::token_type::TokenType::Slash => tmp2 / tmp1,
-                            crate::token::token_type::TokenType::Less => Value::Boolean(tmp2 < tmp1),
-                            crate::token::token_type::TokenType::LessEqual => Value::Boolean(tmp2 <= tmp1),
-                            crate::token::token_type::TokenType::Greater => Value::Boolean(tmp2 > tmp1),
-                            crate::token::token_type::TokenType::GreaterEqual => Value::Boolean(tmp2 >= tmp1),
-                            crate::token::token_type::TokenType::NotEqual => Value::Boolean(tmp2 != tmp1),
-                            crate::token::token_type::TokenType::EqualEqual => Value::Boolean(tmp2 == tmp1),
-                            crate::token::token_type::TokenType::ShiftLeft => tmp2 << tmp1,
-                            crate::token::token_type::TokenType::ShiftRight => tmp2 >> tmp1,
-                            _ => unimplemented!("I wont implement that operator!")
-                        }));
+                    let tmp1 = self.pop()?;
+                    let tmp2 = self.pop()?;
+                    self.stack.push(Stack::new(binop(&op.tok_type, tmp2, tmp1)?));
                 },
-                Opcode::Push(v) => {self.stack.push(Stack::Value(v)); },
-                Opcode::Pop => { self.stack.pop(); },
+                Opcode::Push(v) => {self.stack.push(Stack::new(v)); },
+                Opcode::Pop => { self.pop_stack()?; },
+                Opcode::Dup => {
+                    let top = self.stack.last().cloned().ok_or(ErrorKind::StackUnderflow)?;
+                    self.stack.push(top);
+                }
+                Opcode::Index => {
+                    let idx = self.pop()?;
+                    let v = match self.pop_stack()? {
+                        Stack::Pointer(p) => index(&p.lock().unwrap(), idx)?,
+                        p => index(&p.as_value(), idx)?
+                    };
+                    self.stack.push(Stack::Value(v));
+                }
+                Opcode::SetIndex => {
+                    let idx = self.pop()?;
+                    let p = self.pop_stack()?;
+                    let v = self.pop()?;
+                    // the store goes through the pointer, so the caller's string changes too
+                    match p {
+                        Stack::Pointer(p) => set_index(&mut p.lock().unwrap(), idx, v)?,
+                        p => return Err(ErrorKind::TypeMismatch(format!("index into {}", type_name(&p.as_value()))))
+                    }
+                }
                 Opcode::MakeFunc(sz,s) => {
-
-                    let v = Vec::from( opcodes[ip+1..=ip+sz].to_vec() );
-                    ip+=sz;
-                    let comfunc = Stack::CompressedFunc(v);
-                    self.variable_stack.entry(s.clone()).or_insert_with(|| comfunc);
+                    // the body runs in place when called, params come first
+                    let body = opcodes.get(*ip+1..=*ip+sz).ok_or_else(|| ErrorKind::InvalidOpcode(format!("{:?}", op)))?;
+                    let arity = body.iter().take_while(|o| matches!(o, Opcode::StoreParam(_, _))).count();
+                    self.functions.insert(s.clone(), Stack::Function(*ip+1, arity));
+                    // skip the body and its END_FUNC
+                    *ip+=sz+1;
                 },
-                Opcode::Call(_) => {
-                    let func = self.stack.pop().unwrap();
-                    self.run(func.as_compressed_func(),0)?;
+                Opcode::MakeExtern(header) => {
+                    let f = self.libraries.resolve(header)?;
+                    self.functions.insert(f.name().to_string(), Stack::Extern(self.externs.len()));
+                    self.externs.push(f);
                 }
-                Opcode::JBackward(offset) => {
-                    ip -= offset-1;
+                Opcode::Call(n) => {
+                    let (entry, arity) = match self.functions.get(&n).cloned() {
+                        Some(Stack::Function(entry, arity)) => (entry, arity),
+                        Some(Stack::Extern(idx)) => {
+                            let args = self.pop_args(self.externs[idx].arity())?.into_iter().map(Stack::as_value).collect::<Vec<_>>();
+                            let v = self.externs[idx].call(&args)?.unwrap_or(Value::Null);
+                            self.stack.push(Stack::new(v));
+                            *ip += 1;
+                            continue;
+                        }
+                        _ => {
+                            let native = self.natives.get(&n).cloned().ok_or(ErrorKind::UndefinedName(n))?;
+                            let args = self.pop_args(native.header.args.len())?.into_iter().map(Stack::as_value).collect::<Vec<_>>();
+                            // the VM knows where the native was called from
+                            let v = (native.func)(&args).map_err(|e| e.kind)?;
+                            self.stack.push(Stack::new(v));
+                            *ip += 1;
+                            continue;
+                        }
+                    };
+                    // the arguments were pushed in order, they become the first slots of the frame
+                    let args = self.pop_args(arity)?;
+                    let bp = self.slots.len();
+                    self.slots.extend(args);
+                    self.frames.push(Frame::new(n, Some(*ip+1), bp, self.stack.len(), self.scopes.len()));
+                    *ip = entry;
                     continue;
                 }
-                Opcode::Agn(n) => {
-                    let v = self.stack.pop().unwrap();
-                    *self.variable_stack.get_mut(&n).unwrap() = v;
+                Opcode::JBackward(offset) => {
+                    *ip -= offset-1;
+                    continue;
                 }
                 Opcode::JIfFalse(offset) => {
-                    let mut curr = self.stack.pop().unwrap().as_value();
-                    if !matches!(curr, Value::Boolean(_)) {
-                        curr = Value::new_boolean_from(curr.to_literal());
-                    }
-                    if curr == Value::Boolean(false) {
-                        ip += offset;
+                    let curr = self.pop()?;
+                    if !truthy(curr)? {
+                        *ip += offset;
                     }
                 }
+                Opcode::Switch(table, default) => {
+                    let key = match self.pop()? {
+                        Value::Number(n) => n,
+                        Value::Char(c) => c as i64,
+                        Value::Boolean(b) => b as i64,
+                        v => return Err(ErrorKind::TypeMismatch(format!("switch on {}", type_name(&v))))
+                    };
+                    *ip += match table.binary_search_by_key(&key, |(k, _)| *k) {
+                        Ok(idx) => table[idx].1,
+                        Err(_) => default
+                    };
+                }
                 Opcode::Jmp(offset) => {
-                    ip += offset;
+                    *ip += offset;
                     continue;
                 }
                 Opcode::Neg => {
-                    let tmp1 = self.stack.pop().unwrap().as_value();
-                    self.stack.push(Stack::Value(-tmp1));
+                    let v = match self.pop()? {
+                        Value::Number(n) => Value::Number(n.wrapping_neg()),
+                        Value::Float(f) => Value::Float(-f),
+                        Value::Double(d) => Value::Double(-d),
+                        v => return Err(ErrorKind::TypeMismatch(format!("Minus on {}", type_name(&v))))
+                    };
+                    self.stack.push(Stack::Value(v));
                 },
                 Opcode::Not => {
-                    let tmp1 = self.stack.pop().unwrap().as_value();
+                    let tmp1 = self.pop()?;
+                    if !matches!(tmp1, Value::Boolean(_) | Value::Number(_)) {
+                        return Err(ErrorKind::TypeMismatch(format!("Not on {}", type_name(&tmp1))));
+                    }
                     self.stack.push(Stack::Value(!tmp1));
                 }
+                Opcode::BitNot => {
+                    // Value's `!` is logical for 0 and 1, `~` always flips every bit
+                    let v = match self.pop()? {
+                        Value::Number(n) => Value::Number(!n),
+                        v => return Err(ErrorKind::TypeMismatch(format!("Tilde on {}", type_name(&v))))
+                    };
+                    self.stack.push(Stack::Value(v));
+                }
 
                 Opcode::Constant(v) => {
-                    self.stack.push(Stack::Value(v.clone()));
+                    self.stack.push(Stack::new(v.clone()));
                 }
-                _ => result=Err(VMError::RuntimeError),
+                _ => return Err(ErrorKind::InvalidOpcode(format!("{:?}", op))),
             }
-            ip+=1;        
+            *ip+=1;
         }
-        self.stack.clear();
-        self.variable_stack.clear();
-        result
+        Ok(None)
     }
 }
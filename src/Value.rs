@@ -69,7 +69,9 @@ impl std::ops::Mul for Value {
 impl std::ops::Not for Value {
     type Output = Value;
     fn not(self) -> Self::Output {
-        if matches!(self, Value::Number(_)) {
+        if let Value::Boolean(b) = self {
+            Value::Boolean(!b)
+        } else if matches!(self, Value::Number(_)) {
             if self.clone().to_literal() == 0 {
                 Value::Number(1)
             } else if self.clone().to_literal() == 1 {
@@ -250,6 +252,10 @@ impl Value {
         self.clone().to_any().downcast_ref::<f64>().is_some()
     }
 
+    pub fn is_bool(self) -> bool {
+        self.clone().to_any().downcast_ref::<bool>().is_some()
+    }
+
     pub fn is_string(self) -> bool {
         //self.value().expect("null value").downcast_ref::<String>().unwrap().clone()
         self.clone().to_any().downcast_ref::<String>().is_some()
@@ -258,7 +264,9 @@ impl Value {
     pub fn to_datatype(self) -> DataType {
         if matches!(self, Self::Double(_)) {
             DataType::Suu
-        } else if matches!(self, Self::Boolean(_)) || matches!(self, Self::Char(_)) {
+        } else if matches!(self, Self::Boolean(_)) {
+            DataType::Bool
+        } else if matches!(self, Self::Char(_)) {
             DataType::Char
         } else if matches!(self, Self::Number(_)) {
             DataType::Int
@@ -286,6 +294,10 @@ impl Value {
         *get_cast_value!(self, char)
     }
 
+    pub fn to_bool(self) -> bool {
+        *get_cast_value!(self, bool)
+    }
+
     pub fn to_double(self) -> f64 {
         *get_cast_value!(self, f64)
     }
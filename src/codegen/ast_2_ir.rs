@@ -1,166 +1,372 @@
-use std::sync::atomic::AtomicBool;
+use std::collections::HashMap;
 
-use crate::AST::expr_node::Expr;
+use crate::{Value::Value, AST::expr_node::{DataType, Expr}};
 
 use super::{ir::{Ir, IrBuilder}, ir_opcode::{ConstantPool, Opcode}};
 
 pub struct Ast2Ir {
     expr: Vec<Expr>,
-    pub const_pool: ConstantPool
+    pub const_pool: ConstantPool,
+    /// global name -> index in the VM globals
+    globals: HashMap<String, usize>,
+    /// locals of the function being compiled in slot order, params first
+    locals: Vec<String>,
+    /// `locals.len()` at each block, the block's locals are dropped at its end
+    scopes: Vec<usize>,
+    /// line of the statement being compiled, given to opcodes without a position of their own
+    line: usize
 }
 
+/// Opcodes of a subtree with the source line of each of them
+struct Code {
+    ops: Vec<Opcode>,
+    lines: Vec<usize>,
+    /// line given to the opcodes pushed without one
+    line: usize
+}
+
+impl Code {
+    fn push(&mut self, op: Opcode) {
+        self.push_at(op, self.line);
+    }
+
+    fn push_at(&mut self, op: Opcode, line: usize) {
+        self.ops.push(op);
+        self.lines.push(line);
+    }
+
+    fn append(&mut self, other: &mut Code) {
+        self.ops.append(&mut other.ops);
+        self.lines.append(&mut other.lines);
+    }
+
+    fn len(&self) -> usize {
+        self.ops.len()
+    }
+}
 
-fn visit_expr(e: Expr) ->Vec<Opcode> {
-    static IN_BLOCK: AtomicBool = AtomicBool::new(false);
+/// The declaration inside a top-level statement, if any
+fn top_level_decl(e: &Expr) -> Option<&String> {
     match e {
-        Expr::Statement(st) => {
-            visit_expr(*st)
+        Expr::Statement(st) => top_level_decl(st),
+        Expr::VarDecl(_, _, _, s, _, _) => Some(s),
+        _ => None
+    }
+}
+
+impl Ast2Ir  {
+    pub fn new(vect: Vec<Expr>) -> Self{
+        Ast2Ir { expr: vect, const_pool: ConstantPool::new(), globals: HashMap::new(), locals: Vec::new(), scopes: Vec::new(), line: 0 }
+    }
+
+    fn code(&self) -> Code {
+        Code { ops: Vec::new(), lines: Vec::new(), line: self.line }
+    }
+
+    fn code_of(&self, op: Opcode) -> Code {
+        let mut v = self.code();
+        v.push(op);
+        v
+    }
+
+    fn global(&mut self, n: &str) -> usize {
+        let idx = self.globals.len();
+        *self.globals.entry(n.to_string()).or_insert(idx)
+    }
+
+    /// Load of the innermost local called `n`, or of the global
+    fn load(&self, n: &str) -> Opcode {
+        match self.locals.iter().rposition(|f| f == n) {
+            Some(idx) => Opcode::LoadLocal(idx),
+            None => Opcode::LoadGlobal(*self.globals.get(n).unwrap_or_else(|| panic!("undefined variable '{}'", n)))
         }
-        Expr::Grouping(expr) => {
-            visit_expr(*expr)
+    }
+
+    /// Store to the innermost local called `n`, or to the global
+    fn store(&self, n: &str) -> Opcode {
+        match self.locals.iter().rposition(|f| f == n) {
+            Some(idx) => Opcode::StoreLocal(idx),
+            None => Opcode::StoreGlobal(*self.globals.get(n).unwrap_or_else(|| panic!("undefined variable '{}'", n)))
+        }
+    }
+
+    /// A statement, the value of an expression statement is dropped
+    fn stmt(&mut self, e: Expr) -> Code {
+        let outer = self.line;
+        if let Some(p) = e.pos() {
+            self.line = p.line;
         }
-        Expr::Binary(lhs,op ,rhs) => {
-            let lhs_op = visit_expr(*lhs);
-            let mut rhs_op = visit_expr(*rhs);
-
-            let mut v=Vec::from(lhs_op);
-            v.append(&mut rhs_op);
-            v.push(Opcode::BinOp(op));
-            v
+        let is_value = matches!(e, Expr::Callee(_, _, _) | Expr::Binary(_, _, _) | Expr::Unary(_, _) | Expr::Literal(_)
+            | Expr::Var(_) | Expr::Ternary(_, _, _) | Expr::Grouping(_) | Expr::EnumValue(_, _, _) | Expr::Index(_, _));
+        let mut v = match e {
+            // nothing uses the value, so it is not kept
+            Expr::Assign(_, _, _) | Expr::SetIndex(_, _, _, _) | Expr::Postfix(_) => self.assign(e, false),
+            e => self.visit_expr(e)
+        };
+        if is_value {
+            v.push_at(Opcode::Pop, self.line);
         }
-        Expr::VarDecl(data_type, is_p,_, s, init) => {
-            let mut v = Vec::new();
-            if init.is_some() {
-                v = visit_expr(*init.unwrap());
+        self.line = outer;
+        v
+    }
+
+    /// An assignment, `keep` leaves the value of the expression on the stack
+    fn assign(&mut self, e: Expr, keep: bool) -> Code {
+        match e {
+            Expr::Assign(n, v, _) => {
+                let mut v = self.visit_expr(*v);
+                if keep {
+                    v.push(Opcode::Dup);
+                }
+                v.push(self.store(&n));
+                v
             }
-            let mut v = Vec::from(v);
-            if !IN_BLOCK.load(std::sync::atomic::Ordering::Relaxed) {
-                v.push(Opcode::StoreGlobal(data_type,is_p,s));
-            } else {
-                v.push(Opcode::StoreLocal(data_type,is_p,s));
+            Expr::SetIndex(p, i, v, _) => {
+                /*
+                 * <value> [DUP]
+                 * <pointer> <index>
+                 * SET_INDEX
+                 * */
+                let mut v = self.visit_expr(*v);
+                if keep {
+                    v.push(Opcode::Dup);
+                }
+                v.append(&mut self.visit_expr(*p));
+                v.append(&mut self.visit_expr(*i));
+                v.push(Opcode::SetIndex);
+                v
             }
-            v
-        },
-        Expr::Var(n) => {
-            vec![Opcode::LoadName(n)]
+            Expr::Postfix(a) => {
+                // the target is read before it is assigned
+                let mut v = self.code();
+                if keep {
+                    v = self.visit_expr(a.assign_target().expect("postfix of an assignment"));
+                }
+                v.append(&mut self.assign(*a, false));
+                v
+            }
+            e => unreachable!("{:?} is not an assignment", e)
         }
-        Expr::Block(bl) => {
+    }
 
-            IN_BLOCK.store(true, std::sync::atomic::Ordering::SeqCst);
-            let mut v = Vec::new();
-            v.push(Opcode::Begin);
-            bl.iter().for_each(|f| { v.append(&mut visit_expr(f.clone()) ); });
-            IN_BLOCK.store(false, std::sync::atomic::Ordering::SeqCst);
+    fn visit_expr(&mut self, e: Expr) -> Code {
+        match e {
+            Expr::Statement(st) => {
+                self.stmt(*st)
+            }
+            Expr::Grouping(expr) => {
+                self.visit_expr(*expr)
+            }
+            Expr::Binary(lhs,op ,rhs) => {
+                let mut v = self.visit_expr(*lhs);
+                let mut rhs_op = self.visit_expr(*rhs);
 
-            v.push(Opcode::End);
-            v
-        }
-        Expr::IfStmt(cond,then , elsecase) => {
-            let mut v = Vec::from(visit_expr(*cond));
-            let mut then_v = visit_expr(*then);
-            let mut else_v = Vec::new();
-            if !matches!(*elsecase, Expr::None) {
-                else_v.append(&mut visit_expr(*elsecase));
-                then_v.push(Opcode::Jmp(else_v.len()));
+                v.append(&mut rhs_op);
+                let line = op.line;
+                v.push_at(Opcode::BinOp(op), line);
+                v
             }
-            v.push(Opcode::JIfFalse(then_v.len()));
-            v.append(&mut then_v);
-            v.append(&mut else_v);
-            v
-        }
-        Expr::Assign(n, v) => {
-            let mut v = Vec::from(visit_expr(*v));
-            v.push(Opcode::Agn(n));
-            v
-        },
-        Expr::WhileStmt(cond, body) => {
-            let mut v = Vec::from(visit_expr(*cond));
-            let cond_len = v.len();
-            if cond_len == 1 {
-                let mut body = visit_expr(*body);
-                let body_len = body.len();
-                v.append(&mut body);
-                v.push(Opcode::JBackward(body_len));
-            } else {
-                let mut body = visit_expr(*body);
-                let body_len = body.len();
-                v.push(Opcode::JIfFalse(body_len+1));
-                v.append(&mut body);
-                v.push(Opcode::JBackward(body_len+cond_len+2));
+            Expr::VarDecl(data_type, _,_, s, init, _) => {
+                let mut v = self.code();
+                if let Some(init) = init {
+                    v = self.visit_expr(*init);
+                } else {
+                    // zero initialized
+                    v.push(Opcode::Constant(match data_type {
+                        DataType::Bool => Value::Boolean(false),
+                        DataType::Float => Value::Float(0.0),
+                        DataType::Suu => Value::Double(0.0),
+                        _ => Value::Number(0)
+                    }));
+                }
+                if self.scopes.is_empty() {
+                    v.push(Opcode::StoreGlobal(self.global(&s)));
+                } else {
+                    // the next free slot, a shadowing local gets a slot of its own
+                    v.push(Opcode::StoreLocal(self.locals.len()));
+                    self.locals.push(s);
+                }
+                v
+            },
+            Expr::Var(n) => {
+                self.code_of(self.load(&n))
             }
+            Expr::Block(bl) => {
 
-            v
-        },
-        Expr::FuncStmt(f, body) => {
-            let mut v = Vec::new();
-            let expr = visit_expr(*body);
+                self.scopes.push(self.locals.len());
+                let mut v = self.code();
+                v.push(Opcode::Begin);
+                bl.iter().for_each(|f| { v.append(&mut self.stmt(f.clone()) ); });
+                let mark = self.scopes.pop().unwrap_or(0);
+                self.locals.truncate(mark);
 
-            v.push(Opcode::MakeFunc(expr.len(),f.name));
+                v.push_at(Opcode::End, self.line);
+                v
+            }
+            Expr::IfStmt(cond,then , elsecase, _) => {
+                let mut v = self.visit_expr(*cond);
+                let mut then_v = self.stmt(*then);
+                let mut else_v = self.code();
+                if !matches!(*elsecase, Expr::None) {
+                    else_v.append(&mut self.stmt(*elsecase));
+                    // JMP lands at p+offset, skip the whole else arm
+                    then_v.push(Opcode::Jmp(else_v.len()+1));
+                }
+                v.push(Opcode::JIfFalse(then_v.len()));
+                v.append(&mut then_v);
+                v.append(&mut else_v);
+                v
+            }
+            Expr::Ternary(cond, then_e, else_e) => {
+                /*
+                 * <cond>
+                 * JIFFALSE else
+                 * <then> JMP end
+                 * else: <else>
+                 * end:
+                 * */
+                let mut v = self.visit_expr(*cond);
+                let mut then_v = self.visit_expr(*then_e);
+                let mut else_v = self.visit_expr(*else_e);
+                v.push(Opcode::JIfFalse(then_v.len()+1));
+                v.append(&mut then_v);
+                v.push(Opcode::Jmp(else_v.len()+1));
+                v.append(&mut else_v);
+                v
+            }
+            Expr::SwitchStmt(value, cases, default, _) => {
+                /*
+                 * <value>
+                 * SWITCH
+                 * <case 0> JMP end
+                 * ...
+                 * <default> JMP end
+                 * end:
+                 * */
+                let mut v = self.visit_expr(*value);
+                let mut bodies = cases.iter().map(|(_, b)| self.stmt(b.clone())).collect::<Vec<Code>>();
+                bodies.push(if matches!(*default, Expr::None) { self.code() } else { self.stmt(*default) });
 
-            f.args.iter().for_each(|(d,n,_)| {
-                v.push(Opcode::StoreParam(d.clone(), n.clone()))
-            });
+                let total = bodies.iter().map(|b| b.len()+1).sum::<usize>();
+                let mut table = Vec::new();
+                let mut default_offset = 0;
+                let mut body_v = self.code();
+                for (idx, mut body) in bodies.into_iter().enumerate() {
+                    match cases.get(idx) {
+                        Some((label, _)) => table.push((label.case_value().expect("case label must be constant"), body_v.len())),
+                        None => default_offset = body_v.len()
+                    }
+                    body_v.append(&mut body);
+                    body_v.push(Opcode::Jmp(total-body_v.len()));
+                }
+                table.sort_by_key(|(k, _)| *k);
 
-            v.append(&mut expr.clone());
+                v.push(Opcode::Switch(table, default_offset));
+                v.append(&mut body_v);
+                v
+            }
+            Expr::Assign(_, _, _) | Expr::SetIndex(_, _, _, _) | Expr::Postfix(_) => self.assign(e, true),
+            Expr::Index(p, i) => {
+                let mut v = self.visit_expr(*p);
+                v.append(&mut self.visit_expr(*i));
+                v.push(Opcode::Index);
+                v
+            }
+            Expr::WhileStmt(cond, body, _) => {
+                if matches!(*cond, Expr::Literal(Value::Boolean(true))) {
+                    // no condition to test, JBACKWARD lands on the first opcode of the body
+                    let mut v = self.stmt(*body);
+                    v.push(Opcode::JBackward(v.len()+1));
+                    return v;
+                }
+                let mut v = self.visit_expr(*cond);
+                let cond_len = v.len();
+                let mut body = self.stmt(*body);
+                let body_len = body.len();
+                v.push(Opcode::JIfFalse(body_len+1));
+                v.append(&mut body);
+                v.push(Opcode::JBackward(body_len+cond_len+2));
+                v
+            },
+            Expr::FuncStmt(f, body) => {
+                // the header has no position, the function starts where its body does
+                if let Some(p) = body.pos() {
+                    self.line = p.line;
+                }
+                // MAKEFUNC covers the params and the body, up to END_FUNC
+                // the arguments are the first slots of the frame
+                let outer = std::mem::replace(&mut self.locals, f.args.iter().map(|(_,n,_)| n.clone()).collect());
+                let mut func = self.code();
+                f.args.iter().for_each(|(d,n,_)| func.push(Opcode::StoreParam(d.clone(), n.clone())));
+                func.append(&mut self.stmt(*body));
+                self.locals = outer;
 
-            let ret_last = &expr[expr.len()-2];
+                if !matches!(f.return_type, None | Some(DataType::Void)) {
+                    // falling off the end of a non-void function, the checker rejects this
+                    func.push(Opcode::Invaild)
+                }
 
-            if f.return_type.is_some() {
-                if !matches!(ret_last, Opcode::Return(_)) {
-                    v.push(Opcode::Invaild)
+                let mut v = self.code_of(Opcode::MakeFunc(func.len(), f.name));
+                v.append(&mut func);
+                v.push_at(Opcode::EndFunc, self.line);
+                //v.push(Opcode::StoreName(n));
+                v
+            },
+            Expr::Callee(n, args, pos) => {
+                let mut v = self.code();
+                // arguments are pushed in order, the callee finds them in its first slots
+                for x in args {
+                    v.append(&mut self.visit_expr(x));
                 }
-            } else {
-                if matches!(ret_last, Opcode::Return(_)) {
-                    v.push(Opcode::Invaild)
+                v.push_at(Opcode::Call(n.ident_to_string()), pos.line);
+                v
+            },
+            Expr::Return(val_ret, _) => {
+                match val_ret.map(|v| *v) {
+                    Some(Expr::Literal(v)) => self.code_of(Opcode::Return(Some(v))),
+                    Some(e) => {
+                        let mut v = self.visit_expr(e);
+                        v.push_at(Opcode::ReturnValue, self.line);
+                        v
+                    }
+                    None => self.code_of(Opcode::Return(None))
                 }
             }
+            Expr::Unary(op, rhs) => {
+                let mut v = self.visit_expr(*rhs);
 
-            v.push(Opcode::EndFunc);
-            //v.push(Opcode::StoreName(n));
-            v
-        },
-        Expr::Callee(n, args) => {
-            let mut v = Vec::new();
-            for x in &args {
-                v.push(Opcode::StoreArg(x.to_value()));
+                v.push_at(match op.tok_type {
+                    crate::token::token_type::TokenType::Minus => Opcode::Neg,
+                    crate::token::token_type::TokenType::Not => Opcode::Not,
+                    crate::token::token_type::TokenType::Tilde => Opcode::BitNot,
+                    _ => unimplemented!()
+                }, op.line);
+                v
             }
-            v.push(Opcode::Call(n.ident_to_string()));
-            v
-        },
-        Expr::Return(val_ret) => {
-            if val_ret.is_some() {
-                return vec![Opcode::Return(Some(val_ret.unwrap().to_value()))];
-            }
-
-            vec![Opcode::Return(None)]
-
-            
-        }
-        Expr::Unary(op, rhs) => {
-            let rhs_op = visit_expr(*rhs);
-            let mut v = Vec::from(rhs_op);
-
-            v.push(match op.tok_type {
-                crate::token::token_type::TokenType::Minus => Opcode::Neg,
-                crate::token::token_type::TokenType::Not => Opcode::Not,
-                _ => unimplemented!()
-            });
-            v
+            Expr::Literal(v) => self.code_of(Opcode::Constant(v)),
+            Expr::EnumValue(_, _, d) => self.code_of(Opcode::Constant(Value::Number(d))),
+            // variants are already resolved to constants by the checker
+            Expr::EnumDecl(_, _) => self.code(),
+            Expr::Extern(f) => self.code_of(Opcode::MakeExtern(f)),
+            e => todo!("This expr '{:?}' does not implemented yet.", e)
         }
-        Expr::Literal(v) => vec![Opcode::Constant(v)],
-        e => todo!("This expr '{:?}' does not implemented yet.", e)
-    }
-}
-impl Ast2Ir  {
-    pub fn new(vect: Vec<Expr>) -> Self{
-        Ast2Ir { expr: vect, const_pool: ConstantPool::new() }
     }
 
     pub fn to_ir(&mut self) -> Ir {
         let mut irb = IrBuilder::new();
 
-        for x in self.expr.iter_mut() {
-            irb=irb.append_from_vec(&mut visit_expr(x.clone()));
+        // globals get their index up front, function bodies may use ones declared after them
+        let exprs = self.expr.clone();
+        for n in exprs.iter().filter_map(top_level_decl) {
+            self.global(n);
+        }
+
+        // functions are made first, so top-level code can call any of them
+        let (funcs, rest): (Vec<Expr>, Vec<Expr>) = exprs.into_iter()
+            .partition(|e| matches!(e, Expr::FuncStmt(_, _) | Expr::Extern(_)));
+        for x in funcs.into_iter().chain(rest) {
+            let mut code = self.stmt(x);
+            irb=irb.append_from_vec(&mut code.ops, &code.lines);
         }
         self.const_pool = irb.get_const_pool();
 
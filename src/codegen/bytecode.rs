@@ -0,0 +1,443 @@
+/*
+ * dcz bytecode files (.dczc)
+ *
+ * Compiled IR saved to disk so a script can be run without parsing and
+ * checking it again. Every number is little endian.
+ *
+ *     header          "DCZC", version: u16, flags: u16 (0)
+ *     constant pool   count: u32, values
+ *     function table  count: u32, (name, entry: u32, size: u32, arity: u32)
+ *     code            count: u32, opcodes
+ *     debug lines     count: u32, line: u32 per opcode
+ *
+ * Strings are a u32 byte length followed by UTF-8. Values, data types and
+ * opcodes start with a u8 tag. The function table repeats what the MAKEFUNC
+ * opcodes say, the loader checks that both agree.
+ */
+
+use std::path::Path;
+
+use crate::{token::token_type::TokenType, Value::Value, AST::expr_node::{DataType, Func_Header}};
+
+use super::{ir::Ir, ir_opcode::{ConstantPool, Opcode}};
+
+pub const MAGIC: &[u8; 4] = b"DCZC";
+/// bumped on every incompatible change of the layout or the opcode tags
+pub const VERSION: u16 = 2;
+pub const EXTENSION: &str = "dczc";
+
+/// Operators a BINOP can carry, tagged by position
+const BINOPS: [TokenType; 18] = [
+    TokenType::Plus, TokenType::Minus, TokenType::Star, TokenType::Slash, TokenType::Modulo,
+    TokenType::Caret, TokenType::And, TokenType::Or, TokenType::ShiftLeft, TokenType::ShiftRight,
+    TokenType::EqualEqual, TokenType::NotEqual, TokenType::Less, TokenType::LessEqual,
+    TokenType::Greater, TokenType::GreaterEqual, TokenType::AndBool, TokenType::OrBool
+];
+
+/// Entry of the function table
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncEntry {
+    pub name: String,
+    /// index of the MAKEFUNC opcode
+    pub entry: usize,
+    /// opcodes between MAKEFUNC and END_FUNC
+    pub size: usize,
+    pub arity: usize
+}
+
+/// Functions defined by MAKEFUNC opcodes, in code order
+pub fn functions(code: &[Opcode]) -> Vec<FuncEntry> {
+    code.iter().enumerate().filter_map(|(entry, op)| match op {
+        Opcode::MakeFunc(size, name) => {
+            let arity = code[entry+1..].iter().take(*size).take_while(|o| matches!(o, Opcode::StoreParam(_, _))).count();
+            Some(FuncEntry { name: name.clone(), entry, size: *size, arity })
+        }
+        _ => None
+    }).collect()
+}
+
+struct Writer {
+    buf: Vec<u8>
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.buf.extend(v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: usize) -> Result<(), String> {
+        let v = u32::try_from(v).map_err(|_| format!("{} does not fit the 32 bits of the file format", v))?;
+        self.buf.extend(v.to_le_bytes());
+        Ok(())
+    }
+
+    fn str(&mut self, s: &str) -> Result<(), String> {
+        self.u32(s.len())?;
+        self.buf.extend(s.as_bytes());
+        Ok(())
+    }
+
+    fn value(&mut self, v: &Value) -> Result<(), String> {
+        match v {
+            Value::Null => self.u8(0),
+            Value::Number(n) => { self.u8(1); self.buf.extend(n.to_le_bytes()); }
+            Value::Float(f) => { self.u8(2); self.buf.extend(f.to_le_bytes()); }
+            Value::Double(d) => { self.u8(3); self.buf.extend(d.to_le_bytes()); }
+            Value::Str(s) => { self.u8(4); self.str(s)?; }
+            Value::Object(s) => { self.u8(5); self.str(s)?; }
+            Value::Char(c) => { self.u8(6); self.u32(*c as usize)?; }
+            Value::Boolean(b) => { self.u8(7); self.u8(*b as u8); }
+            Value::List(l) => {
+                self.u8(8);
+                self.u32(l.len())?;
+                for v in l {
+                    self.value(v)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn data_type(&mut self, dt: &DataType) -> Result<(), String> {
+        match dt {
+            DataType::Char => self.u8(0),
+            DataType::Short => self.u8(1),
+            DataType::Int => self.u8(2),
+            DataType::Long => self.u8(3),
+            DataType::Float => self.u8(4),
+            DataType::Suu => self.u8(5),
+            DataType::Bool => self.u8(6),
+            DataType::Void => self.u8(7),
+            DataType::Enum(name) => { self.u8(8); self.str(name)?; }
+            DataType::Unknown => self.u8(9)
+        }
+        Ok(())
+    }
+
+    fn header(&mut self, h: &Func_Header) -> Result<(), String> {
+        self.str(&h.name)?;
+        self.u32(h.args.len())?;
+        for (dt, name, is_p) in &h.args {
+            self.data_type(dt)?;
+            self.str(name)?;
+            self.u8(*is_p as u8);
+        }
+        match &h.return_type {
+            Some(dt) => { self.u8(1); self.data_type(dt)?; }
+            None => self.u8(0)
+        }
+        self.u8(h.is_ptr_dt as u8);
+        Ok(())
+    }
+
+    fn opcode(&mut self, op: &Opcode) -> Result<(), String> {
+        match op {
+            Opcode::Invaild => self.u8(0),
+            Opcode::Return(v) => {
+                self.u8(1);
+                match v {
+                    Some(v) => { self.u8(1); self.value(v)?; }
+                    None => self.u8(0)
+                }
+            }
+            Opcode::ReturnValue => self.u8(2),
+            Opcode::LoadConstant(idx) => { self.u8(3); self.u32(*idx)?; }
+            Opcode::Constant(v) => { self.u8(4); self.value(v)?; }
+            Opcode::Not => self.u8(5),
+            Opcode::Neg => self.u8(6),
+            Opcode::BitNot => self.u8(7),
+            Opcode::StoreGlobal(idx) => { self.u8(8); self.u32(*idx)?; }
+            Opcode::LoadGlobal(idx) => { self.u8(9); self.u32(*idx)?; }
+            Opcode::StoreLocal(idx) => { self.u8(10); self.u32(*idx)?; }
+            Opcode::LoadLocal(idx) => { self.u8(11); self.u32(*idx)?; }
+            Opcode::StoreParam(dt, name) => { self.u8(12); self.data_type(dt)?; self.str(name)?; }
+            Opcode::StoreArg(v) => { self.u8(13); self.value(v)?; }
+            Opcode::End => self.u8(14),
+            Opcode::Begin => self.u8(15),
+            Opcode::BinOp(t) => {
+                self.u8(16);
+                self.u8(BINOPS.iter().position(|b| *b == t.tok_type).expect("BINOP of a binary operator") as u8);
+            }
+            Opcode::Jmp(off) => { self.u8(17); self.u32(*off)?; }
+            Opcode::JBackward(off) => { self.u8(18); self.u32(*off)?; }
+            Opcode::JIfFalse(off) => { self.u8(19); self.u32(*off)?; }
+            Opcode::Switch(table, default) => {
+                self.u8(20);
+                self.u32(table.len())?;
+                for (case, off) in table {
+                    self.buf.extend(case.to_le_bytes());
+                    self.u32(*off)?;
+                }
+                self.u32(*default)?;
+            }
+            Opcode::MakeFunc(size, name) => { self.u8(21); self.u32(*size)?; self.str(name)?; }
+            Opcode::EndFunc => self.u8(22),
+            Opcode::MakeExtern(h) => { self.u8(23); self.header(h)?; }
+            Opcode::Call(name) => { self.u8(24); self.str(name)?; }
+            Opcode::Push(v) => { self.u8(25); self.value(v)?; }
+            Opcode::Pop => self.u8(26),
+            Opcode::Nop => self.u8(27),
+            Opcode::Dup => self.u8(28),
+            Opcode::Index => self.u8(29),
+            Opcode::SetIndex => self.u8(30)
+        }
+        Ok(())
+    }
+}
+
+/// Encode `ir` as the contents of a .dczc file, fails when a length, index or offset needs more than 32 bits
+pub fn serialize(ir: &Ir) -> Result<Vec<u8>, String> {
+    let mut w = Writer { buf: Vec::new() };
+    w.buf.extend(MAGIC);
+    w.u16(VERSION);
+    w.u16(0);
+
+    w.u32(ir.c_pool.len())?;
+    for v in (0..ir.c_pool.len()).filter_map(|idx| ir.c_pool.get(idx)) {
+        w.value(v)?;
+    }
+
+    let funcs = functions(&ir.instr);
+    w.u32(funcs.len())?;
+    for f in &funcs {
+        w.str(&f.name)?;
+        w.u32(f.entry)?;
+        w.u32(f.size)?;
+        w.u32(f.arity)?;
+    }
+
+    w.u32(ir.instr.len())?;
+    for op in &ir.instr {
+        w.opcode(op)?;
+    }
+
+    w.u32(ir.lines.len())?;
+    for l in &ir.lines {
+        w.u32(*l)?;
+    }
+    Ok(w.buf)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| format!("unexpected end of file at byte {}", self.pos))?;
+        let b = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(b)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().expect("slice of N bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(format!("invalid bool {} at byte {}", b, self.pos - 1))
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+
+    /// A count of items that each take at least one byte, checked before anything is allocated
+    fn count(&mut self) -> Result<usize, String> {
+        let n = self.u32()?;
+        if n > self.bytes.len() - self.pos {
+            return Err(format!("count {} at byte {} is larger than the file", n, self.pos - 4));
+        }
+        Ok(n)
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let n = self.u32()?;
+        let at = self.pos;
+        String::from_utf8(self.take(n)?.to_vec()).map_err(|_| format!("invalid UTF-8 string at byte {}", at))
+    }
+
+    fn tag_error(&self, what: &str, tag: u8) -> String {
+        format!("unknown {} tag {} at byte {}", what, tag, self.pos - 1)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        Ok(match self.u8()? {
+            0 => Value::Null,
+            1 => Value::Number(i64::from_le_bytes(self.array()?)),
+            2 => Value::Float(f32::from_le_bytes(self.array()?)),
+            3 => Value::Double(f64::from_le_bytes(self.array()?)),
+            4 => Value::Str(self.str()?),
+            5 => Value::Object(self.str()?),
+            6 => {
+                let c = self.u32()?;
+                Value::Char(char::from_u32(c as u32).ok_or_else(|| format!("invalid char {} at byte {}", c, self.pos - 4))?)
+            }
+            7 => Value::Boolean(self.bool()?),
+            8 => {
+                let n = self.count()?;
+                Value::List((0..n).map(|_| self.value()).collect::<Result<_, _>>()?)
+            }
+            tag => return Err(self.tag_error("value", tag))
+        })
+    }
+
+    fn data_type(&mut self) -> Result<DataType, String> {
+        Ok(match self.u8()? {
+            0 => DataType::Char,
+            1 => DataType::Short,
+            2 => DataType::Int,
+            3 => DataType::Long,
+            4 => DataType::Float,
+            5 => DataType::Suu,
+            6 => DataType::Bool,
+            7 => DataType::Void,
+            8 => DataType::Enum(self.str()?),
+            9 => DataType::Unknown,
+            tag => return Err(self.tag_error("data type", tag))
+        })
+    }
+
+    fn header(&mut self) -> Result<Func_Header, String> {
+        let name = self.str()?;
+        let n = self.count()?;
+        let args = (0..n).map(|_| Ok((self.data_type()?, self.str()?, self.bool()?))).collect::<Result<_, String>>()?;
+        let return_type = if self.bool()? { Some(self.data_type()?) } else { None };
+        Ok(Func_Header { name, args, return_type, is_ptr_dt: self.bool()? })
+    }
+
+    fn opcode(&mut self, line: usize) -> Result<Opcode, String> {
+        Ok(match self.u8()? {
+            0 => Opcode::Invaild,
+            1 => Opcode::Return(if self.bool()? { Some(self.value()?) } else { None }),
+            2 => Opcode::ReturnValue,
+            3 => Opcode::LoadConstant(self.u32()?),
+            4 => Opcode::Constant(self.value()?),
+            5 => Opcode::Not,
+            6 => Opcode::Neg,
+            7 => Opcode::BitNot,
+            8 => Opcode::StoreGlobal(self.u32()?),
+            9 => Opcode::LoadGlobal(self.u32()?),
+            10 => Opcode::StoreLocal(self.u32()?),
+            11 => Opcode::LoadLocal(self.u32()?),
+            12 => Opcode::StoreParam(self.data_type()?, self.str()?),
+            13 => Opcode::StoreArg(self.value()?),
+            14 => Opcode::End,
+            15 => Opcode::Begin,
+            16 => {
+                let b = self.u8()?;
+                let tok_type = BINOPS.get(b as usize).cloned().ok_or_else(|| self.tag_error("operator", b))?;
+                Opcode::binop(tok_type, line)
+            }
+            17 => Opcode::Jmp(self.u32()?),
+            18 => Opcode::JBackward(self.u32()?),
+            19 => Opcode::JIfFalse(self.u32()?),
+            20 => {
+                let n = self.count()?;
+                let table = (0..n).map(|_| Ok((i64::from_le_bytes(self.array()?), self.u32()?))).collect::<Result<_, String>>()?;
+                Opcode::Switch(table, self.u32()?)
+            }
+            21 => Opcode::MakeFunc(self.u32()?, self.str()?),
+            22 => Opcode::EndFunc,
+            23 => Opcode::MakeExtern(self.header()?),
+            24 => Opcode::Call(self.str()?),
+            25 => Opcode::Push(self.value()?),
+            26 => Opcode::Pop,
+            27 => Opcode::Nop,
+            28 => Opcode::Dup,
+            29 => Opcode::Index,
+            30 => Opcode::SetIndex,
+            tag => return Err(self.tag_error("opcode", tag))
+        })
+    }
+}
+
+/// Decode a .dczc file. Malformed files, files of another version and
+/// references out of range (constants, function bodies) are rejected
+pub fn deserialize(bytes: &[u8]) -> Result<Ir, String> {
+    let mut r = Reader { bytes, pos: 0 };
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err("Not a dcz bytecode file".to_string());
+    }
+    r.pos = MAGIC.len();
+    let version = r.u16()?;
+    if version != VERSION {
+        return Err(format!("Bytecode version {} is not supported, expected {}", version, VERSION));
+    }
+    let flags = r.u16()?;
+    if flags != 0 {
+        return Err(format!("Unknown bytecode flags {:#x}", flags));
+    }
+
+    let mut c_pool = ConstantPool::new();
+    for _ in 0..r.count()? {
+        c_pool.append(r.value()?);
+    }
+
+    let n = r.count()?;
+    let funcs = (0..n).map(|_| Ok(FuncEntry { name: r.str()?, entry: r.u32()?, size: r.u32()?, arity: r.u32()? }))
+        .collect::<Result<Vec<_>, String>>()?;
+
+    // the lines come after the code, BINOP tokens get theirs afterwards
+    let n = r.count()?;
+    let mut instr = (0..n).map(|_| r.opcode(0)).collect::<Result<Vec<_>, _>>()?;
+    let n = r.count()?;
+    let lines = (0..n).map(|_| r.u32()).collect::<Result<Vec<_>, _>>()?;
+    if r.pos != bytes.len() {
+        return Err(format!("{} trailing bytes after the debug lines", bytes.len() - r.pos));
+    }
+
+    if !lines.is_empty() && lines.len() != instr.len() {
+        return Err(format!("{} debug lines for {} opcodes", lines.len(), instr.len()));
+    }
+    for (op, line) in instr.iter_mut().zip(&lines) {
+        if let Opcode::BinOp(t) = op {
+            t.line = *line;
+        }
+    }
+
+    for (ip, op) in instr.iter().enumerate() {
+        match op {
+            Opcode::LoadConstant(idx) if *idx >= c_pool.len() => {
+                return Err(format!("LOADCONSTANT at {} refers to constant {} of {}", ip, idx, c_pool.len()));
+            }
+            Opcode::MakeFunc(size, name) if !matches!(instr.get(ip + size + 1), Some(Opcode::EndFunc)) => {
+                return Err(format!("Function '{}' at {} does not end with END_FUNC", name, ip));
+            }
+            _ => {}
+        }
+    }
+    if functions(&instr) != funcs {
+        return Err("Function table does not match the code".to_string());
+    }
+
+    Ok(Ir { instr, lines, c_pool })
+}
+
+pub fn write(path: &Path, ir: &Ir) -> Result<(), String> {
+    let bytes = serialize(ir).map_err(|e| format!("Can't encode '{}': {}", path.display(), e))?;
+    std::fs::write(path, bytes).map_err(|e| format!("Can't write '{}': {}", path.display(), e))
+}
+
+pub fn read(path: &Path) -> Result<Ir, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Can't read '{}': {}", path.display(), e))?;
+    deserialize(&bytes).map_err(|e| format!("Invalid bytecode file '{}': {}", path.display(), e))
+}
@@ -10,7 +10,7 @@ pub struct Codegen{
     pub assign_location: Vec<(String, Value)>, // name, value
     pub func_location: Vec<(String, CodeAssembler)>, // name, opcodes
     pseudo_stack: Vec<Stack>,
-    pseudo_variable_stack: HashMap<String, AsmMemoryOperand>,
+    pseudo_variable_stack: HashMap<usize, AsmMemoryOperand>, // slot, operand
     code_sz: usize,
 }
 
@@ -44,13 +44,6 @@ impl Codegen {
                 Opcode::End => {}
                 Opcode::Nop => instr.nop().expect("NOP"),
                 Opcode::Constant(v) => self.pseudo_stack.push(Stack::Value(v.clone())),
-                Opcode::LoadName(n) => {
-                    if self.func_location.iter().any(|(func_name,_)| func_name == n) {
-                        self.pseudo_stack.push(Stack::Value(Value::Str(n.clone())));
-                    } else {
-                        todo!()
-                    }
-                }
                 Opcode::StoreArg(v) => {
                     // calling convention
                     // Linux CC
@@ -83,14 +76,6 @@ impl Codegen {
                     arg_c+=1
 
                 },
-                Opcode::Agn(n) => {
-                    let (_,p) = self.pseudo_variable_stack.iter().find(|(f,_)| *f == n).unwrap();
-                    let v = self.pseudo_stack.pop().expect("Empty variable").as_value();
-
-
-                    instr.mov(p.clone(),v.to_literal() as i32).expect("AGN (MOV)");
-
-                }
                 Opcode::Call(n) => {
                     let instr_len = instr.assemble(0).expect("INSTR_LEN").len()+9;
                     instr.call(instr_len as u64).expect("CALL");
@@ -156,37 +141,30 @@ impl Codegen {
                 Opcode::Push(v) => {
                     instr.push(v.clone().to_literal() as u32).expect("PUSH");
                 }
-                Opcode::StoreLocal(d,is_p,n) => { //store local
+                Opcode::StoreLocal(idx) => { //store local
                     let value = self.pseudo_stack.pop().unwrap();
+                    if let Some(p) = self.pseudo_variable_stack.get(idx) {
+                        // assignment to a declared slot
+                        instr.mov(*p, value.as_value().to_literal() as i32).expect("MOV(STORELOCAL)");
+                        ip+=1;
+                        continue;
+                    }
+
+                    // slots are untyped, every local gets a qword
+                    let d = DataType::Long;
                     let pad = padding(d.clone(), local_pad_offset);
                     let off = local_pad_offset+pad;
-                    local_pad_offset = off+d.clone().size();
-
-
-                    let d = if !*is_p { d.clone() } else { DataType::Long };
+                    local_pad_offset = off+d.size();
 
-                    let p = match d {
-                        DataType::Int | DataType::Float => {
-                            dword_ptr(rbp-local_pad_offset)
-                        }
-                        DataType::Char => {
-                            byte_ptr(rbp-local_pad_offset)
-                        }
-                        DataType::Short => {
-                            word_ptr(rbp-local_pad_offset)
-                        }
-                        DataType::Long | DataType::Suu => {
-                            qword_ptr(rbp-local_pad_offset)
-                            //qword_ptr(rbp)
-                        }
-                        _ => todo!()
-                    };
+                    let p = qword_ptr(rbp-local_pad_offset);
 
                     let v = value.clone().as_value();
 
 
                     if v.clone().to_any().is::<i64>() {
                         instr.mov(p, (v.to_literal() & 0xffff) as i32).expect("MOV(STORELOCAL)");
+                    } else if v.clone().to_any().is::<bool>() {
+                        instr.mov(p, v.to_bool() as i32).expect("MOV(STORELOCAL) - BOOL");
                     } else if v.clone().to_any().is::<f32>() {
                         instr.movss(xmm0, dword_ptr(0)).expect("MOVSS(STORELOCAL) - FLOAT");
                         instr.movss(p, xmm0).expect("MOVSS(STORELOCAL) - FLOAT");
@@ -194,11 +172,11 @@ impl Codegen {
                         instr.mov(p, v.to_char() as i32).expect("MOV(STORELOCAL)");
                     }
 
-                    self.pseudo_variable_stack.insert(n.clone(), p);
+                    self.pseudo_variable_stack.insert(*idx, p);
                 }
                 Opcode::EndFunc => {
                 }
-                Opcode::StoreGlobal(_d,_is_p,_n) => {
+                Opcode::StoreGlobal(_idx) => {
                     //let value = self.pseudo_stack.pop().unwrap();
                     //self.assign_location.push((n.clone(),value.clone().as_value()));
                     //instr.mov(dword_ptr(0), value.as_value().to_literal() as u32).expect("MOV(STORENAME)");
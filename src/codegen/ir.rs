@@ -2,31 +2,34 @@
 
 
 use crate::Value::Value;
-use super::ir_opcode::*;
+use super::{ir_opcode::*, ir_text::disassemble};
 
 #[derive(Debug)]
 pub struct Ir {
-    pub instr: Vec<Opcode>
+    pub instr: Vec<Opcode>,
+    /// source line of each opcode, 0 when unknown
+    pub lines: Vec<usize>,
+    /// strings loaded with LOADCONSTANT
+    pub c_pool: ConstantPool
 }
 
 impl std::fmt::Display for Ir {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut s =self.instr.iter().map(|opcode| format!("{}\n",opcode.to_string())).collect::<String>();
-        s.pop();
-        write!(f, "{}", s)
+        write!(f, "{}", disassemble(self))
     }
 }
 
 #[derive(Clone)]
 pub struct IrBuilder {
     instr: Vec<Opcode>,
+    lines: Vec<usize>,
     c_pool: ConstantPool,
 }
 
 
 impl IrBuilder {
     pub fn new() -> Self {
-        Self { instr: Vec::new(), c_pool: ConstantPool::new() }
+        Self { instr: Vec::new(), lines: Vec::new(), c_pool: ConstantPool::new() }
     }
 
     pub fn get_const_pool(&self) -> ConstantPool {
@@ -34,7 +37,9 @@ impl IrBuilder {
     }
 
 
-    pub fn append_from_vec(mut self, v: &mut Vec<Opcode>) -> Self {
+    pub fn append_from_vec(mut self, v: &mut Vec<Opcode>, lines: &[usize]) -> Self {
+        // a short line table leaves the rest of the opcodes unknown
+        self.lines.extend((0..v.len()).map(|idx| lines.get(idx).copied().unwrap_or(0)));
         for x in v {
             match x {
                 Opcode::Constant(v)=> {
@@ -56,6 +61,6 @@ impl IrBuilder {
 
 
     pub fn build(self) -> Ir {
-        Ir { instr: self.instr }
+        Ir { instr: self.instr, lines: self.lines, c_pool: self.c_pool }
     }
 }
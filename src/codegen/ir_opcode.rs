@@ -1,7 +1,9 @@
 #![allow(dead_code)]
 
 use std::fmt::{Debug, Display};
-use crate::{token::TokenData, Value::Value, AST::expr_node::DataType};
+use crate::{token::{token_type::TokenType, TokenData}, Value::Value, AST::expr_node::{DataType, Func_Header}};
+
+use super::ir_text::{binop_symbol, data_type_text, header_text, value_text};
 
 #[derive(Clone)]
 pub enum Opcode {
@@ -9,6 +11,8 @@ pub enum Opcode {
     Invaild,
     /// RET
     Return(Option<Value>),
+    /// RET_VALUE, return the value on top of the stack
+    ReturnValue,
     /// LOADCONSTANT(idx)
     LoadConstant(usize),
     ///CONSTANT
@@ -17,12 +21,16 @@ pub enum Opcode {
     Not,
     /// NEG
     Neg,
-    /// STORE_GLOBAL
-    StoreGlobal(DataType,bool, String),
-    /// LOAD_NAME
-    LoadName(String),
-    /// STORE_LOCAL
-    StoreLocal(DataType, bool,String),
+    /// BITNOT, two's complement `~`
+    BitNot,
+    /// STORE_GLOBAL(idx)
+    StoreGlobal(usize),
+    /// LOAD_GLOBAL(idx)
+    LoadGlobal(usize),
+    /// STORE_LOCAL(slot), slots are relative to the frame
+    StoreLocal(usize),
+    /// LOAD_LOCAL(slot)
+    LoadLocal(usize),
     /// STORE_Param
     StoreParam(DataType, String),
     /// STORE_ARG
@@ -39,18 +47,26 @@ pub enum Opcode {
     JBackward(usize),
     /// JIFFALSE(offset)
     JIfFalse(usize),
-    ///AGN(name)
-    Agn(String),
+    /// SWITCH(jump table [(case, offset)], default offset)
+    Switch(Vec<(i64, usize)>, usize),
     /// MAKEFUNC(Size, Name)
     MakeFunc(usize,String),
     ///END_FUNC
     EndFunc,
+    /// MAKE_EXTERN(header), bind a native C function
+    MakeExtern(Func_Header),
     /// CALL(name)
     Call(String),
     /// PUSH(Value)
     Push(Value),
     /// POP
     Pop,
+    /// DUP, push the top of the stack again
+    Dup,
+    /// INDEX, pops the index and the pointer, pushes the element
+    Index,
+    /// SET_INDEX, pops the index, the pointer and the value, stores the value through the pointer
+    SetIndex,
     /// NOP
     Nop,
 }
@@ -59,52 +75,33 @@ impl Debug for Opcode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Opcode::Return(v) => write!(f,"[RET {:?}]", v.clone()),
+            Opcode::ReturnValue => write!(f, "[RET_VALUE]"),
             Opcode::LoadConstant(idx) => write!(f, "[LOADCONSTANT (idx: {})]", idx),
-            Opcode::Constant(v) => {
-                /*
-                let mut value = String::new();
-                if v.value().unwrap().is::<i64>() {
-                    value = v.clone().to_literal().to_string();
-                } else if v.value().unwrap().is::<String>() {
-                    value = v.clone().to_string();
-                } else if v.value().unwrap().is::<char>() {
-                    value = v.clone().to_char().to_string();
-                }
-                else {
-                    value = v.clone().to_float().to_string();
-                }*/
-                let any = v.clone().to_any();
-                let value=
-                    if any.is::<i64>() {
-                        v.clone().to_literal().to_string()
-                    } else if any.is::<String>() {
-                        v.clone().to_string()
-                    } else if any.is::<char>() {
-                        v.clone().to_char().to_string()
-                    } else {
-                        v.clone().to_float().to_string()
-                    };
-
-                write!(f, "[CONSTANT (v: {})]", value)
-            }
+            Opcode::Constant(v) => write!(f, "[CONSTANT (v: {})]", value_text(v)),
             Opcode::Not => write!(f, "[NOT (rhs)]"),
             Opcode::Neg => write!(f, "[NEG (rhs)]"),
+            Opcode::BitNot => write!(f, "[BITNOT (rhs)]"),
             Opcode::Nop => write!(f,"[NOP]"),
-            Opcode::LoadName(n) => write!(f, "[LOAD_NAME ({})]", n),
-            Opcode::StoreGlobal(d,is_p,s) => write!(f, "[STORE_GLOBAL ({:?}(ptr: {}) {})]", d,is_p,s),
-            Opcode::StoreLocal(d,is_p,s) => write!(f, "[STORE_LOCAL ({:?}(ptr: {}) {})]", d,is_p,s),
+            Opcode::StoreGlobal(idx) => write!(f, "[STORE_GLOBAL ({})]", idx),
+            Opcode::LoadGlobal(idx) => write!(f, "[LOAD_GLOBAL ({})]", idx),
+            Opcode::StoreLocal(idx) => write!(f, "[STORE_LOCAL ({})]", idx),
+            Opcode::LoadLocal(idx) => write!(f, "[LOAD_LOCAL ({})]", idx),
             Opcode::End => write!(f, "[END]"),
             Opcode::Begin => write!(f, "[BEGIN]"),
             Opcode::Jmp(offset) => write!(f, "[JMP ({})]", offset),
             Opcode::JIfFalse(offset) => write!(f, "[JIFFALSE ({})]", offset),
             Opcode::JBackward(offset) => write!(f, "[JBackward ({})]", offset),
+            Opcode::Switch(table, default) => write!(f, "[SWITCH ({:?} default: {})]", table, default),
             Opcode::BinOp(tt) => write!(f, "[BINOP (lhs {:?} rhs)]", tt.tok_type),
-            Opcode::Agn(n) => write!(f, "[AGN ({})]", n),
             Opcode::MakeFunc(sz,name) => write!(f, "[MAKEFUNC {}({})]", name,sz),
             Opcode::Push(v) => write!(f, "[PUSH ({})]", v.clone().to_literal()),
             Opcode::Pop => write!(f, "[POP]"),
+            Opcode::Dup => write!(f, "[DUP]"),
+            Opcode::Index => write!(f, "[INDEX (ptr idx)]"),
+            Opcode::SetIndex => write!(f, "[SET_INDEX (v ptr idx)]"),
             Opcode::Call(n) => write!(f, "[CALL ({})]", n.clone()),
             Opcode::EndFunc => write!(f, "[END_FUNC]"),
+            Opcode::MakeExtern(h) => write!(f, "[MAKE_EXTERN {}({})]", h.name, h.args.len()),
             Opcode::StoreParam(d, n) => write!(f, "[STORE_PARAM ({:?} {})]", d,n.to_string()),
             Opcode::StoreArg(v) => write!(f, "[STORE_ARG ({:?})]", v),
             Opcode::Invaild => write!(f, "[INVAILD]")
@@ -112,12 +109,52 @@ impl Debug for Opcode {
     }
 }
 
+impl Opcode {
+    /// BINOP of an operator without its source token, e.g. read back from a file
+    pub fn binop(tok_type: TokenType, line: usize) -> Self {
+        Opcode::BinOp(TokenData { tok_type, start: 0, end: 0, line, col: 0, identifier: String::new(), value: Value::Null, sub_tok: None })
+    }
+}
+
+/// One line of textual IR, jumps show their raw offsets
 impl Display for Opcode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Opcode::Return(v) => write!(f, "ret {:?}", v),
-            Opcode::Nop => write!(f, "nop"),
-            _ => todo!()
+            Opcode::Invaild => write!(f, "invalid"),
+            Opcode::Return(None) => write!(f, "ret"),
+            Opcode::Return(Some(v)) => write!(f, "ret {}", value_text(v)),
+            Opcode::ReturnValue => write!(f, "ret_value"),
+            Opcode::LoadConstant(idx) => write!(f, "load_const {}", idx),
+            Opcode::Constant(v) => write!(f, "const {}", value_text(v)),
+            Opcode::Not => write!(f, "not"),
+            Opcode::Neg => write!(f, "neg"),
+            Opcode::BitNot => write!(f, "bitnot"),
+            Opcode::StoreGlobal(idx) => write!(f, "store_global {}", idx),
+            Opcode::LoadGlobal(idx) => write!(f, "load_global {}", idx),
+            Opcode::StoreLocal(idx) => write!(f, "store_local {}", idx),
+            Opcode::LoadLocal(idx) => write!(f, "load_local {}", idx),
+            Opcode::StoreParam(d, n) => write!(f, "store_param {} {}", data_type_text(d), n),
+            Opcode::StoreArg(v) => write!(f, "store_arg {}", value_text(v)),
+            Opcode::End => write!(f, "end"),
+            Opcode::Begin => write!(f, "begin"),
+            Opcode::BinOp(t) => write!(f, "binop {}", binop_symbol(&t.tok_type)),
+            Opcode::Jmp(off) => write!(f, "jmp {}", off),
+            Opcode::JBackward(off) => write!(f, "jbackward {}", off),
+            Opcode::JIfFalse(off) => write!(f, "jiffalse {}", off),
+            Opcode::Switch(table, default) => {
+                let cases = table.iter().map(|(case, off)| format!("{} => {}", case, off)).collect::<Vec<_>>();
+                write!(f, "switch [{}] default => {}", cases.join(", "), default)
+            }
+            Opcode::MakeFunc(sz, name) => write!(f, "make_func {} {}", name, sz),
+            Opcode::EndFunc => write!(f, "end_func"),
+            Opcode::MakeExtern(h) => write!(f, "make_extern {}", header_text(h)),
+            Opcode::Call(n) => write!(f, "call {}", n),
+            Opcode::Push(v) => write!(f, "push {}", value_text(v)),
+            Opcode::Pop => write!(f, "pop"),
+            Opcode::Dup => write!(f, "dup"),
+            Opcode::Index => write!(f, "index"),
+            Opcode::SetIndex => write!(f, "set_index"),
+            Opcode::Nop => write!(f, "nop")
         }
     }
 }
@@ -0,0 +1,487 @@
+/*
+ * Textual dcz IR
+ *
+ * `disassemble` prints an `Ir` for people, `parse` reads the same text back
+ * so IR can be written by hand, e.g. in tests:
+ *
+ *     .const 0 "hi"
+ *
+ *     0000 @1   make_func main 7
+ *     0001 @2       load_const 0
+ *     0002 @2       call println
+ *     0003 @2       pop
+ *     0004 @3       const true
+ *     0005 @3       jiffalse L0
+ *     0006 @3       ret 1
+ *     L0:
+ *     0007 @4       ret 0
+ *     0008 @4   end_func
+ *
+ * The offset and the `@line` in front of an opcode are optional when parsing,
+ * so is the size of `make_func`, it is taken from the matching `end_func`.
+ * Jumps go to labels or take the raw offset of the opcode. `;` starts a
+ * comment.
+ */
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{token::token_type::TokenType, Value::Value, AST::expr_node::{DataType, Func_Header}};
+
+use super::{ir::Ir, ir_opcode::{ConstantPool, Opcode}};
+
+const BINOPS: [(TokenType, &str); 18] = [
+    (TokenType::Plus, "+"), (TokenType::Minus, "-"), (TokenType::Star, "*"), (TokenType::Slash, "/"),
+    (TokenType::Modulo, "%"), (TokenType::Caret, "^"), (TokenType::And, "&"), (TokenType::Or, "|"),
+    (TokenType::ShiftLeft, "<<"), (TokenType::ShiftRight, ">>"), (TokenType::EqualEqual, "=="),
+    (TokenType::NotEqual, "!="), (TokenType::Less, "<"), (TokenType::LessEqual, "<="),
+    (TokenType::Greater, ">"), (TokenType::GreaterEqual, ">="), (TokenType::AndBool, "&&"), (TokenType::OrBool, "||")
+];
+
+pub fn binop_symbol(t: &TokenType) -> String {
+    BINOPS.iter().find(|(b, _)| b == t).map_or_else(|| format!("{:?}", t), |(_, s)| s.to_string())
+}
+
+/// A value as IR text, floats carry an `f`, suus always a `.` or an exponent
+pub fn value_text(v: &Value) -> String {
+    match v {
+        Value::Null => "null".to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Float(f) => format!("{:?}f", f),
+        Value::Double(d) => format!("{:?}", d),
+        Value::Str(s) => format!("{:?}", s),
+        Value::Object(s) => format!("object {:?}", s),
+        Value::Char(c) => format!("{:?}", c),
+        Value::Boolean(b) => b.to_string(),
+        Value::List(l) => format!("[{}]", l.iter().map(value_text).collect::<Vec<_>>().join(", "))
+    }
+}
+
+pub fn data_type_text(dt: &DataType) -> String {
+    match dt {
+        DataType::Char => "char".to_string(),
+        DataType::Short => "short".to_string(),
+        DataType::Int => "int".to_string(),
+        DataType::Long => "long".to_string(),
+        DataType::Float => "float".to_string(),
+        DataType::Suu => "suu".to_string(),
+        DataType::Bool => "bool".to_string(),
+        DataType::Void => "void".to_string(),
+        DataType::Enum(name) => format!("enum {}", name),
+        DataType::Unknown => "unknown".to_string()
+    }
+}
+
+/// `name(int a, char* s) -> int`
+pub fn header_text(h: &Func_Header) -> String {
+    let ptr = |p: bool| if p { "*" } else { "" };
+    let args = h.args.iter().map(|(dt, n, p)| format!("{}{} {}", data_type_text(dt), ptr(*p), n)).collect::<Vec<_>>();
+    match &h.return_type {
+        Some(dt) => format!("{}({}) -> {}{}", h.name, args.join(", "), data_type_text(dt), ptr(h.is_ptr_dt)),
+        None => format!("{}({})", h.name, args.join(", "))
+    }
+}
+
+/// Where the jumps of the opcode at `ip` go, `None` for a target before the start
+fn targets(op: &Opcode, ip: usize) -> Vec<Option<usize>> {
+    match op {
+        Opcode::Jmp(off) => vec![ip.checked_add(*off)],
+        Opcode::JIfFalse(off) => vec![ip.checked_add(off + 1)],
+        Opcode::JBackward(off) => vec![(ip + 1).checked_sub(*off)],
+        Opcode::Switch(table, default) => table.iter().map(|(_, off)| off)
+            .chain(std::iter::once(default)).map(|off| ip.checked_add(off + 1)).collect(),
+        _ => Vec::new()
+    }
+}
+
+/// Full listing of `ir`: constant pool, then every opcode with its offset and
+/// source line, functions indented and jump targets as labels
+pub fn disassemble(ir: &Ir) -> String {
+    let mut out = String::new();
+    for idx in 0..ir.c_pool.len() {
+        if let Some(v) = ir.c_pool.get(idx) {
+            out += &format!(".const {} {}\n", idx, value_text(v));
+        }
+    }
+
+    let labels: HashMap<usize, usize> = ir.instr.iter().enumerate()
+        .flat_map(|(ip, op)| targets(op, ip)).flatten()
+        .collect::<BTreeSet<_>>().into_iter().enumerate().map(|(n, ip)| (ip, n)).collect();
+    let target = |t: Option<usize>, raw: usize| match t.and_then(|t| labels.get(&t)) {
+        Some(n) => format!("L{}", n),
+        None => raw.to_string()
+    };
+
+    let mut depth = 0;
+    for (ip, op) in ir.instr.iter().enumerate() {
+        if let Some(n) = labels.get(&ip) {
+            out += &format!("L{}:\n", n);
+        }
+        let text = match op {
+            Opcode::Jmp(off) => format!("jmp {}", target(targets(op, ip)[0], *off)),
+            Opcode::JIfFalse(off) => format!("jiffalse {}", target(targets(op, ip)[0], *off)),
+            Opcode::JBackward(off) => format!("jbackward {}", target(targets(op, ip)[0], *off)),
+            Opcode::Switch(table, default) => {
+                let t = targets(op, ip);
+                let cases = table.iter().zip(&t).map(|((case, off), t)| format!("{} => {}", case, target(*t, *off)));
+                format!("switch [{}] default => {}", cases.collect::<Vec<_>>().join(", "), target(t[table.len()], *default))
+            }
+            op => op.to_string()
+        };
+        if matches!(op, Opcode::MakeFunc(_, _)) {
+            if ip > 0 {
+                out.push('\n');
+            }
+        } else if matches!(op, Opcode::EndFunc) {
+            depth = usize::saturating_sub(depth, 1);
+        }
+        let line = match ir.lines.get(ip) {
+            Some(l) if *l != 0 => format!("@{}", l),
+            _ => String::new()
+        };
+        out += &format!("{:04} {:<5}{}{}\n", ip, line, "    ".repeat(depth), text);
+        if matches!(op, Opcode::MakeFunc(_, _)) {
+            depth += 1;
+        }
+    }
+    if let Some(n) = labels.get(&ir.instr.len()) {
+        out += &format!("L{}:\n", n);
+    }
+    out
+}
+
+/// Cursor over one line of IR text
+struct Line<'a> {
+    s: &'a str,
+    pos: usize
+}
+
+impl<'a> Line<'a> {
+    fn rest(&self) -> &'a str {
+        &self.s[self.pos..]
+    }
+
+    fn skip_ws(&mut self) {
+        self.pos = self.s.len() - self.rest().trim_start().len();
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_ws();
+        self.rest().is_empty()
+    }
+
+    fn eat(&mut self, p: &str) -> bool {
+        self.skip_ws();
+        if self.rest().starts_with(p) {
+            self.pos += p.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, p: &str) -> Result<(), String> {
+        if self.eat(p) { Ok(()) } else { Err(format!("expected '{}' at '{}'", p, self.rest())) }
+    }
+
+    /// Run of characters up to whitespace or punctuation
+    fn atom(&mut self) -> Result<&'a str, String> {
+        self.skip_ws();
+        let rest = self.rest();
+        let len = rest.find(|c: char| c.is_whitespace() || ",()[]\"'".contains(c)).unwrap_or(rest.len());
+        if len == 0 {
+            return Err(format!("expected a word at '{}'", rest));
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    fn number(&mut self) -> Result<usize, String> {
+        let a = self.atom()?;
+        a.parse().map_err(|_| format!("expected a number, got '{}'", a))
+    }
+
+    /// A quoted string or char with Rust escapes, the quote is the first character
+    fn quoted(&mut self) -> Result<String, String> {
+        self.skip_ws();
+        let rest = self.rest();
+        let quote = rest.chars().next().ok_or("expected a quote")?;
+        let mut out = String::new();
+        let mut chars = rest.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    let (_, e) = chars.next().ok_or("unfinished escape")?;
+                    out.push(match e {
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        '0' => '\0',
+                        '\\' | '"' | '\'' => e,
+                        'u' => {
+                            let hex: String = chars.by_ref().map(|(_, c)| c).skip(1).take_while(|c| *c != '}').collect();
+                            u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32).ok_or_else(|| format!("invalid escape \\u{{{}}}", hex))?
+                        }
+                        e => return Err(format!("unknown escape \\{}", e))
+                    });
+                }
+                c if c == quote => {
+                    self.pos += i + 1;
+                    return Ok(out);
+                }
+                c => out.push(c)
+            }
+        }
+        Err(format!("unterminated {}", rest))
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_ws();
+        if self.rest().starts_with('"') {
+            return Ok(Value::Str(self.quoted()?));
+        }
+        if self.rest().starts_with('\'') {
+            let s = self.quoted()?;
+            let mut chars = s.chars();
+            return match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(Value::Char(c)),
+                _ => Err(format!("'{}' is not one char", s))
+            };
+        }
+        if self.eat("[") {
+            let mut list = Vec::new();
+            while !self.eat("]") {
+                if !list.is_empty() {
+                    self.expect(",")?;
+                }
+                list.push(self.value()?);
+            }
+            return Ok(Value::List(list));
+        }
+        let a = self.atom()?;
+        Ok(match a {
+            "null" => Value::Null,
+            "true" => Value::Boolean(true),
+            "false" => Value::Boolean(false),
+            "object" => Value::Object(self.quoted()?),
+            a => {
+                if let Ok(n) = a.parse::<i64>() {
+                    Value::Number(n)
+                } else if let Some(f) = a.strip_suffix('f').filter(|_| !a.ends_with("inf")).and_then(|f| f.parse::<f32>().ok()) {
+                    Value::Float(f)
+                } else {
+                    Value::Double(a.parse().map_err(|_| format!("expected a value, got '{}'", a))?)
+                }
+            }
+        })
+    }
+
+    /// A data type and whether it is a pointer
+    fn data_type(&mut self) -> Result<(DataType, bool), String> {
+        let a = self.atom()?;
+        let (name, is_p) = match a.strip_suffix('*') {
+            Some(name) => (name, true),
+            None => (a, false)
+        };
+        let dt = match name {
+            "char" => DataType::Char,
+            "short" => DataType::Short,
+            "int" => DataType::Int,
+            "long" => DataType::Long,
+            "float" => DataType::Float,
+            "suu" => DataType::Suu,
+            "bool" => DataType::Bool,
+            "void" => DataType::Void,
+            "unknown" => DataType::Unknown,
+            "enum" => {
+                let a = self.atom()?;
+                return Ok(match a.strip_suffix('*') {
+                    Some(name) => (DataType::Enum(name.to_string()), true),
+                    None => (DataType::Enum(a.to_string()), false)
+                });
+            }
+            t => return Err(format!("unknown data type '{}'", t))
+        };
+        Ok((dt, is_p))
+    }
+
+    fn header(&mut self) -> Result<Func_Header, String> {
+        let name = self.atom()?.to_string();
+        self.expect("(")?;
+        let mut args = Vec::new();
+        while !self.eat(")") {
+            if !args.is_empty() {
+                self.expect(",")?;
+            }
+            let (dt, is_p) = self.data_type()?;
+            args.push((dt, self.atom()?.to_string(), is_p));
+        }
+        let (return_type, is_ptr_dt) = if self.eat("->") {
+            let (dt, is_p) = self.data_type()?;
+            (Some(dt), is_p)
+        } else {
+            (None, false)
+        };
+        Ok(Func_Header { name, args, return_type, is_ptr_dt })
+    }
+
+    /// Offset of a jump at `ip`, from a label or given as a number
+    fn jump(&mut self, labels: &HashMap<String, usize>, to_offset: impl Fn(usize) -> Option<usize>) -> Result<usize, String> {
+        let a = self.atom()?;
+        if let Ok(off) = a.parse() {
+            return Ok(off);
+        }
+        let t = *labels.get(a).ok_or_else(|| format!("unknown label '{}'", a))?;
+        to_offset(t).ok_or_else(|| format!("label '{}' can not be reached from here", a))
+    }
+
+    fn opcode(&mut self, ip: usize, labels: &HashMap<String, usize>) -> Result<Opcode, String> {
+        let forward = |skip: usize| move |t: usize| t.checked_sub(ip + skip);
+        let mnemonic = self.atom()?;
+        Ok(match mnemonic {
+            "invalid" => Opcode::Invaild,
+            "ret" => Opcode::Return(if self.at_end() { None } else { Some(self.value()?) }),
+            "ret_value" => Opcode::ReturnValue,
+            "load_const" => Opcode::LoadConstant(self.number()?),
+            "const" => Opcode::Constant(self.value()?),
+            "not" => Opcode::Not,
+            "neg" => Opcode::Neg,
+            "bitnot" => Opcode::BitNot,
+            "store_global" => Opcode::StoreGlobal(self.number()?),
+            "load_global" => Opcode::LoadGlobal(self.number()?),
+            "store_local" => Opcode::StoreLocal(self.number()?),
+            "load_local" => Opcode::LoadLocal(self.number()?),
+            "store_param" => Opcode::StoreParam(self.data_type()?.0, self.atom()?.to_string()),
+            "store_arg" => Opcode::StoreArg(self.value()?),
+            "end" => Opcode::End,
+            "begin" => Opcode::Begin,
+            "binop" => {
+                let a = self.atom()?;
+                let (t, _) = BINOPS.iter().find(|(_, s)| *s == a).ok_or_else(|| format!("unknown operator '{}'", a))?;
+                Opcode::binop(t.clone(), 0)
+            }
+            "jmp" => Opcode::Jmp(self.jump(labels, forward(0))?),
+            "jiffalse" => Opcode::JIfFalse(self.jump(labels, forward(1))?),
+            "jbackward" => Opcode::JBackward(self.jump(labels, |t| (ip + 1).checked_sub(t))?),
+            "switch" => {
+                self.expect("[")?;
+                let mut table = Vec::new();
+                while !self.eat("]") {
+                    if !table.is_empty() {
+                        self.expect(",")?;
+                    }
+                    let case = self.atom()?;
+                    let case = case.parse().map_err(|_| format!("expected a case number, got '{}'", case))?;
+                    self.expect("=>")?;
+                    table.push((case, self.jump(labels, forward(1))?));
+                }
+                self.expect("default")?;
+                self.expect("=>")?;
+                Opcode::Switch(table, self.jump(labels, forward(1))?)
+            }
+            "make_func" => {
+                let name = self.atom()?.to_string();
+                // sized when its end_func is found
+                Opcode::MakeFunc(if self.at_end() { usize::MAX } else { self.number()? }, name)
+            }
+            "end_func" => Opcode::EndFunc,
+            "make_extern" => Opcode::MakeExtern(self.header()?),
+            "call" => Opcode::Call(self.atom()?.to_string()),
+            "push" => Opcode::Push(self.value()?),
+            "pop" => Opcode::Pop,
+            "dup" => Opcode::Dup,
+            "index" => Opcode::Index,
+            "set_index" => Opcode::SetIndex,
+            "nop" => Opcode::Nop,
+            m => return Err(format!("unknown opcode '{}'", m))
+        })
+    }
+}
+
+/// `;` outside of quotes starts a comment
+fn strip_comment(l: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in l.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, ';') => return l[..i].trim(),
+            _ => {}
+        }
+    }
+    l.trim()
+}
+
+/// Read IR text as printed by `disassemble`
+pub fn parse(text: &str) -> Result<Ir, String> {
+    let lines: Vec<(usize, &str)> = text.lines().enumerate().map(|(n, l)| (n + 1, strip_comment(l))).filter(|(_, l)| !l.is_empty()).collect();
+
+    // labels first, jumps may go forward
+    let mut labels = HashMap::new();
+    let mut count = 0;
+    for (n, l) in &lines {
+        if let Some(label) = l.strip_suffix(':') {
+            if labels.insert(label.to_string(), count).is_some() {
+                return Err(format!("line {}: label '{}' defined twice", n, label));
+            }
+        } else if !l.starts_with(".const") {
+            count += 1;
+        }
+    }
+
+    let mut c_pool = ConstantPool::new();
+    let mut instr = Vec::new();
+    let mut ir_lines = Vec::new();
+    let mut funcs = Vec::new();
+    for (n, l) in lines {
+        let err = |e: String| format!("line {}: {}", n, e);
+        let mut line = Line { s: l, pos: 0 };
+        if l.ends_with(':') {
+            continue;
+        }
+        if line.eat(".const") {
+            let idx = line.number().map_err(err)?;
+            if idx != c_pool.len() {
+                return Err(err(format!("constant {} follows constant {}", idx, c_pool.len() as isize - 1)));
+            }
+            c_pool.append(line.value().map_err(err)?);
+        } else {
+            // optional offset and line
+            let before = line.pos;
+            if line.atom().ok().is_none_or(|a| a.parse::<usize>().is_err()) {
+                line.pos = before;
+            }
+            let mut source_line = 0;
+            if line.eat("@") {
+                source_line = line.number().map_err(err)?;
+            }
+            let ip = instr.len();
+            let mut op = line.opcode(ip, &labels).map_err(err)?;
+            if !line.at_end() {
+                return Err(err(format!("unexpected '{}'", line.rest())));
+            }
+            match &mut op {
+                Opcode::BinOp(t) => t.line = source_line,
+                Opcode::MakeFunc(_, _) => funcs.push(ip),
+                Opcode::EndFunc => {
+                    let start = funcs.pop().ok_or_else(|| err("end_func without make_func".to_string()))?;
+                    let Opcode::MakeFunc(size, name) = &mut instr[start] else { unreachable!() };
+                    if *size == usize::MAX {
+                        *size = ip - start - 1;
+                    } else if *size != ip - start - 1 {
+                        return Err(err(format!("function '{}' has {} opcodes, make_func says {}", name, ip - start - 1, size)));
+                    }
+                }
+                _ => {}
+            }
+            instr.push(op);
+            ir_lines.push(source_line);
+        }
+    }
+    if let Some(start) = funcs.pop() {
+        return Err(format!("make_func at {} has no end_func", start));
+    }
+    Ok(Ir { instr, lines: ir_lines, c_pool })
+}
@@ -3,7 +3,7 @@
 use std::{ffi::{CStr, CString}, marker::PhantomData, ops::Deref};
 
 use llvm_sys_201::{
-    analysis::LLVMVerifyFunction, core::*, prelude::*, target::LLVMSetModuleDataLayout, target_machine::LLVMOpaqueTargetMachine, LLVMTypeKind, LLVMValueKind
+    analysis::LLVMVerifyFunction, core::*, prelude::*, target::LLVMSetModuleDataLayout, target_machine::LLVMOpaqueTargetMachine, LLVMIntPredicate, LLVMLinkage, LLVMOpcode, LLVMRealPredicate, LLVMTypeKind, LLVMUnnamedAddr, LLVMValueKind
 };
 
 pub struct Module {
@@ -64,6 +64,15 @@ impl<'llvm> Module {
             LLVMDumpModule(self.module);
         }
     }
+    /// Textual LLVM IR of the module, what `dump` prints
+    pub fn print_to_string(&self) -> String {
+        unsafe {
+            let s = LLVMPrintModuleToString(self.module);
+            let ir = CStr::from_ptr(s).to_string_lossy().into_owned();
+            LLVMDisposeMessage(s);
+            ir
+        }
+    }
     pub fn type_u64(&self) -> Type<'llvm> {
         let t_ref = unsafe {
             LLVMIntTypeInContext(self.ctx, 64)
@@ -77,6 +86,12 @@ impl<'llvm> Module {
         };
         Type::new(t_ref)
     }
+    pub fn type_bool(&self) -> Type<'llvm> {
+        let t_ref = unsafe {
+            LLVMInt1TypeInContext(self.ctx)
+        };
+        Type::new(t_ref)
+    }
     pub fn type_i64(&self) -> Type<'llvm> {
         self.type_u64()
     }
@@ -94,6 +109,27 @@ impl<'llvm> Module {
         Type::new(t_ref)
     }
 
+    pub fn type_i16(&self) -> Type<'llvm> {
+        let t_ref = unsafe {
+            LLVMInt16TypeInContext(self.ctx)
+        };
+        Type::new(t_ref)
+    }
+
+    pub fn type_float(&self) -> Type<'llvm> {
+        let t_ref = unsafe {
+            LLVMFloatTypeInContext(self.ctx)
+        };
+        Type::new(t_ref)
+    }
+
+    pub fn type_double(&self) -> Type<'llvm> {
+        let t_ref = unsafe {
+            LLVMDoubleTypeInContext(self.ctx)
+        };
+        Type::new(t_ref)
+    }
+
     pub fn add_fn(&'llvm self, name: &str, fn_type: Type<'llvm>) -> FnValue<'llvm> {
         debug_assert_eq!(
             fn_type.kind(),
@@ -111,6 +147,28 @@ impl<'llvm> Module {
         FnValue::new(value_ref)
     }
 
+    pub fn add_global(&'llvm self, ty: Type<'llvm>, name: &str) -> LlvmValue<'llvm> {
+        let c_string = CString::new(name).expect("cstring failed");
+
+        let value_ref = unsafe {
+            LLVMAddGlobal(self.module, ty.0, c_string.as_ptr())
+        };
+        LlvmValue::new(value_ref)
+    }
+
+    /// Private constant string global, usable as a constant initializer (unlike `Builder::global_string`)
+    pub fn const_string(&'llvm self, raw_str: &str) -> LlvmValue<'llvm> {
+        unsafe {
+            let c = LLVMConstStringInContext2(self.ctx, raw_str.as_ptr().cast(), raw_str.len(), 0);
+            let g = LLVMAddGlobal(self.module, LLVMTypeOf(c), b"str\0".as_ptr().cast());
+            LLVMSetInitializer(g, c);
+            LLVMSetGlobalConstant(g, 1);
+            LLVMSetLinkage(g, LLVMLinkage::LLVMPrivateLinkage);
+            LLVMSetUnnamedAddress(g, LLVMUnnamedAddr::LLVMGlobalUnnamedAddr);
+            LlvmValue::new(LLVMConstPointerCast(g, LLVMPointerType(LLVMInt8TypeInContext(self.ctx), 0)))
+        }
+    }
+
     pub fn type_fn(&'llvm self, args: &mut [Type<'llvm>], ret: Type<'llvm>) -> Type<'llvm> {
         let t_ref = unsafe {
             LLVMFunctionType(
@@ -171,6 +229,29 @@ impl<'llvm> Type<'llvm> {
     pub fn dump(&self) {
         unsafe { LLVMDumpType(self.0) };
     }
+
+    /// Bit width of an integer type, 0 for anything else
+    pub fn int_width(&self) -> u32 {
+        if self.kind() != LLVMTypeKind::LLVMIntegerTypeKind {
+            return 0;
+        }
+        unsafe { LLVMGetIntTypeWidth(self.0) }
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self.kind(), LLVMTypeKind::LLVMFloatTypeKind | LLVMTypeKind::LLVMDoubleTypeKind)
+    }
+
+    pub fn const_bool(self, b: bool) -> LlvmValue<'llvm> {
+        debug_assert_eq!(
+            self.kind(),
+            LLVMTypeKind::LLVMIntegerTypeKind,
+            "Expected a bool type when creating const bool value!"
+        );
+
+        let value_ref = unsafe { LLVMConstInt(self.0, b as u64, 0) };
+        LlvmValue::new(value_ref)
+    }
     pub fn const_char(self, c: char) -> LlvmValue<'llvm> {
         debug_assert_eq!(
             self.kind(),
@@ -181,6 +262,20 @@ impl<'llvm> Type<'llvm> {
         let value_ref = unsafe { LLVMConstInt(self.0, c as u64, 1) };
         LlvmValue::new(value_ref)
     }
+    pub fn const_null(self) -> LlvmValue<'llvm> {
+        let value_ref = unsafe { LLVMConstNull(self.0) };
+        LlvmValue::new(value_ref)
+    }
+    pub fn const_f32(self, n: f32) -> LlvmValue<'llvm> {
+        debug_assert_eq!(
+            self.kind(),
+            LLVMTypeKind::LLVMFloatTypeKind,
+            "Expected a float type when creating const f32 value!"
+        );
+
+        let value_ref = unsafe { LLVMConstReal(self.0, n as f64) };
+        LlvmValue::new(value_ref)
+    }
     pub fn const_f64(self, n: f64) -> LlvmValue<'llvm> {
         debug_assert_eq!(
             self.kind(),
@@ -262,6 +357,72 @@ impl <'llvm>Builder<'llvm> {
 
         LlvmValue::new(value_ref)
     }
+    /// Any two-operand instruction, e.g. `LLVMSub`, `LLVMShl`, `LLVMFMul`
+    pub fn binop(&self, op: LLVMOpcode, lhs: LlvmValue<'llvm>, rhs: LlvmValue<'llvm>) -> LlvmValue<'llvm> {
+        let value_ref = unsafe {
+            LLVMBuildBinOp(self.builder, op, lhs.value_ref(), rhs.value_ref(), b"binop\0".as_ptr().cast())
+        };
+        LlvmValue::new(value_ref)
+    }
+    pub fn icmp(&self, pred: LLVMIntPredicate, lhs: LlvmValue<'llvm>, rhs: LlvmValue<'llvm>) -> LlvmValue<'llvm> {
+        let value_ref = unsafe {
+            LLVMBuildICmp(
+                self.builder,
+                pred,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                b"cmp\0".as_ptr().cast()
+            )
+        };
+
+        LlvmValue::new(value_ref)
+    }
+    /// Float comparison, e.g. `LLVMRealOLT`
+    pub fn fcmp(&self, pred: LLVMRealPredicate, lhs: LlvmValue<'llvm>, rhs: LlvmValue<'llvm>) -> LlvmValue<'llvm> {
+        let value_ref = unsafe {
+            LLVMBuildFCmp(
+                self.builder,
+                pred,
+                lhs.value_ref(),
+                rhs.value_ref(),
+                b"fcmp\0".as_ptr().cast()
+            )
+        };
+
+        LlvmValue::new(value_ref)
+    }
+    pub fn zext(&self, v: LlvmValue<'llvm>, ty: Type<'llvm>) -> LlvmValue<'llvm> {
+        let value_ref = unsafe {
+            LLVMBuildZExt(self.builder, v.value_ref(), ty.0, b"zext\0".as_ptr().cast())
+        };
+        LlvmValue::new(value_ref)
+    }
+    pub fn sext(&self, v: LlvmValue<'llvm>, ty: Type<'llvm>) -> LlvmValue<'llvm> {
+        let value_ref = unsafe {
+            LLVMBuildSExt(self.builder, v.value_ref(), ty.0, b"sext\0".as_ptr().cast())
+        };
+        LlvmValue::new(value_ref)
+    }
+    pub fn sitofp(&self, v: LlvmValue<'llvm>, ty: Type<'llvm>) -> LlvmValue<'llvm> {
+        let value_ref = unsafe {
+            LLVMBuildSIToFP(self.builder, v.value_ref(), ty.0, b"sitofp\0".as_ptr().cast())
+        };
+        LlvmValue::new(value_ref)
+    }
+    pub fn trunc(&self, v: LlvmValue<'llvm>, ty: Type<'llvm>) -> LlvmValue<'llvm> {
+        let value_ref = unsafe {
+            LLVMBuildTrunc(self.builder, v.value_ref(), ty.0, b"trunc\0".as_ptr().cast())
+        };
+        LlvmValue::new(value_ref)
+    }
+    /// Function that owns the block the builder is positioned in
+    pub fn current_fn(&self) -> FnValue<'llvm> {
+        let value_ref = unsafe {
+            LLVMGetBasicBlockParent(LLVMGetInsertBlock(self.builder))
+        };
+        FnValue::new(value_ref)
+    }
+    /// Private global holding the string, like a C string literal
     pub fn global_string(&self, raw_str: &str) -> LlvmValue<'llvm> {
         let v = unsafe {
             LLVMBuildGlobalString(self.builder,
@@ -271,9 +432,21 @@ impl <'llvm>Builder<'llvm> {
         assert!(!v.is_null());
         LlvmValue::new(v)
     }
+    /// Stack slot in the entry block of the current function, so an alloca in a loop
+    /// body is made once and not on every iteration
     pub fn alloca(&self, Types: Type<'llvm>, name: &str) -> LlvmValue<'llvm> {
         let v = unsafe {
-            LLVMBuildAlloca(self.builder, Types.0,CString::new(name).expect("cstring failed").as_ptr())
+            let block = LLVMGetInsertBlock(self.builder);
+            let entry = LLVMGetEntryBasicBlock(LLVMGetBasicBlockParent(block));
+            let first = LLVMGetFirstInstruction(entry);
+            if first.is_null() {
+                LLVMPositionBuilderAtEnd(self.builder, entry);
+            } else {
+                LLVMPositionBuilderBefore(self.builder, first);
+            }
+            let v = LLVMBuildAlloca(self.builder, Types.0,CString::new(name).expect("cstring failed").as_ptr());
+            LLVMPositionBuilderAtEnd(self.builder, block);
+            v
         };
         LlvmValue::new(v)
     }
@@ -298,6 +471,16 @@ impl <'llvm>Builder<'llvm> {
         LlvmValue::new(v)
     }
 
+    /// Pointer to element `idx` of the `ty`s at `ptr`
+    pub fn gep(&self, ty: Type<'llvm>, ptr: LlvmValue<'llvm>, idx: LlvmValue<'llvm>) -> LlvmValue<'llvm> {
+        let mut indices = [idx.value_ref()];
+        let v = unsafe {
+            LLVMBuildGEP2(self.builder, ty.0, ptr.value_ref(), indices.as_mut_ptr(), 1, c"elem".as_ptr())
+        };
+        assert!(!v.is_null());
+        LlvmValue::new(v)
+    }
+
     pub fn ret(&self, ret: LlvmValue<'llvm>) -> LlvmValue<'llvm> {
         let v = unsafe {
             LLVMBuildRet(self.builder,ret.value_ref())
@@ -325,6 +508,52 @@ impl <'llvm>Builder<'llvm> {
         };
         LlvmValue::new(value_ref)
     }
+    pub fn br(&self, bb: BasicBlock<'llvm>) -> LlvmValue<'llvm> {
+        let v = unsafe {
+            LLVMBuildBr(self.builder, bb.0)
+        };
+        LlvmValue::new(v)
+    }
+    pub fn cond_br(&self, cond: LlvmValue<'llvm>, then_bb: BasicBlock<'llvm>, else_bb: BasicBlock<'llvm>) -> LlvmValue<'llvm> {
+        let v = unsafe {
+            LLVMBuildCondBr(self.builder, cond.value_ref(), then_bb.0, else_bb.0)
+        };
+        LlvmValue::new(v)
+    }
+    pub fn select(&self, cond: LlvmValue<'llvm>, then_v: LlvmValue<'llvm>, else_v: LlvmValue<'llvm>) -> LlvmValue<'llvm> {
+        let v = unsafe {
+            LLVMBuildSelect(self.builder, cond.value_ref(), then_v.value_ref(), else_v.value_ref(), b"select\0".as_ptr().cast())
+        };
+        LlvmValue::new(v)
+    }
+    pub fn phi(&self, ty: Type<'llvm>) -> LlvmValue<'llvm> {
+        let v = unsafe {
+            LLVMBuildPhi(self.builder, ty.0, b"phi\0".as_ptr().cast())
+        };
+        LlvmValue::new(v)
+    }
+    /// Block the builder is positioned in
+    pub fn current_block(&self) -> BasicBlock<'llvm> {
+        BasicBlock(unsafe { LLVMGetInsertBlock(self.builder) }, PhantomData)
+    }
+    pub fn switch(&self, v: LlvmValue<'llvm>, else_bb: BasicBlock<'llvm>, num_cases: usize) -> LlvmValue<'llvm> {
+        let v = unsafe {
+            LLVMBuildSwitch(self.builder, v.value_ref(), else_bb.0, num_cases as libc::c_uint)
+        };
+        LlvmValue::new(v)
+    }
+    pub fn unreachable(&self) -> LlvmValue<'llvm> {
+        let v = unsafe {
+            LLVMBuildUnreachable(self.builder)
+        };
+        LlvmValue::new(v)
+    }
+    /// Whether the current block already ends with a terminator (ret, br, ...)
+    pub fn is_terminated(&self) -> bool {
+        unsafe {
+            !LLVMGetBasicBlockTerminator(LLVMGetInsertBlock(self.builder)).is_null()
+        }
+    }
     pub fn pos_at_end(&self, bb: BasicBlock<'llvm>) {
         unsafe {
             LLVMPositionBuilderAtEnd(self.builder, bb.0);
@@ -365,6 +594,31 @@ impl <'llvm>LlvmValue<'llvm> {
         unsafe { LLVMDumpValue(self.value_ref()) };
     }
 
+    pub fn type_of(&self) -> Type<'llvm> {
+        Type::new(unsafe { LLVMTypeOf(self.value_ref()) })
+    }
+
+    pub fn set_initializer(&self, init: LlvmValue<'llvm>) {
+        unsafe { LLVMSetInitializer(self.value_ref(), init.value_ref()) };
+    }
+
+    /// Mark a global as constant, which places it in a read-only section
+    pub fn set_global_constant(&self, is_const: bool) {
+        unsafe { LLVMSetGlobalConstant(self.value_ref(), is_const as LLVMBool) };
+    }
+
+    /// Add incoming (value, predecessor) pairs to a phi instruction
+    pub fn add_incoming(&self, incoming: &[(LlvmValue<'llvm>, BasicBlock<'llvm>)]) {
+        let mut values = incoming.iter().map(|(v, _)| v.value_ref()).collect::<Vec<LLVMValueRef>>();
+        let mut blocks = incoming.iter().map(|(_, b)| b.0).collect::<Vec<LLVMBasicBlockRef>>();
+        unsafe { LLVMAddIncoming(self.value_ref(), values.as_mut_ptr(), blocks.as_mut_ptr(), incoming.len() as libc::c_uint) };
+    }
+
+    /// Add a case to a switch instruction
+    pub fn add_case(&self, on_v: LlvmValue<'llvm>, dest: BasicBlock<'llvm>) {
+        unsafe { LLVMAddCase(self.value_ref(), on_v.value_ref(), dest.0) };
+    }
+
     pub fn set_name(&self, name: &str) {
         unsafe { LLVMSetValueName2(self.value_ref(), name.as_ptr().cast(), name.len()) };
     }
@@ -415,6 +669,12 @@ impl <'llvm>FnValue<'llvm> {
         };
         Type::new(type_ref)
     }
+    pub fn return_type(&self) -> Type<'llvm> {
+        let type_ref = unsafe {
+            LLVMGetReturnType(LLVMGlobalGetValueType(self.value_ref()))
+        };
+        Type::new(type_ref)
+    }
     pub fn verify(&self) -> bool {
         unsafe {
             LLVMVerifyFunction(
@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use llvm_sys_201::{LLVMIntPredicate, LLVMOpcode, LLVMRealPredicate};
+
 use crate::{codegen::llvm::{Builder, FnValue, LlvmValue, Module, Type}, token::token_type::TokenType, Value::Value, AST::expr_node::{DataType, Expr, Func_Header}};
 
 pub struct LLVMCodegen <'llvm>{
@@ -49,45 +51,130 @@ impl<'llvm> LLVMCodegen<'llvm> {
     }
 
     pub fn codegen_all(&'llvm self) -> Vec<TypeValue<'llvm>> {
+        // declare every function up front, calls may come before the definition
+        for e in &self.exprs {
+            if let Expr::FuncStmt(f, _) | Expr::Extern(f) = e
+                && self.module.get_fn(&f.name).is_none() {
+                self.extern_codegen(f.clone());
+            }
+        }
+
         let mut hm = HashMap::new();
         self.exprs.clone().into_iter().map(|f| {
-            self.codegen(f,&mut hm)
+            match f {
+                Expr::VarDecl(dt, is_ptr, is_const, name, init, _) => {
+                    self.global_codegen(dt, is_ptr, is_const, name, init, &mut hm)
+                }
+                f => self.codegen(f,&mut hm)
+            }
         }).collect::<Vec<TypeValue<'llvm>>>()
     }
 
     fn dczdt_2_llvmdt(&self, dt: DataType, is_ptr: bool) -> Type<'llvm> {
-        match dt {
-            DataType::Void => {
-                let mut t = self.module.type_void();
-                if is_ptr {
-                    t.to_pointer();
-                }
-                t
-            }
-            DataType::Int => {
-                let mut t = self.module.type_i32();
-                if is_ptr {
-                    t.to_pointer();
-                }
-                t
-            }
-            DataType::Long => {
-                self.module.type_i64()
-            }
-            DataType::Char => {
-                
-                let mut t = self.module.type_char();
-                if is_ptr {
-                    t.to_pointer();
-                }
-                t
-            }
+        let mut t = match dt {
+            // LLVM has no pointer to void, use i8* like clang
+            DataType::Void if is_ptr => self.module.type_char(),
+            DataType::Void => return self.module.type_void(),
+            DataType::Int | DataType::Enum(_) => self.module.type_i32(),
+            DataType::Short => self.module.type_i16(),
+            DataType::Long => self.module.type_i64(),
+            DataType::Float => self.module.type_float(),
+            DataType::Suu => self.module.type_double(),
+            // bool is i1 as a value but stored as i8, same as C's _Bool
+            DataType::Char | DataType::Bool => self.module.type_char(),
             _ => {
                 todo!("{:?}",dt)
             }
+        };
+        if is_ptr {
+            t.to_pointer();
+        }
+        t
+    }
+
+    /// Address, type and data type of `p[i]`, `p` is a pointer variable
+    fn element(&'llvm self, p: Expr, i: Expr, variable: &mut HashMap<String, (LlvmValue<'llvm>, Type<'llvm>, DataType)>) -> (LlvmValue<'llvm>, Type<'llvm>, DataType) {
+        let n = p.ident_to_string();
+        let (ptr_ptr, ptr_ty, dt) = variable.get(&n).cloned().unwrap();
+        let ptr = self.builder.load(&n, ptr_ty, ptr_ptr);
+        let idx: LlvmValue<'llvm> = self.codegen(i, variable).into();
+        let ty = self.dczdt_2_llvmdt(dt.clone(), false);
+        (self.builder.gep(ty, ptr, idx), ty, dt)
+    }
+
+    fn load_element(&self, elem: LlvmValue<'llvm>, ty: Type<'llvm>, dt: &DataType) -> LlvmValue<'llvm> {
+        let v = self.builder.load("elem", ty, elem);
+        if matches!(dt, DataType::Bool) {
+            self.builder.trunc(v, self.module.type_bool())
+        } else {
+            v
+        }
+    }
+
+    /// Convert a value to the type it is stored/passed as (i1 widening, integer width, int to float)
+    fn coerce(&self, v: LlvmValue<'llvm>, ty: Type<'llvm>) -> LlvmValue<'llvm> {
+        match (v.type_of().int_width(), ty.int_width()) {
+            (1, to) if to > 1 => self.builder.zext(v, ty),
+            (from, to) if from > 1 && to > from => self.builder.sext(v, ty),
+            (from, to) if to > 0 && from > to => self.builder.trunc(v, ty),
+            (from, 0) if from > 0 && ty.is_float() => self.builder.sitofp(v, ty),
+            _ => v
         }
     }
 
+    /// An `i1` for a condition operand, any other value is compared against zero like C
+    fn truth(&self, v: LlvmValue<'llvm>) -> LlvmValue<'llvm> {
+        let ty = v.type_of();
+        match ty.int_width() {
+            1 => v,
+            0 => self.builder.fcmp(LLVMRealPredicate::LLVMRealONE, v, ty.const_null()),
+            _ => self.builder.icmp(LLVMIntPredicate::LLVMIntNE, v, ty.const_null())
+        }
+    }
+
+    /// Type both arms of a ternary are converted to
+    fn unify_type(&self, a: Type<'llvm>, b: Type<'llvm>) -> Type<'llvm> {
+        match (a.int_width(), b.int_width()) {
+            (wa, wb) if wa > 0 && wb > 0 => if wa >= wb { a } else { b },
+            // int and float, the float wins
+            (0, wb) if wb > 0 => a,
+            (wa, 0) if wa > 0 => b,
+            _ => a
+        }
+    }
+
+    fn global_codegen(&self,
+        dt: DataType,
+        is_ptr: bool,
+        is_const: bool,
+        name: String,
+        init: Option<Box<Expr>>,
+        variable: &mut HashMap<String, (LlvmValue<'llvm>,Type<'llvm>,DataType)>
+    ) -> TypeValue<'llvm> {
+        /*
+            @{name} = global <type> <const>
+        */
+        let ty = self.dczdt_2_llvmdt(dt.clone(), is_ptr);
+        let global = self.module.add_global(ty, &name);
+
+        let init_v = match init.map(|f| *f) {
+            None => ty.const_null(),
+            Some(Expr::Literal(Value::Str(s))) => self.module.const_string(&s),
+            Some(Expr::Literal(Value::Number(n))) => ty.const_i64(n),
+            Some(Expr::Literal(Value::Boolean(b))) => ty.const_i64(b as i64),
+            Some(Expr::Literal(Value::Char(c))) => ty.const_char(c),
+            Some(Expr::Literal(Value::Float(f))) => ty.const_f32(f),
+            Some(Expr::Literal(Value::Double(d))) => ty.const_f64(d),
+            Some(Expr::EnumValue(_, _, d)) => ty.const_i64(d),
+            Some(o) => panic!("Global '{}' initializer must be a constant expression, got {:?}", name, o)
+        };
+        global.set_initializer(init_v);
+        global.set_global_constant(is_const);
+
+        variable.insert(name, (global, ty, dt));
+        TypeValue::None
+    }
+
     fn extern_codegen(&self,f: Func_Header) -> TypeValue<'llvm> {
         let mut args_dt = Vec::new();
         for x in f.clone().args {
@@ -112,7 +199,7 @@ impl<'llvm> LLVMCodegen<'llvm> {
     e: Expr,
     variable: &mut HashMap<
         String, 
-        (LlvmValue<'llvm>,Type<'llvm>)
+        (LlvmValue<'llvm>,Type<'llvm>,DataType)
     >) -> TypeValue<'llvm> {
         match e {
             Expr::Literal(v) => {
@@ -123,29 +210,147 @@ impl<'llvm> LLVMCodegen<'llvm> {
                     Value::Str(s)=> {
                         TypeValue::LLVMValue(self.builder.global_string(&s))
                     }
+                    Value::Boolean(b) => {
+                        TypeValue::LLVMValue(self.module.type_bool().const_bool(b))
+                    }
+                    Value::Float(f) => {
+                        TypeValue::LLVMValue(self.module.type_float().const_f32(f))
+                    }
+                    Value::Double(d) => {
+                        TypeValue::LLVMValue(self.module.type_double().const_f64(d))
+                    }
+                    Value::Char(c) => {
+                        TypeValue::LLVMValue(self.module.type_char().const_char(c))
+                    }
                     _ => todo!()
                 }
                 
             },
+            Expr::Unary(op, rhs) => {
+                let v: LlvmValue<'llvm> = self.codegen(*rhs,variable).into();
+                let ty = v.type_of();
+                TypeValue::LLVMValue(match op.tok_type {
+                    TokenType::Minus if ty.is_float() => self.builder.binop(LLVMOpcode::LLVMFSub, ty.const_null(), v),
+                    TokenType::Minus => self.builder.binop(LLVMOpcode::LLVMSub, ty.const_null(), v),
+                    // bool flips, any other int is compared against zero like C
+                    TokenType::Not if ty.int_width() == 1 => self.builder.binop(LLVMOpcode::LLVMXor, v, ty.const_bool(true)),
+                    TokenType::Not => self.builder.icmp(LLVMIntPredicate::LLVMIntEQ, v, ty.const_null()),
+                    _ => self.builder.binop(LLVMOpcode::LLVMXor, v, ty.const_i64(-1)),
+                })
+            }
+            Expr::Binary(lhs, op, rhs) if matches!(op.tok_type, TokenType::AndBool | TokenType::OrBool) => {
+                /*
+                    <lhs>
+                    br i1 %lhs, label %rhs, label %end    ; `||` swaps the labels
+                rhs:
+                    <rhs>
+                    br label %end
+                end:
+                    phi i1 [ false, %lhs ], [ %rhs, %rhs ]    ; `||` gives true
+                */
+                let is_or = op.tok_type == TokenType::OrBool;
+                let l = self.codegen(*lhs, variable).into();
+                let l = self.truth(l);
+                let lhs_end = self.builder.current_block();
+                let f = self.builder.current_fn();
+                let rhs_bb = self.module.new_basic_block(f);
+                let end_bb = self.module.new_basic_block(f);
+                if is_or {
+                    self.builder.cond_br(l, end_bb, rhs_bb);
+                } else {
+                    self.builder.cond_br(l, rhs_bb, end_bb);
+                }
+
+                self.builder.pos_at_end(rhs_bb);
+                let r = self.codegen(*rhs, variable).into();
+                let r = self.truth(r);
+                let rhs_end = self.builder.current_block();
+                self.builder.br(end_bb);
+
+                self.builder.pos_at_end(end_bb);
+                let bool_ty = self.module.type_bool();
+                let phi = self.builder.phi(bool_ty);
+                phi.add_incoming(&[(bool_ty.const_bool(is_or), lhs_end), (r, rhs_end)]);
+                TypeValue::LLVMValue(phi)
+            }
             Expr::Binary(lhs, op, rhs) => {
-                let lhs = self.codegen(*lhs,variable);
-                let rhs = self.codegen(*rhs,variable);
+                let lhs: LlvmValue<'llvm> = self.codegen(*lhs,variable).into();
+                let rhs: LlvmValue<'llvm> = self.codegen(*rhs,variable).into();
+                // both operands have to share a type, e.g. `long_var += 1`
+                let ty = self.unify_type(lhs.type_of(), rhs.type_of());
+                let (lhs, rhs) = (self.coerce(lhs, ty), self.coerce(rhs, ty));
 
                 match op.tok_type {
-                    TokenType::Plus => {
+                    TokenType::Plus | TokenType::Minus | TokenType::Star | TokenType::Slash | TokenType::Modulo |
+                    TokenType::ShiftLeft | TokenType::ShiftRight |
+                    TokenType::And | TokenType::Or | TokenType::Caret => {
+                        let opcode = match (op.tok_type, ty.is_float()) {
+                            (TokenType::Plus, false) => LLVMOpcode::LLVMAdd,
+                            (TokenType::Plus, true) => LLVMOpcode::LLVMFAdd,
+                            (TokenType::Minus, false) => LLVMOpcode::LLVMSub,
+                            (TokenType::Minus, true) => LLVMOpcode::LLVMFSub,
+                            (TokenType::Star, false) => LLVMOpcode::LLVMMul,
+                            (TokenType::Star, true) => LLVMOpcode::LLVMFMul,
+                            (TokenType::Slash, false) => LLVMOpcode::LLVMSDiv,
+                            (TokenType::Slash, true) => LLVMOpcode::LLVMFDiv,
+                            (TokenType::Modulo, false) => LLVMOpcode::LLVMSRem,
+                            (TokenType::Modulo, true) => LLVMOpcode::LLVMFRem,
+                            (TokenType::ShiftLeft, _) => LLVMOpcode::LLVMShl,
+                            (TokenType::ShiftRight, _) => LLVMOpcode::LLVMAShr,
+                            (TokenType::And, _) => LLVMOpcode::LLVMAnd,
+                            (TokenType::Or, _) => LLVMOpcode::LLVMOr,
+                            _ => LLVMOpcode::LLVMXor,
+                        };
+                        TypeValue::LLVMValue(self.builder.binop(opcode, lhs, rhs))
+                    }
+                    TokenType::EqualEqual | TokenType::NotEqual |
+                    TokenType::Less | TokenType::LessEqual |
+                    TokenType::Greater | TokenType::GreaterEqual if ty.is_float() => {
+                        // ordered, a NaN operand compares false
+                        let pred = match op.tok_type {
+                            TokenType::EqualEqual => LLVMRealPredicate::LLVMRealOEQ,
+                            TokenType::NotEqual => LLVMRealPredicate::LLVMRealONE,
+                            TokenType::Less => LLVMRealPredicate::LLVMRealOLT,
+                            TokenType::LessEqual => LLVMRealPredicate::LLVMRealOLE,
+                            TokenType::Greater => LLVMRealPredicate::LLVMRealOGT,
+                            _ => LLVMRealPredicate::LLVMRealOGE,
+                        };
                         TypeValue::LLVMValue(
-                            self.builder.iadd(lhs.into(), rhs.into())
+                            self.builder.fcmp(pred, lhs, rhs)
                         )
-                    },
+                    }
+                    TokenType::EqualEqual | TokenType::NotEqual |
+                    TokenType::Less | TokenType::LessEqual |
+                    TokenType::Greater | TokenType::GreaterEqual => {
+                        let pred = match op.tok_type {
+                            TokenType::EqualEqual => LLVMIntPredicate::LLVMIntEQ,
+                            TokenType::NotEqual => LLVMIntPredicate::LLVMIntNE,
+                            TokenType::Less => LLVMIntPredicate::LLVMIntSLT,
+                            TokenType::LessEqual => LLVMIntPredicate::LLVMIntSLE,
+                            TokenType::Greater => LLVMIntPredicate::LLVMIntSGT,
+                            _ => LLVMIntPredicate::LLVMIntSGE,
+                        };
+                        TypeValue::LLVMValue(
+                            self.builder.icmp(pred, lhs, rhs)
+                        )
+                    }
                     _ => {
                         todo!()
                     }
                 }
             }
             Expr::Extern(f) => {
-                self.extern_codegen(f)
+                match self.module.get_fn(&f.name) {
+                    Some(fn_v) => TypeValue::FnValue(fn_v),
+                    None => self.extern_codegen(f)
+                }
+            }
+            Expr::EnumValue(_, _, d) => {
+                TypeValue::LLVMValue(self.module.type_i32().const_i32(d as i32))
             }
-            Expr::VarDecl(dt,is_ptr ,is_const ,name ,init ) => {
+            Expr::EnumDecl(_, _) => TypeValue::None,
+            Expr::None => TypeValue::None,
+            Expr::VarDecl(dt,is_ptr ,is_const ,name ,init, _) => {
                 /*
                     %{name}_ptr = alloca <type>
                     store <type> <val>, ptr %{name}_ptr
@@ -153,24 +358,51 @@ impl<'llvm> LLVMCodegen<'llvm> {
 
                 
                 let ptr_name = name.clone() + "_ptr";
-                let alloca= self.builder.alloca(self.dczdt_2_llvmdt(dt.clone(), is_ptr), &ptr_name);
+                let ty = self.dczdt_2_llvmdt(dt.clone(), is_ptr);
+                let alloca= self.builder.alloca(ty, &ptr_name);
                 if let Some(v) = init {
                     let vf: LlvmValue<'llvm> = self.codegen(*v,variable).into();
-                    self.builder.store(vf, alloca);
+                    self.builder.store(self.coerce(vf, ty), alloca);
                 }
                 variable.insert(name, 
                     (
                         alloca,
-                        self.dczdt_2_llvmdt(dt, is_ptr)
+                        ty,
+                        dt
                     ));
                 TypeValue::None
             }
+            Expr::Assign(n, v, _) => {
+                let (ptr, ty, _) = *variable.get(&n).unwrap();
+                let vf: LlvmValue<'llvm> = self.codegen(*v,variable).into();
+                self.builder.store(self.coerce(vf, ty), ptr);
+                // the assignment is also an expression, `a = b = 1`
+                self.codegen(Expr::Var(n), variable)
+            }
+            Expr::Index(p, i) => {
+                let (elem, ty, dt) = self.element(*p, *i, variable);
+                TypeValue::LLVMValue(self.load_element(elem, ty, &dt))
+            }
+            Expr::SetIndex(p, i, v, _) => {
+                let vf: LlvmValue<'llvm> = self.codegen(*v, variable).into();
+                let (elem, ty, dt) = self.element(*p, *i, variable);
+                self.builder.store(self.coerce(vf, ty), elem);
+                TypeValue::LLVMValue(self.load_element(elem, ty, &dt))
+            }
+            Expr::Postfix(a) => {
+                let old = self.codegen(a.assign_target().expect("postfix of an assignment"), variable);
+                self.codegen(*a, variable);
+                old
+            }
             Expr::Var(n) => {
                 let v = variable.get(&n).unwrap();
-                let l=self.builder.load(n.as_str(),v.1, v.0);
+                let mut l=self.builder.load(n.as_str(),v.1, v.0);
+                if matches!(v.2, DataType::Bool) {
+                    l = self.builder.trunc(l, self.module.type_bool());
+                }
                 TypeValue::LLVMValue(l)
             }
-            Expr::Callee(name, args) => {
+            Expr::Callee(name, args, _) => {
                 if let Some(func) = self.module.get_fn(&name.ident_to_string()) {
                     //func.dump();
                     if func.args() != args.len() {
@@ -178,7 +410,11 @@ impl<'llvm> LLVMCodegen<'llvm> {
                     }
                     let mut args = args
                             .iter()
-                            .map(|arg| self.codegen(arg.clone(),variable).into()).collect::<
+                            .enumerate()
+                            .map(|(idx, arg)| {
+                                let v = self.codegen(arg.clone(),variable).into();
+                                self.coerce(v, func.arg(idx).type_of())
+                            }).collect::<
                             Vec<LlvmValue<'_>>
                             >();
 
@@ -187,19 +423,129 @@ impl<'llvm> LLVMCodegen<'llvm> {
                     panic!("Error!");
                 }
             }
+            Expr::Ternary(cond, then_e, else_e) => {
+                let c: LlvmValue<'llvm> = self.codegen(*cond, variable).into();
+
+                // cheap arms without side effects are both evaluated and selected
+                if is_pure(&then_e) && is_pure(&else_e) {
+                    let a: LlvmValue<'llvm> = self.codegen(*then_e, variable).into();
+                    let b: LlvmValue<'llvm> = self.codegen(*else_e, variable).into();
+                    let ty = self.unify_type(a.type_of(), b.type_of());
+                    return TypeValue::LLVMValue(self.builder.select(c, self.coerce(a, ty), self.coerce(b, ty)));
+                }
+
+                let f = self.builder.current_fn();
+                let then_bb = self.module.new_basic_block(f);
+                let else_bb = self.module.new_basic_block(f);
+                let end_bb = self.module.new_basic_block(f);
+                self.builder.cond_br(c, then_bb, else_bb);
+
+                // arms may end in another block (nested ternary), the phi needs those
+                self.builder.pos_at_end(then_bb);
+                let a: LlvmValue<'llvm> = self.codegen(*then_e, variable).into();
+                let then_end = self.builder.current_block();
+                self.builder.pos_at_end(else_bb);
+                let b: LlvmValue<'llvm> = self.codegen(*else_e, variable).into();
+                let else_end = self.builder.current_block();
+
+                let ty = self.unify_type(a.type_of(), b.type_of());
+                self.builder.pos_at_end(then_end);
+                let a = self.coerce(a, ty);
+                self.builder.br(end_bb);
+                self.builder.pos_at_end(else_end);
+                let b = self.coerce(b, ty);
+                self.builder.br(end_bb);
+
+                self.builder.pos_at_end(end_bb);
+                let phi = self.builder.phi(ty);
+                phi.add_incoming(&[(a, then_end), (b, else_end)]);
+                TypeValue::LLVMValue(phi)
+            }
+            Expr::SwitchStmt(value, cases, default, _) => {
+                let v: LlvmValue<'llvm> = self.codegen(*value, variable).into();
+                let f = self.builder.current_fn();
+
+                let default_bb = self.module.new_basic_block(f);
+                let end_bb = self.module.new_basic_block(f);
+                let switch = self.builder.switch(v, default_bb, cases.len());
+
+                for (label, body) in cases {
+                    let case_bb = self.module.new_basic_block(f);
+                    switch.add_case(v.type_of().const_i64(label.case_value().expect("case label must be constant")), case_bb);
+                    self.builder.pos_at_end(case_bb);
+                    self.codegen(body, variable);
+                    if !self.builder.is_terminated() {
+                        self.builder.br(end_bb);
+                    }
+                }
+
+                self.builder.pos_at_end(default_bb);
+                self.codegen(*default, variable);
+                if !self.builder.is_terminated() {
+                    self.builder.br(end_bb);
+                }
+
+                self.builder.pos_at_end(end_bb);
+                TypeValue::None
+            }
             Expr::Block(v) => {
                 for x in v.iter() {
+                    // nothing can follow a return in the same basic block
+                    if self.builder.is_terminated() {
+                        break;
+                    }
                     self.codegen(x.clone(),variable);
                 }
                 TypeValue::None
             }
-            Expr::Return(v) => {
+            Expr::IfStmt(cond, then_bl, else_bl, _) => {
+                let c: LlvmValue<'llvm> = self.codegen(*cond, variable).into();
+                let f = self.builder.current_fn();
+                let then_bb = self.module.new_basic_block(f);
+                let else_bb = self.module.new_basic_block(f);
+                let end_bb = self.module.new_basic_block(f);
+                self.builder.cond_br(c, then_bb, else_bb);
+
+                // each arm only falls through to the end when it didn't return
+                for (bb, body) in [(then_bb, *then_bl), (else_bb, *else_bl)] {
+                    self.builder.pos_at_end(bb);
+                    self.codegen(body, &mut variable.clone());
+                    if !self.builder.is_terminated() {
+                        self.builder.br(end_bb);
+                    }
+                }
+
+                self.builder.pos_at_end(end_bb);
+                TypeValue::None
+            }
+            Expr::WhileStmt(cond, body, _) => {
+                let f = self.builder.current_fn();
+                let cond_bb = self.module.new_basic_block(f);
+                let body_bb = self.module.new_basic_block(f);
+                let end_bb = self.module.new_basic_block(f);
+                self.builder.br(cond_bb);
+
+                self.builder.pos_at_end(cond_bb);
+                let c: LlvmValue<'llvm> = self.codegen(*cond, variable).into();
+                self.builder.cond_br(c, body_bb, end_bb);
+
+                self.builder.pos_at_end(body_bb);
+                self.codegen(*body, &mut variable.clone());
+                if !self.builder.is_terminated() {
+                    self.builder.br(cond_bb);
+                }
+
+                self.builder.pos_at_end(end_bb);
+                TypeValue::None
+            }
+            Expr::Return(v, _) => {
                 if let Some(e) = v {
+                    let ret_v = self.codegen(*e,variable).into();
                     TypeValue::LLVMValue(
-                        self.builder.ret(self.codegen(*e,variable).into())
+                        self.builder.ret(self.coerce(ret_v, self.builder.current_fn().return_type()))
                     )
                 } else {
-                    todo!("Not support empty return");
+                    TypeValue::LLVMValue(self.builder.retvoid())
                 }
             }
             Expr::FuncStmt(header, block) => {
@@ -214,9 +560,23 @@ impl<'llvm> LLVMCodegen<'llvm> {
                 let bb = self.module.new_basic_block(f);
                 self.builder.pos_at_end(bb);
 
-                self.codegen(*block,variable);
-                if header.return_type.is_none() {
-                    self.builder.retvoid();
+                // locals live on top of the globals and die with the function
+                let mut locals = variable.clone();
+                // parameters are copied to the stack so they can be assigned like any local
+                for (idx, (dt, name, is_ptr)) in header.args.iter().enumerate() {
+                    let ty = self.dczdt_2_llvmdt(dt.clone(), *is_ptr);
+                    let alloca = self.builder.alloca(ty, &(name.clone() + "_ptr"));
+                    self.builder.store(f.arg(idx), alloca);
+                    locals.insert(name.clone(), (alloca, ty, dt.clone()));
+                }
+                self.codegen(*block,&mut locals);
+                if !self.builder.is_terminated() {
+                    if matches!(header.return_type, None | Some(DataType::Void)) {
+                        self.builder.retvoid();
+                    } else {
+                        // the checker made sure every path returned, so this block has no predecessor
+                        self.builder.unreachable();
+                    }
                 }
                 assert!(f.verify());
                 TypeValue::FnValue(f)
@@ -226,4 +586,17 @@ impl<'llvm> LLVMCodegen<'llvm> {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// No side effect and cannot trap, so it can be evaluated unconditionally
+fn is_pure(e: &Expr) -> bool {
+    match e {
+        Expr::Literal(_) | Expr::Var(_) | Expr::EnumValue(_, _, _) => true,
+        Expr::Grouping(e) | Expr::Unary(_, e) => is_pure(e),
+        Expr::Binary(lhs, op, rhs) => {
+            !matches!(op.tok_type, TokenType::Slash | TokenType::Modulo) && is_pure(lhs) && is_pure(rhs)
+        }
+        Expr::Ternary(c, a, b) => is_pure(c) && is_pure(a) && is_pure(b),
+        _ => false
+    }
+}
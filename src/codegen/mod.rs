@@ -1,5 +1,7 @@
 pub mod ir;
 pub mod ir_opcode;
+pub mod bytecode;
+pub mod ir_text;
 pub mod ast_2_ir;
 pub mod codegen;
 
@@ -0,0 +1,202 @@
+/*
+ * Embedding API.
+ *
+ * An `Engine` owns the search path, the preprocessor defines and one VM.
+ * Scripts are run in the VM or compiled to an object file through LLVM:
+ *
+ *     let mut engine = Engine::new();
+ *     engine.register_native("twice", 1, |args| ...);
+ *     engine.eval("func add(int a, int b) -> int { return a + b; }")?;
+ *     let sum = engine.call("add", &[Value::Number(1), Value::Number(2)])?;
+ *
+ * Functions and globals of the last evaluated script stay loaded for `call`.
+ */
+
+use std::{collections::HashMap, fmt::Display, path::{Path, PathBuf}};
+
+use crate::{codegen::{ast_2_ir::Ast2Ir, bytecode, ir::Ir, ir_opcode::ConstantPool, llvm::Module, llvm_codegen::LLVMCodegen}, object_out::llvm_object::LLVMObject, token::TokenData, Import::ModuleLoader, Preprocessor::Preprocessor, Value::Value, VM::{error::VMError, native::NativeFn, vm::VM}, AST::{ast_checker::Checker, expr_node::Expr, AST}, CHeader, ObjectArch};
+
+/// Script source, text is treated as a file in the working directory
+#[derive(Debug, Clone, Copy)]
+pub enum Source<'a> {
+    Text(&'a str),
+    File(&'a Path)
+}
+
+impl<'a> From<&'a str> for Source<'a> {
+    fn from(s: &'a str) -> Self {
+        Source::Text(s)
+    }
+}
+
+impl<'a> From<&'a Path> for Source<'a> {
+    fn from(p: &'a Path) -> Self {
+        Source::File(p)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    pub arch: ObjectArch,
+    pub output: PathBuf,
+    /// also write a C header with the functions and globals of the script
+    pub emit_header: Option<PathBuf>,
+    /// print the checked AST and the LLVM module
+    pub dump: bool
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self { arch: ObjectArch::X64, output: PathBuf::from("test.o"), emit_header: None, dump: false }
+    }
+}
+
+#[derive(Debug)]
+pub enum EngineError {
+    /// the script did not load, parse or check, or the object file was not written
+    Compile(String),
+    Runtime(VMError)
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineError::Compile(e) => write!(f, "{}", e),
+            EngineError::Runtime(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+impl From<VMError> for EngineError {
+    fn from(e: VMError) -> Self {
+        EngineError::Runtime(e)
+    }
+}
+
+pub struct Engine {
+    include_paths: Vec<PathBuf>,
+    defines: HashMap<String, Vec<TokenData>>,
+    vm: VM
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self { include_paths: Vec::new(), defines: HashMap::new(), vm: VM::new(ConstantPool::new()) }
+    }
+
+    /// Add a directory to the module search path
+    pub fn include(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.include_paths.push(dir.into());
+        self
+    }
+
+    /// Define a preprocessor symbol, `NAME` or `NAME=value`
+    pub fn define(&mut self, def: &str) -> Result<&mut Self, EngineError> {
+        let (name, value) = Preprocessor::parse_define(def).map_err(EngineError::Compile)?;
+        self.defines.insert(name, value);
+        Ok(self)
+    }
+
+    /// Make the symbols of a shared library available to `extern func`s
+    pub fn load_library(&mut self, path: &str) -> Result<(), EngineError> {
+        self.vm.load_library(path).map_err(EngineError::Compile)
+    }
+
+    /// Let scripts call `func` as `name`, with `arity` arguments of any type
+    pub fn register_native(&mut self, name: &str, arity: usize, func: NativeFn) -> &mut Self {
+        self.vm.register_native(name, arity, func);
+        self
+    }
+
+    /// Load, parse and check a script. The natives are only declared for the VM
+    fn check<'a>(&self, source: Source<'a>, natives: bool) -> Result<Vec<Expr>, EngineError> {
+        let loader = ModuleLoader::new(self.include_paths.clone(), self.defines.clone());
+        let meta_data = match source {
+            Source::Text(s) => loader.load_source(s, Path::new(".")),
+            Source::File(p) => loader.load(p)
+        }.map_err(|e| EngineError::Compile(e.to_string()))?;
+        let filename = meta_data.filename.clone();
+        let ast_tree = AST::new(meta_data).parse().map_err(EngineError::Compile)?;
+
+        let mut c = Checker::new(&ast_tree);
+        c.set_source(&filename);
+        if natives {
+            c.declare_natives(self.vm.native_headers());
+        }
+        c.check().map_err(EngineError::Compile)
+    }
+
+    /// Compile a script to VM code, e.g. to save it with `bytecode::write`
+    pub fn compile<'a>(&self, source: impl Into<Source<'a>>) -> Result<Ir, EngineError> {
+        let expr = self.check(source.into(), true)?;
+        Ok(Ast2Ir::new(expr).to_ir())
+    }
+
+    fn load<'a>(&mut self, source: Source<'a>) -> Result<(), EngineError> {
+        let ir = self.compile(source)?;
+        Ok(self.vm.load(ir)?)
+    }
+
+    /// Run a script's top-level code, then its `main()` if it has one.
+    /// Gives back what `main` returned
+    pub fn eval<'a>(&mut self, source: impl Into<Source<'a>>) -> Result<Option<Value>, EngineError> {
+        self.load(source.into())?;
+        if !self.vm.has_function("main") {
+            return Ok(None);
+        }
+        Ok(self.vm.call("main", &[])?)
+    }
+
+    /// Call a function of the last evaluated script, `None` for a void function
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Option<Value>, EngineError> {
+        Ok(self.vm.call(name, args)?)
+    }
+
+    /// Run a script like `dcz --run`: `main()` is required and its return value is the exit code
+    pub fn run_main<'a>(&mut self, source: impl Into<Source<'a>>) -> Result<i32, EngineError> {
+        let ir = self.compile(source)?;
+        self.run_ir(ir)
+    }
+
+    /// Run compiled code like `run_main`, e.g. a loaded .dczc file
+    pub fn run_ir(&mut self, ir: Ir) -> Result<i32, EngineError> {
+        if !bytecode::functions(&ir.instr).iter().any(|f| f.name == "main") {
+            return Err(EngineError::Compile("No 'main' function to run".to_string()));
+        }
+        Ok(self.vm.run_main(ir)?)
+    }
+
+    /// Compile a script to a native object file through LLVM
+    pub fn compile_to_object<'a>(&self, source: impl Into<Source<'a>>, options: &CompileOptions) -> Result<(), EngineError> {
+        let source = source.into();
+        let expr = self.check(source, false)?;
+        if options.dump {
+            println!("{:#?}", expr);
+        }
+
+        let name = match source {
+            Source::Text(_) => "main".to_string(),
+            Source::File(p) => p.display().to_string()
+        };
+        if let Some(h) = &options.emit_header {
+            std::fs::write(h, CHeader::generate(&name, &expr)).map_err(|e| EngineError::Compile(e.to_string()))?;
+        }
+
+        let binding = Module::new(name);
+        let cg_c = LLVMCodegen::compile(expr, &binding);
+        cg_c.codegen_all();
+        if options.dump {
+            cg_c.get_module().dump();
+        }
+
+        LLVMObject::new(cg_c.get_module(), options.arch.clone()).write_object(&options.output).map_err(EngineError::Compile)
+    }
+}
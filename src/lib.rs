@@ -0,0 +1,27 @@
+#![allow(non_snake_case)]
+
+//! dcz as a library: `Engine` compiles and runs scripts for a host program,
+//! the `dcz` binary is a thin command line around it.
+
+pub mod object_out;
+pub mod AST;
+pub mod token;
+pub mod DataSection;
+pub mod Value;
+mod test;
+pub mod codegen;
+pub mod VM;
+pub mod MessageHandler;
+pub mod Import;
+pub mod CHeader;
+pub mod Preprocessor;
+pub mod engine;
+
+pub use engine::{CompileOptions, Engine, EngineError, Source};
+
+#[derive(Debug, Clone)]
+pub enum ObjectArch {
+    X16,
+    X32,
+    X64
+}
@@ -1,26 +1,8 @@
 #![allow(non_snake_case)]
 
-use std::{fs::File, io::Read, path::Path/*, iter::zip*/};
-use clap::Parser;
-use codegen::codegen::Codegen;
-use object_out::ObjectOut;
-//use codegen::codegen::Codegen;
-use token::Token;
-use AST::{AST as dcz_ast, ast_checker::Checker};
-
-use crate::{codegen::{llvm::Module, llvm_codegen::TypeValue}, object_out::llvm_object};
-
-//use object_out::ObjectOut;
-
-mod object_out;
-mod AST;
-mod token;
-mod DataSection;
-mod Value;
-mod test;
-mod codegen;
-mod VM;
-mod MessageHandler;
+use std::path::{Path, PathBuf};
+use clap::{Parser, ValueEnum};
+use dcz::{codegen::bytecode, CompileOptions, Engine, EngineError, ObjectArch, MessageHandler::message_handler::MessageType};
 
 
 #[derive(Parser, Debug)]
@@ -38,14 +20,41 @@ struct Cmd {
     Optimization: char,
 
     #[arg(short, default_value="x64")]
-    Architecture: String
+    Architecture: String,
+
+    #[arg(short = 'I', long = "include")]
+    /// Add a directory to the module search path.
+    include: Vec<PathBuf>,
+
+    #[arg(short = 'D', long = "define")]
+    /// Define a preprocessor symbol (NAME or NAME=value).
+    define: Vec<String>,
+
+    #[arg(short = 'l', long = "lib")]
+    /// Load a shared library for the extern functions of a script run with --run.
+    lib: Vec<String>,
+
+    #[arg(long, value_enum, default_value_t = Emit::Object)]
+    /// What to compile the file to, bytecode is written next to it as .dczc, IR is printed.
+    emit: Emit,
+
+    #[arg(long = "emit-header")]
+    /// Write a C header with the functions and globals of the file.
+    emit_header: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Print the checked AST and the LLVM module while compiling to an object.
+    dump: bool
 }
 
-#[derive(Debug, Clone)]
-enum ObjectArch {
-    X16,
-    X32,
-    X64
+#[derive(ValueEnum, Clone, Debug)]
+enum Emit {
+    /// native object file through LLVM
+    Object,
+    /// VM bytecode for `--run`
+    Bytecode,
+    /// textual VM code, also of a .dczc file
+    Ir
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>>{
@@ -62,63 +71,57 @@ fn main() -> Result<(), Box<dyn std::error::Error>>{
         }
     };
 
-    let file_io=File::open(file_path)?;
-    let t = Token::FromIO(file_path,file_io);
-    let mut p=dcz_ast::new(t?.tokenize());
-    let ast_tree = p.parse();
-
-    let mut c = Checker::new(&ast_tree);
-    let expr = c.check()?;
-    println!("{:#?}", expr);
-
-    let binding = Module::new(args.file);
-    let cg_c = codegen::llvm_codegen::LLVMCodegen::compile(expr, &binding);
-    cg_c.codegen_all();
-    cg_c.get_module().dump();
-
-    llvm_object::LLVMObject::new(cg_c.get_module(), arch).ir2obj();
-
-    
-    /*
-    let mut ast2ir = codegen::ast_2_ir::Ast2Ir::new(c.check()?);
-    let opcode_list = ast2ir.to_ir();*/
-    /*
+    let mut engine = Engine::new();
+    for dir in args.include {
+        engine.include(dir);
+    }
+    for d in &args.define {
+        engine.define(d)?;
+    }
 
+    let is_bytecode = file_path.extension().is_some_and(|e| e == bytecode::EXTENSION);
     if args.run {
-        use VM::vm;
-        vm::VM::new(ast2ir.const_pool.clone()).run(opcode_list.instr,0).expect("VMError");
-        return Ok(());
+        // interpret in the bytecode VM, no LLVM or linker involved
+        for l in &args.lib {
+            engine.load_library(l)?;
+        }
+        let result = if is_bytecode {
+            bytecode::read(file_path).map_err(EngineError::Compile).and_then(|ir| engine.run_ir(ir))
+        } else {
+            engine.run_main(file_path)
+        };
+        match result {
+            Ok(code) => std::process::exit(code),
+            Err(EngineError::Runtime(e)) => {
+                // the trace is one call per line
+                eprintln!("{}: {}\nat {}:{}", MessageType::Error, e, args.file, e.line);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                // multi-line diagnostics (macro expansion notes) are unreadable through Debug
+                eprintln!("{}: {}", MessageType::Error, e);
+                std::process::exit(1);
+            }
+        }
     }
 
-    
-    let mut code_gen = Codegen::new();
-
-    code_gen.instr(opcode_list.instr,0);
-
-    let mut obj = ObjectOut::new();
-    let mut func_vec = Vec::new();
-    for (n,o) in code_gen.func_location.iter_mut() {
-        func_vec.push((n.clone(),obj.add_func(n.as_str(), o.assemble(0)?)));
-    }
-    /*
-    if opcode.instructions().len() > 0 {
-        obj.add_func("_start",opcode.assemble(0)?);
-    }*/
-    
-    code_gen.call_location.iter().for_each(|(n,loc)| {
-        if let Some(idx) = func_vec.iter().position(|(f,_)| f == n) {
-            let (_,sym) = func_vec[idx];
-            //dbg!(loc);
-            obj.add_text_reloc(sym, *loc as u64, -4);
+    if let Emit::Bytecode | Emit::Ir = args.emit {
+        let ir = if is_bytecode { bytecode::read(file_path).map_err(EngineError::Compile) } else { engine.compile(file_path) };
+        let result = ir.and_then(|ir| match args.emit {
+            Emit::Ir => { print!("{}", ir); Ok(()) }
+            _ => bytecode::write(&file_path.with_extension(bytecode::EXTENSION), &ir).map_err(EngineError::Compile)
+        });
+        if let Err(e) = result {
+            eprintln!("{}: {}", MessageType::Error, e);
+            std::process::exit(1);
         }
-    });
-
-    code_gen.assign_location.iter().for_each(|(n,v)| {
-        obj.add_value_data(n.clone(),v.clone());
-    });
-
-    std::fs::write("output.o", obj.write_buff()).expect("Failed to save output.o");
+        return Ok(());
+    }
 
-*/
+    let options = CompileOptions { arch, emit_header: args.emit_header, dump: args.dump, ..Default::default() };
+    if let Err(e) = engine.compile_to_object(file_path, &options) {
+        eprintln!("{}: {}", MessageType::Error, e);
+        std::process::exit(1);
+    }
     Ok(())
 }
@@ -1,4 +1,4 @@
-use std::ffi::CStr;
+use std::{ffi::{CStr, CString}, path::Path};
 
 use llvm_sys_201::target_machine::{LLVMOpaqueTargetMachine, LLVMTarget, LLVMTargetRef};
 
@@ -58,18 +58,23 @@ impl<'llvm> LLVMObject<'llvm> {
             }
         }
     }
-    pub fn ir2obj(&self) {
+    /// Emit the module as an object file at `path`
+    pub fn write_object(&self, path: &Path) -> Result<(), String> {
+        let c_path = CString::new(path.to_string_lossy().as_bytes()).map_err(|e| e.to_string())?;
         unsafe {
-            let mut err: *mut i8 = std::mem::MaybeUninit::zeroed().as_mut_ptr();
-            let is_ok = llvm_sys_201::target_machine::LLVMTargetMachineEmitToFile(
+            let mut err: *mut i8 = std::ptr::null_mut();
+            let failed = llvm_sys_201::target_machine::LLVMTargetMachineEmitToFile(
                 self.target_machine,
                 self.llvm_module.module,
-                b"test.o\0".as_ptr().cast(),
+                c_path.as_ptr(),
                 llvm_sys_201::target_machine::LLVMCodeGenFileType::LLVMObjectFile,
                 &mut err
             );
-            println!("isok: {:?}", is_ok);
-            println!("e: {:?}", CStr::from_ptr(err).to_string_lossy().to_string());
+            if failed != 0 {
+                let msg = if err.is_null() { "unknown error".to_string() } else { CStr::from_ptr(err).to_string_lossy().to_string() };
+                return Err(format!("Can't write '{}': {}", path.display(), msg));
+            }
         }
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -1,11 +1,44 @@
 #[cfg(test)]
 mod test {
-    use crate::{token::{token_type::TokenType, Token, TokenData}, Value::Value};
+    use std::collections::{HashMap, HashSet};
+
+    use crate::{Engine, EngineError, codegen::{bytecode, ir::Ir, ir_text, ast_2_ir::Ast2Ir, llvm::Module, llvm_codegen::LLVMCodegen, ir_opcode::{ConstantPool, Opcode}}, VM::{error::{ErrorKind, VMError}, verify::verify, vm::VM}, CHeader, DataSection::DataSection, Import::ModuleLoader, Preprocessor::Preprocessor, token::{token_type::TokenType, Token, TokenData}, Value::Value, AST::{ast_checker::Checker, const_eval, expr_node::Expr, AST}};
+
+    fn parse(src: &str) -> Vec<Expr> {
+        AST::new(Token::new(src.to_string()).tokenize().unwrap()).parse().unwrap()
+    }
+
+    /// Parse and check, a parse error is returned like a checker error
+    fn check(src: &str) -> Result<Vec<Expr>, String> {
+        let ast = AST::new(Token::new(src.to_string()).tokenize().unwrap()).parse()?;
+        Checker::new(&ast).check()
+    }
+
+    /// Compile for the VM and run `main`
+    fn run_vm(src: &str) -> Result<i32, VMError> {
+        let mut ast2ir = Ast2Ir::new(check(src).unwrap());
+        let ir = ast2ir.to_ir();
+        VM::new(ast2ir.const_pool.clone()).run_main(ir)
+    }
+
+    /// LLVM IR of the module, every function is verified as it is lowered
+    fn llvm_ir(src: &str) -> String {
+        let module = Module::new("test".to_string());
+        LLVMCodegen::compile(check(src).unwrap(), &module).codegen_all();
+        module.print_to_string()
+    }
+
+    fn twice(args: &[Value]) -> Result<Value, VMError> {
+        match args[0] {
+            Value::Number(n) => Ok(Value::Number(n * 2)),
+            _ => Err(VMError::new(ErrorKind::Native("twice expects an int".to_string())))
+        }
+    }
 
     #[test]
     fn tokenizer_test_simple() {
         let mut t = Token::new("(()".to_string());
-        let meta_data = t.tokenize();
+        let meta_data = t.tokenize().unwrap();
         assert_eq!(meta_data.tok_data, vec![
             TokenData {
                 tok_type: TokenType::LeftParen,
@@ -13,6 +46,7 @@ mod test {
                 end:1,
                 identifier: "".to_string(),
                 line: 1,
+                col: 1,
                 value: Value::Null,
                 sub_tok: None
             },
@@ -22,6 +56,7 @@ mod test {
                 end:2,
                 identifier: "".to_string(),
                 line: 1,
+                col: 2,
                 value: Value::Null,
                 sub_tok: None
             },
@@ -31,6 +66,7 @@ mod test {
                 end: 3,
                 identifier: "".to_string(),
                 line: 1,
+                col: 3,
                 value: Value::Null,
                 sub_tok: None
             },
@@ -40,6 +76,7 @@ mod test {
                 end: 0,
                 identifier: "".to_string(),
                 line: 1,
+                col: 0,
                 value: Value::Null,
                 sub_tok: None
             }
@@ -48,7 +85,7 @@ mod test {
     #[test] 
     fn tokenizer_test_string() {
         let mut t = Token::new("\"Hello World\"".to_string());
-        let meta_data = t.tokenize();
+        let meta_data = t.tokenize().unwrap();
         assert_eq!(meta_data.tok_data, vec![
             TokenData {
                 tok_type: TokenType::String,
@@ -56,6 +93,7 @@ mod test {
                 end: 13,
                 identifier: "\"Hello World\"".to_string(),
                 line: 1,
+                col: 1,
                 value: Value::Str("Hello World".to_string()),
                 sub_tok: None
             },
@@ -65,6 +103,7 @@ mod test {
                 end: 0,
                 identifier: "".to_string(),
                 line: 1,
+                col: 0,
                 value: Value::Null,
                 sub_tok: None
             }
@@ -73,7 +112,7 @@ mod test {
     #[test] 
     fn tokenizer_test_identifier_keyword() {
         let mut t = Token::new("abcxyz".to_string());
-        let meta_data1 = t.tokenize();
+        let meta_data1 = t.tokenize().unwrap();
         assert_eq!(meta_data1.tok_data, vec![
             TokenData {
                 tok_type: TokenType::Identifier,
@@ -81,6 +120,7 @@ mod test {
                 end: 6,
                 identifier: "abcxyz".to_string(),
                 line: 1,
+                col: 1,
                 value: Value::Object("abcxyz".to_string()),
                 sub_tok: None
             },
@@ -90,12 +130,13 @@ mod test {
                 end: 0,
                 identifier: "".to_string(),
                 line: 1,
+                col: 0,
                 value: Value::Null,
                 sub_tok: None
             }
         ]);
         let mut t1 = Token::new("suu number".to_string());
-        let meta_data2 = t1.tokenize();
+        let meta_data2 = t1.tokenize().unwrap();
         assert_eq!(meta_data2.tok_data, vec![
             TokenData {
                 tok_type: TokenType::DataType,
@@ -103,6 +144,7 @@ mod test {
                 end: 3,
                 identifier: "suu".to_string(),
                 line: 1,
+                col: 1,
                 value: Value::Object("suu".to_string()),
                 sub_tok: None
             },
@@ -112,6 +154,7 @@ mod test {
                 end: 10,
                 identifier: "number".to_string(),
                 line: 1,
+                col: 5,
                 value: Value::Object("number".to_string()),
                 sub_tok: None
             },
@@ -121,12 +164,650 @@ mod test {
                 end: 0,
                 identifier: "".to_string(),
                 line: 1,
+                col: 0,
                 value: Value::Null,
                 sub_tok: None
             }
         ])
     }
 
+    #[test]
+    fn tokenizer_test_bool() {
+        let mut t = Token::new("bool b = true".to_string());
+        let meta_data = t.tokenize().unwrap();
+        let tok = meta_data.tok_data.iter().map(|f| f.tok_type.clone()).collect::<Vec<TokenType>>();
+        assert_eq!(tok, vec![TokenType::DataType, TokenType::Identifier, TokenType::Equal, TokenType::Boolean, TokenType::EOF]);
+        assert_eq!(meta_data.tok_data[3].value, Value::Boolean(true));
+    }
+
+    #[test]
+    fn bool_condition_test() {
+        assert!(check("func f() { bool b = true; if b { return; } while 1 < 2 { } }").is_ok());
+        assert_eq!(check("func f() { int x = 1; if x { return; } }").unwrap_err(), "Condition must be 'bool', got Int");
+        assert!(check("func f() { while 1 { } }").is_err());
+    }
+
+    #[test]
+    fn enum_discriminant_test() {
+        let expr = check("enum Color { Red, Green = 5, Blue }").unwrap();
+        let values = |n: i64| Some(Box::new(Expr::Literal(Value::Number(n))));
+        assert_eq!(expr, vec![Expr::EnumDecl("Color".to_string(), vec![
+            ("Red".to_string(), values(0)),
+            ("Green".to_string(), values(5)),
+            ("Blue".to_string(), values(6)),
+        ])]);
+
+        // discriminants are lowered as int
+        assert!(check("enum Big { A = 2147483647 }").is_ok());
+        assert_eq!(check("enum Big { A = 2147483647, B }").unwrap_err(), "Enum discriminant of 'B' is 2147483648, out of the range of int");
+        assert!(check("enum Big { A = 0 - 2147483649 }").unwrap_err().contains("out of the range of int"));
+
+        // a type name that is not a builtin must be a declared enum
+        assert!(check("enum Color { Red } func f(Color c) -> Color { return c; }").is_ok());
+        assert_eq!(check("func f(Colour c) { }").unwrap_err(), "Unknown data type 'Colour'");
+        assert_eq!(check("func f() -> Colour { }").unwrap_err(), "Unknown data type 'Colour'");
+        assert_eq!(check("func f() { Colour c; }").unwrap_err(), "Unknown data type 'Colour'");
+    }
+
+    #[test]
+    fn const_eval_test() {
+        let eval = |src: &str| const_eval::eval(&parse(src)[0], &|_| None);
+        assert_eq!(eval("(1 << 3) % 5 + 2 * 3;"), Ok(Value::Number(9)));
+        assert_eq!(eval("1 < 2 && 3 != 4;"), Ok(Value::Boolean(true)));
+        assert_eq!(eval("false && 1 / 0 == 0;"), Ok(Value::Boolean(false)));
+        assert!(eval("7 % 0;").is_err());
+        assert!(eval("0x7fffffffffffffff + 1;").is_err());
+    }
+
+    #[test]
+    fn global_test() {
+        // unused globals are dropped, so `main` touches each one
+        let src = "float f = 1.5; suu d = 1.5 * 2; int n = 2 + 3; float h = 1; char* s = \"hi\";
+            func main() -> int { f = 2.5; d = d; h = h; char c = s[0]; c = 'x'; return n; }";
+        let inits = check(src).unwrap().into_iter().filter_map(|e| match e {
+            Expr::VarDecl(_, _, _, _, init, _) => Some(*init.unwrap()),
+            _ => None
+        }).collect::<Vec<Expr>>();
+        assert_eq!(inits, vec![
+            Expr::Literal(Value::Float(1.5)),
+            Expr::Literal(Value::Double(3.0)),
+            Expr::Literal(Value::Number(5)),
+            Expr::Literal(Value::Float(1.0)),
+            Expr::Literal(Value::Str("hi".to_string())),
+        ]);
+        assert!(check("func f() -> int { return 1; } int x = f(); func g() -> int { return x; }").unwrap_err().starts_with("Global variable 'x' initializer must be a constant expression"));
+
+        let ir = llvm_ir(src);
+        for global in ["@f = global float 1.500000e+00", "@d = global double 3.000000e+00", "@n = global i32 5", "@h = global float 1.000000e+00", "c\"hi\\00\"", "store i8 120"] {
+            assert!(ir.contains(global), "{} not in\n{}", global, ir);
+        }
+    }
+
+    #[test]
+    fn llvm_compare_test() {
+        let src = "func main() -> int {
+                int a = 1; float x = 1.5;
+                bool b = x < 2.5 && a > 0 || x >= 3;
+                if b { return 1; }
+                return 0;
+            }";
+        let ir = llvm_ir(src);
+        for needle in ["fcmp olt float", "fcmp oge float", "icmp sgt i32", "phi i1 [ false,", "phi i1 [ true,"] {
+            assert!(ir.contains(needle), "{} not in\n{}", needle, ir);
+        }
+    }
+
+    #[test]
+    fn import_test() {
+        let dir = std::env::temp_dir().join(format!("dcz_import_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(dir.join("lib/math.dcz"), "func one() -> int { return 1; }").unwrap();
+        std::fs::write(dir.join("main.dcz"), "use math; func main() -> int { return math::one(); }").unwrap();
+
+        let meta_data = ModuleLoader::new(vec![dir.join("lib")], HashMap::new()).load(&dir.join("main.dcz")).unwrap();
+        let idents = meta_data.tok_data.iter()
+            .filter(|t| t.tok_type == TokenType::Identifier)
+            .map(|t| t.identifier.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(idents, vec!["math::one", "return", "main", "return", "math::one"]);
+
+        std::fs::write(dir.join("lib/math.dcz"), "import \"../main.dcz\";").unwrap();
+        let err = ModuleLoader::new(vec![dir.join("lib")], HashMap::new()).load(&dir.join("main.dcz")).unwrap_err();
+        assert!(err.to_string().starts_with("Import cycle detected"));
+
+        // same file stem in two directories, `use` keeps them apart by their path
+        for d in ["a", "b"] {
+            std::fs::create_dir_all(dir.join("lib").join(d)).unwrap();
+            std::fs::write(dir.join("lib").join(d).join("util.dcz"), format!("func id() -> int {{ return {}; }}", d.len())).unwrap();
+        }
+        std::fs::write(dir.join("main.dcz"), "use a::util; use b::util; func main() -> int { return a::util::id() + b::util::id(); }").unwrap();
+        let meta_data = ModuleLoader::new(vec![dir.join("lib")], HashMap::new()).load(&dir.join("main.dcz")).unwrap();
+        let names = meta_data.tok_data.iter().filter(|t| t.identifier.ends_with("::id")).map(|t| t.identifier.as_str()).collect::<HashSet<_>>();
+        assert_eq!(names, HashSet::from(["a::util::id", "b::util::id"]));
+
+        std::fs::write(dir.join("main.dcz"), "import \"a/util.dcz\"; import \"b/util.dcz\";").unwrap();
+        let err = ModuleLoader::new(vec![dir.join("lib")], HashMap::new()).load(&dir.join("main.dcz")).unwrap_err();
+        assert!(err.to_string().contains("are both namespaced 'util'"), "{}", err);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn c_header_test() {
+        let externs = CHeader::import("
+            #include <stddef.h>
+            typedef struct _IO_FILE FILE; /* opaque */
+            extern int puts (const char *__s);
+            FILE *fopen(const char *path, const char *mode);
+            size_t strlen(const char *);
+            int printf(const char *fmt, ...);
+            static inline int twice(int x) { return x * 2; }
+            void free(void *ptr);
+        ");
+        assert_eq!(CHeader::to_dcz(&externs), "\
+            extern func puts(char* s) -> int;\n\
+            extern func fopen(char* path, char* mode) -> void*;\n\
+            extern func strlen(char* arg0) -> long;\n\
+            extern func printf(char* fmt) -> int;\n\
+            extern func free(void* ptr);\n");
+
+        let header = CHeader::generate("math.dcz", &check("func add(int a, int b) -> int { return 1; }").unwrap());
+        assert!(header.contains("#ifndef MATH_DCZ_H"));
+        assert!(header.contains("int32_t add(int32_t a, int32_t b);"));
+    }
+
+    #[test]
+    fn preprocessor_test() {
+        let run = |defines: &[&str]| {
+            let src = "#!define N 2 + 1\n#!if DEBUG && LEVEL > 1\nN;\n#!elif DEBUG\n1;\n#!else\n0;\n#!endif\n";
+            let defines = defines.iter().map(|d| Preprocessor::parse_define(d).unwrap()).collect();
+            Preprocessor::new(defines, Vec::new())
+                .process(Token::new(src.to_string()).tokenize().unwrap().tok_data, std::path::Path::new("test.dcz"), &mut DataSection::new())
+                .unwrap()
+                .iter()
+                .map(|t| t.tok_type.clone())
+                .collect::<Vec<TokenType>>()
+        };
+        assert_eq!(run(&[]), vec![TokenType::Number, TokenType::Semicolon, TokenType::EOF]);
+        assert_eq!(run(&["DEBUG", "LEVEL=3"]), vec![TokenType::Number, TokenType::Plus, TokenType::Number, TokenType::Semicolon, TokenType::EOF]);
+
+        let unterminated = Token::new("#!if A\n".to_string()).tokenize().unwrap().tok_data;
+        assert!(Preprocessor::new(HashMap::new(), Vec::new()).process(unterminated, std::path::Path::new("test.dcz"), &mut DataSection::new()).is_err());
+
+        for cond in ["#!if 1 +\n0;\n#!endif\n", "#!if (1\n0;\n#!endif\n", "#!if 1 2\n0;\n#!endif\n"] {
+            let malformed = Token::new(cond.to_string()).tokenize().unwrap().tok_data;
+            assert!(Preprocessor::new(HashMap::new(), Vec::new()).process(malformed, std::path::Path::new("test.dcz"), &mut DataSection::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn macro_expansion_test() {
+        let expand = |src: &str| {
+            Preprocessor::new(HashMap::new(), Vec::new())
+                .process(Token::new(src.to_string()).tokenize().unwrap().tok_data, std::path::Path::new("test.dcz"), &mut DataSection::new())
+                .map(|t| t.iter().map(|t| match t.tok_type {
+                    TokenType::Number | TokenType::Identifier => t.identifier.clone(),
+                    TokenType::Star => "*".to_string(),
+                    TokenType::Plus => "+".to_string(),
+                    TokenType::Slash => "/".to_string(),
+                    TokenType::LeftParen => "(".to_string(),
+                    TokenType::RightParen => ")".to_string(),
+                    _ => String::new()
+                }).collect::<String>())
+        };
+        assert_eq!(expand("#!macro sq(x) = x * x\n#!macro add(a, b) = a + b\nadd(sq(1 + 2), 3)"), Ok("(((1+2)*(1+2))+3)".to_string()));
+        assert_eq!(expand("#!macro sq(x) = x * x\n10 / sq(2)"), Ok("10/(2*2)".to_string()));
+        assert_eq!(Engine::new().eval("#!macro sq(x) = x * x\nfunc main() -> int { return 10 / sq(2); }").unwrap(), Some(Value::Number(2)));
+
+        // the body's `step` is the global one, a local `step` at the call site would capture it
+        let step = "#!macro inc(v) = v + step\nint step = 1;\n";
+        assert!(expand(&format!("{}func f() -> int {{ int step = 5; return inc(2); }}", step)).unwrap_err()
+            .contains("Macro 'inc' uses 'step', which is declared again at the call site (line 3)"));
+        assert!(expand(&format!("{}func f(int step) -> int {{ return inc(2); }}", step)).is_err());
+        assert!(expand(&format!("{}func f() -> int {{ if true {{ int step = 5; }} return inc(2); }}", step)).is_ok());
+        assert!(expand(&format!("{}func g() {{ int step = 5; }}\nint x = inc(2);", step)).is_ok());
+        assert!(expand("#!macro f(a) = f(a)\nf(1)").unwrap_err().contains("Recursive expansion of macro 'f'"));
+        assert!(expand("#!macro f(a) = a\nf(1, 2)").unwrap_err().contains("defined at test.dcz:1"));
+    }
+
+    #[test]
+    fn switch_test() {
+        let check_body = |body: &str| check(&format!("enum Color {{ Red, Green }}\nfunc f(int n, bool b, Color c, suu d) -> int {{ {} return 0; }}", body));
+        assert_eq!(check_body("switch n { case 1: return 1; case 1: return 2; }").unwrap_err(), "Duplicate case label '1' in switch");
+        assert_eq!(check_body("switch c { case Red: return 1; case Red: return 2; }").unwrap_err(), "Duplicate case label 'Red' in switch");
+        assert!(check_body("switch n { case 'a': return 1; case 2: return 2; }").is_ok());
+        assert!(check_body("switch b { case true: return 1; default: return 2; }").is_ok());
+        assert_eq!(check_body("switch n { case true: return 1; }").unwrap_err(), "Case label 'true' is Bool, but the switch value is Int");
+        assert_eq!(check_body("switch n { case Red: return 1; }").unwrap_err(), "Case label 'Red' is Enum(\"Color\"), but the switch value is Int");
+        assert_eq!(check_body("switch b { case 1: return 1; }").unwrap_err(), "Case label '1' is Int, but the switch value is Bool");
+        assert_eq!(check_body("switch c { case 0: return 1; }").unwrap_err(), "Case label '0' is not a variant of enum 'Color'");
+        assert!(check_body("switch d { case 1: return 1; }").unwrap_err().starts_with("Cannot switch on Suu"));
+
+        // the jump table is sorted by label, the bodies stay in source order
+        let src = "func pick(int n) -> int { switch n { case 30: return 3; case 10: return 1; case 20: return 2; default: return 0; } }
+            func main() -> int { return pick(10) + pick(20) * 10 + pick(30) * 100 + pick(40) * 1000; }";
+        let ir = Ast2Ir::new(check(src).unwrap()).to_ir();
+        let table = ir.instr.iter().find_map(|o| match o {
+            Opcode::Switch(table, default) => Some((table.iter().map(|(k, _)| *k).collect::<Vec<i64>>(), table[1].1 < table[0].1, *default > table[0].1)),
+            _ => None
+        });
+        assert_eq!(table, Some((vec![10, 20, 30], false, true)));
+        assert_eq!(run_vm(src).unwrap(), 321);
+    }
+
+    #[test]
+    fn ternary_test() {
+        let Expr::Statement(e) = parse("a || b ? 1 : c ? 2 : 3;").remove(0) else { panic!("expect statement") };
+        let Expr::Ternary(cond, _, else_e) = *e else { panic!("expect ternary") };
+        assert!(matches!(*cond, Expr::Binary(_, _, _)));
+        assert!(matches!(*else_e, Expr::Ternary(_, _, _)));
+
+        assert_eq!(const_eval::eval(&parse("1 < 2 ? 3 : 4;")[0], &|_| None), Ok(Value::Number(3)));
+    }
+
+    #[test]
+    fn compound_assign_test() {
+        let types = Token::new("a <<= b >>= c ^= d-- ++e".to_string()).tokenize().unwrap().tok_data
+            .into_iter().map(|t| t.tok_type).collect::<Vec<_>>();
+        assert_eq!(types[..9], [TokenType::Identifier, TokenType::ShiftLeftEqual, TokenType::Identifier, TokenType::ShiftRightEqual,
+            TokenType::Identifier, TokenType::CaretEqual, TokenType::Identifier, TokenType::MinusMinus, TokenType::PlusPlus]);
+
+        let Expr::Statement(e) = parse("x *= y + 1;").remove(0) else { panic!("expect statement") };
+        let Expr::Assign(n, v, _) = *e else { panic!("expect assign") };
+        assert_eq!(n, "x");
+        let Expr::Binary(lhs, op, rhs) = *v else { panic!("expect binary") };
+        assert_eq!((*lhs, op.tok_type), (Expr::Var("x".to_string()), TokenType::Star));
+        assert!(matches!(*rhs, Expr::Binary(_, _, _)));
+
+        for (src, op) in [("x++;", TokenType::Plus), ("++x;", TokenType::Plus), ("x--;", TokenType::Minus)] {
+            let Expr::Statement(e) = parse(src).remove(0) else { panic!("expect statement") };
+            // the postfix forms evaluate to the old value
+            let e = match *e { Expr::Postfix(a) => a, e => Box::new(e) };
+            let Expr::Assign(_, v, _) = *e else { panic!("expect assign") };
+            let Expr::Binary(_, bin_op, one) = *v else { panic!("expect binary") };
+            assert_eq!((bin_op.tok_type, *one), (op, Expr::Literal(Value::Number(1))));
+        }
+    }
+
+    #[test]
+    fn assign_target_test() {
+        let mut engine = Engine::new();
+        let src = "func main() -> int {
+            int x = 1; int y = x++; int z = ++x;
+            int a = 0; int b = 0; b = a = 5;
+            char* s = \"hello\"; s[0] = 'j'; *s += 1; s[4]++;
+            assert(s == \"kellp\");
+            return y * 100 + z * 10 + b;
+        }";
+        assert_eq!(engine.eval(src).unwrap(), Some(Value::Number(135)));
+
+        // a pointer argument is the caller's string, not a copy of it
+        let src = "func set(char* s) { s[0] = 'x'; }
+            func main() -> int { char* s = \"abc\"; char* t = s; set(t); return s[0]; }";
+        assert_eq!(engine.eval(src).unwrap(), Some(Value::Char('x')));
+
+        let ir = llvm_ir("func main() -> float { float x = 1; x += 1.5; return x; }");
+        assert!(ir.contains("fadd float"), "{}", ir);
+
+        assert_eq!(check("func f() { 1 = 2; }").unwrap_err(), "Invalid assignment target\nat stdin:1:14");
+        assert!(check("func f() { int x = 0; x.y = 1; }").unwrap_err().starts_with("Fields are not supported"));
+        assert_eq!(check("func f() { int x = 0; x[0] = 1; }").unwrap_err(), "Cannot index 'x', it is not a pointer");
+        assert_eq!(check("func f(int* p) { p[true] = 1; }").unwrap_err(), "Index of 'p' must be an integer, got Bool");
+        assert!(check("func f(int* p) { *p = p[1]; }").is_ok());
+    }
+
+    #[test]
+    fn bitwise_test() {
+        let eval = |src: &str| const_eval::eval(&parse(src)[0], &|_| None);
+        // & before ^ before |
+        assert_eq!(eval("6 ^ 3 | 1 & ~0;"), Ok(Value::Number(5)));
+        assert_eq!(eval("1 | 2 ^ 3;"), Ok(Value::Number(1)));
+        assert_eq!(eval("~5;"), Ok(Value::Number(-6)));
+        assert_eq!(eval("true ^ true;"), Ok(Value::Boolean(false)));
+        assert!(eval("~true;").is_err());
+
+        // the VM flips integers only, anything else is a typed error
+        assert_eq!(Engine::new().eval("func main() -> int { int x = 5; return ~x; }").unwrap(), Some(Value::Number(-6)));
+        let run = |v: Value| VM::new(ConstantPool::new()).run(vec![Opcode::Constant(v), Opcode::BitNot], 0);
+        assert!(run(Value::Number(5)).is_ok());
+        assert_eq!(run(Value::Boolean(true)).unwrap_err().kind, ErrorKind::TypeMismatch("Tilde on bool".to_string()));
+        assert_eq!(run(Value::Double(1.5)).unwrap_err().kind, ErrorKind::TypeMismatch("Tilde on suu".to_string()));
+    }
+
+    #[test]
+    fn return_path_test() {
+        let checked = check("func f() -> int { if 1 > 2 { return 1; } else { return 2; } return 3; return 4; }").unwrap();
+        let Expr::FuncStmt(_, body) = &checked[0] else { panic!("expect function") };
+        let Expr::Block(body) = &**body else { panic!("expect block") };
+        // `return 3; return 4;` are unreachable, the folded if already returns
+        assert_eq!(body.len(), 1);
+        assert!(matches!(body[0], Expr::Block(_)));
+
+        check("func g() -> int { while true { } } func h() { return; }").unwrap();
+
+        // locals of a loop body get their stack slot once, in the entry block
+        let ir = llvm_ir("func f(int n) -> int { int i = 0; while i < n { int t = i; i = t + 1; } return i; }");
+        let entry = &ir[..ir.find("br label").unwrap()];
+        assert_eq!(entry.matches("alloca").count(), 3);
+        assert_eq!(ir.matches("alloca").count(), 3);
+    }
+
+    #[test]
+    fn forward_reference_test() {
+        let src = "
+            func main() -> int { return even(10); }
+            func even(int n) -> int { return n == 0 ? 1 : odd(n - 1); }
+            func odd(int n) -> int { return n == 0 ? 0 : even(n - 1); }
+        ";
+        let checked = check(src).unwrap();
+        let names = checked.iter().map(|e| e.get_function().0).collect::<Vec<_>>();
+        assert_eq!(names, ["main", "even", "odd"]);
+
+        // the VM calls by name, so a call compiled before its callee still resolves
+        assert_eq!(run_vm(src).unwrap(), 1);
+
+        // LLVM declares every function before the first body, `main` calls `even` defined below it
+        let ir = llvm_ir(src);
+        let main = &ir[ir.find("define i32 @main").unwrap()..ir.find("define i32 @even").unwrap()];
+        assert!(main.contains("call i32 @even(i32 10)"), "{}", ir);
+        assert!(!ir.contains("declare"), "{}", ir);
+    }
+
+    #[test]
+    fn vm_run_test() {
+        let src = "
+            int g = 4;
+            func seven() -> int { int a = 3; return a + g; }
+            func main() -> int {
+                int x = 0;
+                int i = 0;
+                while i < 5 { x += i; i++; }
+                if x == 10 { x = x * 2; } else { x = 1; }
+                g ^= 1;
+                return x + seven() + (x > 3 ? 100 : 0);
+            }
+        ";
+        assert_eq!(run_vm(src).unwrap(), 128);
+    }
+
+    #[test]
+    fn vm_call_frame_test() {
+        let src = "
+            int calls = 0;
+            func main() -> int { int n = 10; return fib(n) + fact(5) + count(3) + calls; }
+            func fib(int n) -> int { if n < 2 { return n; } return fib(n - 1) + fib(n - 2); }
+            func fact(int n) -> int { if n <= 1 { return 1; } int m = n - 1; return n * fact(m); }
+            func count(int a) -> int { calls += a; return 0; }
+        ";
+        // the caller's `n` is untouched by the callee's `n`
+        assert_eq!(run_vm(src).unwrap(), 55 + 120 + 3);
+    }
+
+    #[test]
+    fn vm_slot_test() {
+        let src = "
+            int late = 7;
+            func main() -> int {
+                int x = 1;
+                int total = 0;
+                int i = 0;
+                while i < 3 { int y = 10; y += i; total += y; i++; }
+                { int z = 100; total += z; }
+                return total + x + late;
+            }
+        ";
+        let ir = Ast2Ir::new(check(src).unwrap()).to_ir();
+        // names are resolved at compile time, `main` is emitted before the global it reads
+        assert!(ir.instr.iter().any(|op| matches!(op, Opcode::LoadLocal(_))));
+        assert!(ir.instr.iter().any(|op| matches!(op, Opcode::LoadGlobal(0))));
+        // `y` and `z` reuse the same slot once their block ends
+        assert_eq!(ir.instr.iter().filter(|op| matches!(op, Opcode::StoreLocal(3))).count(), 3);
+        assert_eq!(run_vm(src).unwrap(), 33 + 100 + 1 + 7);
+    }
+
+    #[test]
+    fn vm_error_test() {
+        let src = "
+            func div(int a, int b) -> int {
+                return a / b;
+            }
+            func main() -> int {
+                int z = 0;
+                int w = z * 2;
+                return div(10, w);
+            }
+        ";
+        let err = run_vm(src).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::DivisionByZero);
+        assert_eq!(err.line, 3);
+        let trace = err.trace.iter().map(|t| (t.function.clone(), t.line)).collect::<Vec<_>>();
+        // the call is on a line of its own, without an operator
+        assert_eq!(trace, vec![(Some("div".to_string()), 3), (Some("main".to_string()), 8)]);
+
+        let err = VM::new(ConstantPool::new()).run(vec![Opcode::Call("nope".to_string())], 0).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UndefinedName("nope".to_string()));
+        let err = VM::new(ConstantPool::new()).run(vec![Opcode::Constant(Value::Number(1)), Opcode::Pop, Opcode::Pop], 0).unwrap_err();
+        assert_eq!((err.kind, err.ip), (ErrorKind::StackUnderflow, 2));
+
+        // `%` fails like `/`, with an error instead of a panic
+        let runtime = |src: &str| match Engine::new().eval(src) {
+            Err(EngineError::Runtime(e)) => Some(e.kind),
+            _ => None
+        };
+        assert_eq!(runtime("func main() -> int { int a = 7; int b = 0; return a % b; }"), Some(ErrorKind::DivisionByZero));
+        assert_eq!(runtime("func main() -> int { suu a = 7.5; int b = 2; return a % b; }"), Some(ErrorKind::TypeMismatch("Modulo on suu and suu".to_string())));
+    }
+
+    #[test]
+    fn vm_ffi_test() {
+        let src = "
+            extern func strlen(char* s) -> long;
+            extern func abs(int x) -> int;
+            extern func atof(char* s) -> suu;
+            func main() -> int {
+                int n = strlen(\"12\\n\");
+                if atof(\"2.5\") == 2.5 { n += 10; }
+                return n + abs(-100);
+            }
+        ";
+        // string literals keep their digits and trailing escapes
+        assert_eq!(run_vm(src).unwrap(), 3 + 10 + 100);
+
+        assert!(VM::new(ConstantPool::new()).load_library("/nonexistent/libdcz.so").is_err());
+    }
+
+    #[test]
+    fn vm_native_test() {
+        let run = |src: &str| {
+            let ast = parse(src);
+            let mut checker = Checker::new(&ast);
+            let mut headers = VM::new(ConstantPool::new());
+            headers.register_native("twice", 1, twice);
+            checker.declare_natives(headers.native_headers());
+            let mut ast2ir = Ast2Ir::new(checker.check().unwrap());
+            let ir = ast2ir.to_ir();
+            let mut vm = VM::new(ast2ir.const_pool.clone());
+            vm.register_native("twice", 1, twice);
+            vm.run_main(ir)
+        };
+        assert_eq!(run("func main() -> int { assert(len(\"abc\") == 3); return twice(20) + 2; }"), Ok(42));
+        assert_eq!(run("func main() -> int { exit(7); return twice(1); }"), Ok(7));
+        assert_eq!(run("func main() -> int { assert(1 > 2); return 0; }").unwrap_err().kind, ErrorKind::Native("assertion failed".to_string()));
+    }
+
+    #[test]
+    fn engine_send_test() {
+        // an embedder can hand the engine to a worker thread
+        fn assert_send<T: Send>() {}
+        assert_send::<Engine>();
+        assert_send::<VM>();
+    }
+
+    #[test]
+    fn engine_test() {
+        let mut engine = Engine::new();
+        engine.register_native("twice", 1, twice);
+        let src = "int base = 10;\nfunc add(int a, int b) -> int { return a + b + base; }\nfunc main() -> int { return twice(add(1, 2)); }";
+        assert_eq!(engine.eval(src).unwrap(), Some(Value::Number(26)));
+        assert_eq!(engine.call("add", &[Value::Number(3), Value::Number(4)]).unwrap(), Some(Value::Number(17)));
+        assert!(matches!(engine.call("add", &[Value::Number(3)]), Err(EngineError::Runtime(_))));
+        assert!(matches!(engine.call("sub", &[]), Err(EngineError::Runtime(VMError { kind: ErrorKind::UndefinedName(_), .. }))));
+        assert!(matches!(engine.run_main("func f() -> int { return 1; }"), Err(EngineError::Compile(_))));
+
+        // bad scripts are errors of the host, not the end of it
+        match engine.eval("func main() -> int { return 1 +; }") {
+            Err(EngineError::Compile(e)) => assert_eq!(e, "Expect Expression, got Semicolon\nat stdin:1:32"),
+            r => panic!("{:?}", r)
+        }
+        assert!(matches!(engine.eval("func main() -> int { return \"a; }"), Err(EngineError::Compile(_))));
+        assert!(matches!(engine.eval("func main() -> int { int x = 1; x = true; return x; }"), Err(EngineError::Compile(_))));
+        assert!(matches!(engine.eval("func main() -> int { return y; }"), Err(EngineError::Compile(_))));
+        assert!(engine.define("A=\"b").is_err());
+    }
+
+    #[test]
+    fn bytecode_test() {
+        let engine = Engine::new();
+        let src = "int g = 5;\nextern func abs(int n) -> int;\nfunc pick(int n) -> int { switch n { case 1: return 10; default: return abs(0 - g); } }\nfunc main() -> int { println(\"hi\"); return pick(1) + pick(2) * 1.5 > 1 ? 7 : 8; }";
+        let ir = engine.compile(src).unwrap();
+        let bytes = bytecode::serialize(&ir).unwrap();
+        let loaded = bytecode::deserialize(&bytes).unwrap();
+        assert_eq!(format!("{:?}", loaded.instr), format!("{:?}", ir.instr));
+        assert_eq!(loaded.lines, ir.lines);
+        assert_eq!(bytecode::serialize(&loaded).unwrap(), bytes);
+        assert_eq!(Engine::new().run_ir(loaded).unwrap(), 7);
+
+        assert!(bytecode::deserialize(b"DCZ").is_err());
+        assert!(bytecode::deserialize(&bytes[..bytes.len() - 1]).unwrap_err().contains("end of file"));
+        let mut newer = bytes.clone();
+        newer[4] = 99;
+        assert!(bytecode::deserialize(&newer).unwrap_err().contains("version"));
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(bytecode::deserialize(&trailing).is_err());
+
+        // the format stores indexes in 32 bits, a larger one is an error and not a wrapped index
+        let far = Ir { instr: vec![Opcode::LoadGlobal(u32::MAX as usize + 1)], lines: Vec::new(), c_pool: ConstantPool::new() };
+        assert!(bytecode::serialize(&far).unwrap_err().contains("32 bits"));
+    }
+
+    #[test]
+    fn ir_text_test() {
+        let engine = Engine::new();
+        let src = "extern func strlen(char* s) -> long;\nfunc pick(int n) -> int { switch n { case 1: return 10; default: return 2; } }\nfunc main() -> int { int i = 0; while i < 3 { i += 1; } println(\"a;b\"); return pick(i) + 'c' * 1.5 > 0.5 ? 1 : 0; }";
+        let ir = engine.compile(src).unwrap();
+        let text = ir.to_string();
+        let parsed = ir_text::parse(&text).unwrap();
+        assert_eq!(bytecode::serialize(&parsed).unwrap(), bytecode::serialize(&ir).unwrap());
+        assert_eq!(parsed.to_string(), text);
+
+        // hand written, without offsets, lines or function sizes
+        let ir = ir_text::parse("
+            .const 0 \"done\"
+            make_func main
+                const 0
+                store_local 0
+            L0:
+                load_local 0
+                const 5
+                binop <
+                jiffalse L1
+                load_local 0
+                const 2
+                binop +
+                store_local 0
+                jbackward L0
+            L1:
+                load_const 0
+                call println
+                pop
+                load_local 0
+                ret_value   ; 6
+            end_func
+        ").unwrap();
+        assert!(matches!(ir.instr[0], Opcode::MakeFunc(16, _)));
+        assert_eq!(Engine::new().run_ir(ir).unwrap(), 6);
+
+        assert!(ir_text::parse("jmp L9").unwrap_err().contains("unknown label"));
+        assert!(ir_text::parse("make_func f\nnop").unwrap_err().contains("no end_func"));
+        assert!(ir_text::parse("frob 1").unwrap_err().starts_with("line 1"));
+    }
+
+    #[test]
+    fn verify_test() {
+        let check = |text: &str| {
+            let ir = ir_text::parse(text).unwrap();
+            verify(&ir.instr, &ir.lines, &ir.c_pool, &[]).map_err(|e| (e.kind, e.ip))
+        };
+        let verify_error = |text: &str| match check(text) {
+            Err((ErrorKind::Verify(msg), ip)) => (msg, ip),
+            r => panic!("expected a verify error, got {:?}", r)
+        };
+        assert_eq!(check("make_func f\nstore_param int a\nload_local 0\nret_value\nend_func\nconst 1\ncall f\npop"), Ok(()));
+
+        assert!(verify_error("jmp 5\nnop").0.contains("leaves its function"));
+        assert!(verify_error("nop\njbackward 3").0.contains("before the start"));
+        assert!(verify_error("make_func f\njmp 4\nend_func\nnop\nnop").0.contains("leaves its function"));
+        assert_eq!(verify_error("const true\njiffalse 1\nconst 1\nnop").1, 2);
+        assert!(verify_error("begin\nnop").0.contains("open blocks"));
+        assert!(verify_error("end").0.contains("END without BEGIN"));
+        // the parser already rejects unbalanced functions
+        let raw = |code: Vec<Opcode>| verify(&code, &[], &ConstantPool::new(), &[]).unwrap_err().kind;
+        assert!(matches!(raw(vec![Opcode::MakeFunc(2, "f".to_string()), Opcode::ReturnValue, Opcode::EndFunc]), ErrorKind::Verify(m) if m.contains("END_FUNC")));
+        assert!(matches!(raw(vec![Opcode::Nop, Opcode::EndFunc]), ErrorKind::Verify(m) if m.contains("without MAKEFUNC")));
+        assert!(verify_error("make_func f\nconst true\njiffalse 1\nret 1\nend_func").0.contains("without returning"));
+        assert_eq!(check("const 1\npop\npop"), Err((ErrorKind::StackUnderflow, 2)));
+        assert_eq!(check("nop\ninvalid"), Err((ErrorKind::InvalidOpcode("[INVAILD]".to_string()), 1)));
+        assert_eq!(check("make_func f\nret\ninvalid\nend_func"), Ok(()));
+        assert_eq!(check("load_const 0"), Err((ErrorKind::InvalidOpcode("[LOADCONSTANT (idx: 0)]".to_string()), 0)));
+
+        // values of expression statements are dropped, loops keep the stack balanced
+        let mut engine = Engine::new();
+        let src = "func f(int x) -> int { return x * 2; }\nfunc main() -> int { int i = 0; while i < 3 { f(i); len(\"ab\"); i += 1; } while true { return i; } }";
+        assert_eq!(engine.eval(src).unwrap(), Some(Value::Number(3)));
+    }
+
+    /// Fully parenthesized form of an expression, operators are printed by token type
+    fn sexpr(e: &Expr) -> String {
+        match e {
+            Expr::Statement(e) | Expr::Grouping(e) => sexpr(e),
+            Expr::Var(n) => n.clone(),
+            Expr::Literal(v) => v.clone().to_literal().to_string(),
+            Expr::Unary(op, rhs) => format!("({:?} {})", op.tok_type, sexpr(rhs)),
+            Expr::Binary(lhs, op, rhs) => format!("({:?} {} {})", op.tok_type, sexpr(lhs), sexpr(rhs)),
+            Expr::Ternary(c, a, b) => format!("(? {} {} {})", sexpr(c), sexpr(a), sexpr(b)),
+            Expr::Assign(n, v, _) => format!("(= {} {})", n, sexpr(v)),
+            e => panic!("unexpected {:?}", e)
+        }
+    }
+
+    #[test]
+    fn precedence_test() {
+        let corpus = [
+            ("a << 1 < b", "(Less (ShiftLeft a 1) b)"),
+            ("x & 1 == 0", "(And x (EqualEqual 1 0))"),
+            ("a + b * c - d", "(Minus (Plus a (Star b c)) d)"),
+            ("a - b - c", "(Minus (Minus a b) c)"),
+            ("a / b % c", "(Modulo (Slash a b) c)"),
+            ("-a * ~b", "(Star (Minus a) (Tilde b))"),
+            ("!a == b", "(EqualEqual (Not a) b)"),
+            ("a < b == c > d", "(EqualEqual (Less a b) (Greater c d))"),
+            ("a | b ^ c & d", "(Or a (Caret b (And c d)))"),
+            ("a || b && c", "(OrBool a (AndBool b c))"),
+            ("a && b | c", "(AndBool a (Or b c))"),
+            ("a >> 1 + 2", "(ShiftRight a (Plus 1 2))"),
+            ("(a + b) * c", "(Star (Plus a b) c)"),
+            ("a ? b : c ? d : e", "(? a b (? c d e))"),
+            ("a || b ? c = 1 : d", "(? (OrBool a b) (= c 1) d)"),
+            ("a = b = c + 1", "(= a (= b (Plus c 1)))"),
+            ("a += b ? 1 : 2", "(= a (Plus a (? b 1 2)))"),
+            ("a <<= 1 | 2", "(= a (ShiftLeft a (Or 1 2)))"),
+        ];
+        for (src, tree) in corpus {
+            assert_eq!(sexpr(&parse(&format!("{};", src))[0]), tree, "{}", src);
+        }
+    }
+
     #[test]
     fn value_test() {
         let v = Value::new("1".to_string());
@@ -1,8 +1,8 @@
-use std::{fs::File,io::Read, path::Path, process::exit};
+use std::{fs::File,io::Read, path::Path};
 
 use token_type::TokenType;
 
-use crate::{DataSection::DataSection, MessageHandler::message_handler::{self, throw_message}, Value::Value};
+use crate::{DataSection::DataSection, MessageHandler::message_handler::located, Value::Value};
 pub mod token_type;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -11,6 +11,8 @@ pub struct TokenData {
     pub start: usize,
     pub end: usize,
     pub line: usize,
+    /// column of `start` in its line, from 1
+    pub col: usize,
     pub identifier: String,
     pub value: Value,
     pub sub_tok: Option<Vec<TokenData>>
@@ -32,6 +34,8 @@ pub struct Token {
     start: usize,
     line: usize,
     data: DataSection,
+    /// first lexing error, stops `tokenize`
+    error: Option<String>,
     pub source_file_name:String,
 }
 
@@ -67,13 +71,13 @@ fn fmt_escape(string: String) -> String {
 
 impl Token {
     pub fn new(code: String) -> Self {
-        Self { code: code, current:0,start:0, line:1, data: DataSection::new(), source_file_name: "stdin".to_string(), at:0 }
+        Self { code: code, current:0,start:0, line:1, data: DataSection::new(), error: None, source_file_name: "stdin".to_string(), at:0 }
     }
 
     pub fn FromIO(p: &Path, mut fileio: File) -> Result<Self,Box<dyn std::error::Error>> {
         let mut file_content = String::new();
         fileio.read_to_string(&mut file_content)?;
-        Ok(Self { code: file_content,current:0,start:0, line:1, data: DataSection::new(), source_file_name: p.display().to_string(), at:0 })
+        Ok(Self { code: file_content,current:0,start:0, line:1, data: DataSection::new(), error: None, source_file_name: p.display().to_string(), at:0 })
     }
 
     fn is_eof(&self) -> bool {
@@ -85,17 +89,28 @@ impl Token {
     }
 
     fn To_TokenData_Identifier(&self, tok_type: TokenType, identifier: String) -> TokenData {
-        TokenData { tok_type: tok_type, start: self.start, end: self.current, identifier: identifier.clone(), line: self.line , value: Value::new(identifier), sub_tok: None}
+        TokenData { tok_type: tok_type, start: self.start, end: self.current, identifier: identifier.clone(), line: self.line, col: self.col(), value: Value::new(identifier), sub_tok: None}
     }    
     fn To_TokenData_String(&self, string_literal: String) -> TokenData {
-        TokenData { tok_type: TokenType::String, start: self.start, end: self.current, identifier: string_literal.clone(), line: self.line , value: Value::new(string_literal[1..string_literal.len()-1].to_string()),sub_tok: None}
+        TokenData { tok_type: TokenType::String, start: self.start, end: self.current, identifier: string_literal.clone(), line: self.line, col: self.col(), value: Value::Str(string_literal[1..string_literal.len()-1].to_string()),sub_tok: None}
     }
     fn To_TokenData_Obj(&self, tok_type: TokenType, identifier: String) -> TokenData {
-        TokenData { tok_type: tok_type, start: self.start, end: self.current, identifier: identifier.clone(), line: self.line , value: Value::new_obj(identifier.trim().to_string()),sub_tok: None}
+        TokenData { tok_type: tok_type, start: self.start, end: self.current, identifier: identifier.clone(), line: self.line, col: self.col(), value: Value::new_obj(identifier.trim().to_string()),sub_tok: None}
     }
     fn To_TokenData_SubToken(&self, tok_type: TokenType, sub_tok: Vec<TokenData>) -> TokenData {
-        TokenData { tok_type: tok_type, start: self.start, end: self.current, line: self.line, identifier: String::new(), value: Value::Null, sub_tok: Some(sub_tok) }
+        TokenData { tok_type: tok_type, start: self.start, end: self.current, line: self.line, col: self.col(), identifier: String::new(), value: Value::Null, sub_tok: Some(sub_tok) }
     }
+    /// Column of the token being scanned, `at` is the column after it
+    fn col(&self) -> usize {
+        (self.at + 1).saturating_sub(self.current - self.start)
+    }
+
+    /// Record a lexing error at the current position, the token is dropped
+    fn error(&mut self, message: &str) -> Option<TokenData> {
+        self.error.get_or_insert(located(&self.source_file_name, self.line as i64, self.col() as i64, message));
+        None
+    }
+
     fn advance(&mut self) -> char {
         self.current+=1;
         self.at+=1;
@@ -138,18 +153,48 @@ impl Token {
             '}' => Some(self.ToTokenData_Symbol(TokenType::RightBrace)),
             '[' => Some(self.ToTokenData_Symbol(TokenType::LeftBracket)),
             ']' => Some(self.ToTokenData_Symbol(TokenType::RightBracket)),
-            '+' => Some(self.ToTokenData_Symbol(TokenType::Plus)),
+            '+' => {
+                if self.match_chr('+') {
+                    Some(self.ToTokenData_Symbol(TokenType::PlusPlus))
+                } else if self.match_chr('=') {
+                    Some(self.ToTokenData_Symbol(TokenType::PlusEqual))
+                } else {
+                    Some(self.ToTokenData_Symbol(TokenType::Plus))
+                }
+            },
             '-' => {
                 if self.match_chr('>') {
                     Some(self.ToTokenData_Symbol(TokenType::PointTo))
+                } else if self.match_chr('-') {
+                    Some(self.ToTokenData_Symbol(TokenType::MinusMinus))
+                } else if self.match_chr('=') {
+                    Some(self.ToTokenData_Symbol(TokenType::MinusEqual))
                 }else {
                     Some(self.ToTokenData_Symbol(TokenType::Minus))
                 }
             },
-            '*' => Some(self.ToTokenData_Symbol(TokenType::Star)),
-            '/' => Some(self.ToTokenData_Symbol(TokenType::Slash)),
+            '*' => {
+                if self.match_chr('=') {
+                    Some(self.ToTokenData_Symbol(TokenType::StarEqual))
+                } else {
+                    Some(self.ToTokenData_Symbol(TokenType::Star))
+                }
+            },
+            '/' => {
+                if self.match_chr('=') {
+                    Some(self.ToTokenData_Symbol(TokenType::SlashEqual))
+                } else {
+                    Some(self.ToTokenData_Symbol(TokenType::Slash))
+                }
+            },
             ';' => Some(self.ToTokenData_Symbol(TokenType::Semicolon)),
-            ':' => Some(self.ToTokenData_Symbol(TokenType::Colon)),
+            ':' => {
+                if self.match_chr(':') {
+                    Some(self.ToTokenData_Symbol(TokenType::ColonColon))
+                } else {
+                    Some(self.ToTokenData_Symbol(TokenType::Colon))
+                }
+            },
             '=' => {
                 if self.match_chr('=') {
                         Some(self.ToTokenData_Symbol(TokenType::EqualEqual))
@@ -160,6 +205,9 @@ impl Token {
                 '>' => {
                     if self.match_chr('=') {
                         Some(self.ToTokenData_Symbol(TokenType::GreaterEqual))
+                    } else if self.peek() == '>' && self.peek_next() == '=' {
+                        self.match_str(">=");
+                        Some(self.ToTokenData_Symbol(TokenType::ShiftRightEqual))
                     } else if self.match_chr('>') {
                         Some(self.ToTokenData_Symbol(TokenType::ShiftRight))
                     } else {
@@ -169,6 +217,8 @@ impl Token {
                 '&' => {
                     if self.match_chr('&') {
                         Some(self.ToTokenData_Symbol(TokenType::AndBool))
+                    } else if self.match_chr('=') {
+                        Some(self.ToTokenData_Symbol(TokenType::AndEqual))
                     } else {
                         Some(self.ToTokenData_Symbol(TokenType::And))
                     }
@@ -176,6 +226,8 @@ impl Token {
                 '|' => {
                     if self.match_chr('|') {
                         Some(self.ToTokenData_Symbol(TokenType::OrBool))
+                    } else if self.match_chr('=') {
+                        Some(self.ToTokenData_Symbol(TokenType::OrEqual))
                     } else {
                         Some(self.ToTokenData_Symbol(TokenType::Or))
                     }
@@ -183,6 +235,9 @@ impl Token {
                 '<' => {
                     if self.match_chr('=') {
                         Some(self.ToTokenData_Symbol(TokenType::LessEqual))
+                    } else if self.peek() == '<' && self.peek_next() == '=' {
+                        self.match_str("<=");
+                        Some(self.ToTokenData_Symbol(TokenType::ShiftLeftEqual))
                     } else if self.match_chr('<') {
                         Some(self.ToTokenData_Symbol(TokenType::ShiftLeft))
                     } else {
@@ -196,19 +251,34 @@ impl Token {
                         Some(self.ToTokenData_Symbol(TokenType::Not))
                     }
                 }
-                '%' => Some(self.ToTokenData_Symbol(TokenType::Modulo)),
+                '%' => {
+                    if self.match_chr('=') {
+                        Some(self.ToTokenData_Symbol(TokenType::ModuloEqual))
+                    } else {
+                        Some(self.ToTokenData_Symbol(TokenType::Modulo))
+                    }
+                }
+                '^' => {
+                    if self.match_chr('=') {
+                        Some(self.ToTokenData_Symbol(TokenType::CaretEqual))
+                    } else {
+                        Some(self.ToTokenData_Symbol(TokenType::Caret))
+                    }
+                }
+                '~' => Some(self.ToTokenData_Symbol(TokenType::Tilde)),
+                '?' => Some(self.ToTokenData_Symbol(TokenType::Question)),
                 ',' => Some(self.ToTokenData_Symbol(TokenType::Comma)),
+                '.' => Some(self.ToTokenData_Symbol(TokenType::Dot)),
                 '#' => {
                     if self.match_chr('#') {
                         while !self.match_str("##") && ! self.is_eof() {
-                            if self.peek() == '\n' { self.line+=1; }
-                            self.advance();
+                            if self.advance() == '\n' { self.line+=1; self.at = 0; }
                         }
                         None
                     } else if self.match_chr('!') {
                         let mut sub_token: Vec<TokenData> = Vec::new();
 
-                        while self.peek() != '\n' && !self.is_eof() {
+                        while self.peek() != '\n' && !self.is_eof() && self.error.is_none() {
                             let td = self.tokenize_single_char();
                             if td.is_some() {
                                 sub_token.push(
@@ -230,25 +300,13 @@ impl Token {
                 '"' => {
                     while self.peek() != '"' && !self.is_eof() {
                         if self.peek() == '\n' {
-                            throw_message(
-                            &self.source_file_name, 
-                            message_handler::MessageType::Error,
-                            self.line as i64, 
-                            self.at as i64, 
-                            "Invaild string literal format");
-                            exit(1);
+                            return self.error("Invaild string literal format");
                         }
                         self.advance();
                     }
 
                     if self.is_eof() {
-                        throw_message(
-                            &self.source_file_name, 
-                            message_handler::MessageType::Error,
-                            self.line as i64, 
-                            self.at as i64, 
-                            "Unterminated string literal");
-                            exit(1);
+                        return self.error("Unterminated string literal");
                     }
                     self.advance();
                     let mut sub_str = self.code[self.start..self.current].to_string();
@@ -288,13 +346,7 @@ impl Token {
                     // char support
                     self.advance();
                     if !self.match_chr('\'') {
-                        throw_message(
-                            &self.source_file_name, 
-                            message_handler::MessageType::Error,
-                            self.line as i64, 
-                            self.at as i64, 
-                            "Invaild char format!");
-                        exit(1);
+                        return self.error("Invaild char format!");
                     }
                     Some(self.To_TokenData_Identifier(TokenType::Char, self.code[self.start+1..self.current-1].to_string()))
                 }
@@ -307,7 +359,13 @@ impl Token {
                         "while",
                         "for",
                         "let",
-                        "extern"
+                        "extern",
+                        "enum",
+                        "switch",
+                        "case",
+                        "default",
+                        "import",
+                        "use"
                     ];
 
                     let data_type_kw = vec![
@@ -319,17 +377,20 @@ impl Token {
                         "short",
                         "long",
                         "const",
-                        "void"
+                        "void",
+                        "bool"
                     ];
 
                     while self.peek().is_alphanumeric() || self.peek() == '_' { self.advance(); }
 
                     let str_text = &self.code[self.start..self.current];
 
-                    if kw.iter().any(|f| *f==str_text) {
+                    if str_text == "true" || str_text == "false" {
+                        Some(self.To_TokenData_Identifier(TokenType::Boolean, str_text.to_string()))
+                    } else if kw.contains(&str_text) {
                         //self.ToTokenData_Symbol(TokenType::Keywords);
                         Some(self.To_TokenData_Obj(TokenType::Keywords, str_text.to_string()))
-                    } else if data_type_kw.iter().any(|f| *f==str_text)  {
+                    } else if data_type_kw.contains(&str_text) {
                         Some(self.To_TokenData_Obj(TokenType::DataType, str_text.to_string()))
                     } else {
                         //self.ToTokenData_Symbol(TokenType::Identifier);
@@ -342,22 +403,17 @@ impl Token {
                     self.at = 0;
                     None
                 },
-                _ => {
-                    throw_message(&self.source_file_name, 
-                        message_handler::MessageType::Error, 
-                        self.at as i64, 
-                        self.current as i64,
-                        &format!("Unknown token: {}", curr_char)
-                    );
-                    exit(1);
-                }
+                _ => self.error(&format!("Unknown token: {}", curr_char))
             }
     }
 
-    pub fn tokenize(&mut self) -> MetaData {
+    pub fn tokenize(&mut self) -> Result<MetaData, String> {
         let mut token_data: Vec<TokenData> = Vec::new();
         while !self.is_eof() {
             let tok_data = self.tokenize_single_char();
+            if let Some(e) = self.error.take() {
+                return Err(e);
+            }
             if tok_data.is_some() {
                 token_data.push(tok_data.unwrap());
             }
@@ -368,6 +424,7 @@ impl Token {
                 start: self.start, 
                 end: self.current, 
                 line:self.line, 
+                col: self.col(),
                 identifier: String::new(), 
                 value: Value::Null ,
                 sub_tok: None
@@ -376,6 +433,6 @@ impl Token {
         self.current = 0;
         self.start =0;
 
-        MetaData { filename: self.source_file_name.clone(), tok_data: token_data, data: self.data.clone() }
+        Ok(MetaData { filename: self.source_file_name.clone(), tok_data: token_data, data: self.data.clone() })
     }
 }
@@ -8,6 +8,7 @@ pub enum TokenType {
     Macro,
     String,
     Char,
+    Boolean,
 
     Plus,
     Minus,
@@ -20,13 +21,18 @@ pub enum TokenType {
     LeftBracket,
     RightBracket,
     Modulo,
+    Caret,
+    Tilde,
     Equal,
     Semicolon,
     And,
     Or,
     Not,
     Comma,
+    Dot,
     Colon,
+    ColonColon,
+    Question,
     NotEqual,
     EqualEqual,
     Less,
@@ -38,5 +44,17 @@ pub enum TokenType {
     AndBool,
     OrBool,
     PointTo,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    ModuloEqual,
+    ShiftLeftEqual,
+    ShiftRightEqual,
+    AndEqual,
+    OrEqual,
+    CaretEqual,
+    PlusPlus,
+    MinusMinus,
     EOF
 }